@@ -32,6 +32,7 @@ $(foreach lib,$(LIBS),\
 OUTPUT_DIR := target/$(RUST_TARGET)
 OUTPUT_BINS := $(foreach bin,$(BINS),$(OUTPUT_DIR)/$(bin))
 OUTPUT_LIBS := $(foreach lib,$(LIBS),$(OUTPUT_DIR)/$(LIB_$(lib)))
+OUTPUT_TESTS := $(foreach lib,$(LIBS),$(OUTPUT_DIR)/test-$(lib))
 
 .PHONY : all
 all : bins libs
@@ -42,6 +43,10 @@ bins : $(OUTPUT_BINS)
 .PHONY : libs
 libs : $(OUTPUT_LIBS)
 
+.PHONY : test
+test : $(OUTPUT_TESTS)
+	$(foreach test,$(OUTPUT_TESTS),$(test) &&) true
+
 .PHONY : clean
 clean :
 	rm -rf target/
@@ -58,6 +63,15 @@ $$(OUTPUT_DIR)/$$(LIB_$1) : src/lib$1/lib.rs $$(foreach lib,$$(DEPENDS_lib$1),$$
 		--out-dir $$(dir $$@)
 endef
 
+define make-test=
+$$(OUTPUT_DIR)/test-$1 : src/lib$1/lib.rs $$(foreach lib,$$(DEPENDS_lib$1),$$(OUTPUT_DIR)/$$(LIB_$$(lib)))
+	@mkdir -p $$(dir $$@) $$(OUTPUT_DIR)/depends/
+	$$(RUSTC) $$(RUST_FLAGS) --test $$< \
+		-L $$(OUTPUT_DIR) \
+		--dep-info $$(OUTPUT_DIR)/depends/test-$1.d \
+		-o $$@
+endef
+
 define make-bin=
 $$(OUTPUT_DIR)/$1 : src/$1/main.rs $$(foreach lib,$$(DEPENDS_$1),$$(OUTPUT_DIR)/$$(LIB_$$(lib)))
 	@mkdir -p $$(dir $$@) $$(OUTPUT_DIR)/depends/
@@ -74,5 +88,7 @@ endef
 
 $(foreach lib,$(LIBS),\
 	$(eval $(call make-lib,$(lib))))
+$(foreach lib,$(LIBS),\
+	$(eval $(call make-test,$(lib))))
 $(foreach bin,$(BINS),\
 	$(eval $(call make-bin,$(bin))))
@@ -29,30 +29,27 @@ fn main() {
     //print_table(&mut clients.iter());
 
     /*
-    let mut pk_iter = db::select::SelectPrimaryKey {
-        base: clients.iter(),
-        key: Some(99820),
-    };
+    let mut pk_iter = db::select::select_primary_key(clients.iter(), Some(db::Integer(99820)))
+        .unwrap();
     print_table(&mut pk_iter);
     */
 
     let mut cross_iter = db::select::cross(clients.iter(), depts.iter());
-    let client_id_field = cross_iter.schema().map_field("Clientes.departamento").unwrap();
-    let dept_id_field = cross_iter.schema().map_field("Departamentos.id").unwrap();
-    let mut select_iter = db::select::Select {
+    let client_id_field = cross_iter.schema().find_field("Clientes.departamento").unwrap();
+    let dept_id_field = cross_iter.schema().find_field("Departamentos.id").unwrap();
+    let select_iter = db::select::Select {
         base: cross_iter,
         condition: |record| { record.get(client_id_field) == record.get(dept_id_field) },
     };
-    print_table(&mut select_iter);
+    let mut project_iter = db::select::project_names(select_iter,
+        &["Clientes.id", "Clientes.nome", "Departamentos.nome"]).unwrap();
+    print_table(&mut project_iter);
 
     /*
     let clients_iter = clients.iter();
-    let client_id_field = clients_iter.schema().map_field("departamento").unwrap();
+    let client_id_field = clients_iter.schema().find_field("departamento").unwrap();
     let mut pk_join_iter = db::select::pk_join(clients_iter, depts.iter(),
-        |record| { match *record.get(client_id_field) {
-            db::Integer(k) => Some(k),
-            _ => None,
-        }});
+        |record| Some(record.get(client_id_field).clone())).unwrap();
     print_table(&mut pk_join_iter);
     */
 }
@@ -0,0 +1,520 @@
+use collections::TreeMap;
+use std::io;
+
+use super::snapshot::SnapshotFile;
+
+use super::{
+    Field,
+    FieldType,
+    IntegerType,
+    TextType,
+    read_value,
+    write_value,
+};
+
+// Containers with more positions than this are stored as plain bitmaps.
+static ARRAY_LIMIT : uint = 4096;
+static CONTAINER_WORDS : uint = 1024;
+
+#[deriving(Clone)]
+enum Container {
+    // Sorted low 16 bits of each position.
+    ArrayContainer(Vec<u16>),
+    BitmapContainer(Vec<u64>),
+}
+
+impl Container {
+    fn to_words(&self) -> Vec<u64> {
+        match *self {
+            BitmapContainer(ref words) => words.clone(),
+            ArrayContainer(ref values) => {
+                let mut words = Vec::from_elem(CONTAINER_WORDS, 0u64);
+                for &v in values.iter() {
+                    *words.get_mut(v as uint / 64) |= 1u64 << (v as uint % 64);
+                }
+                words
+            },
+        }
+    }
+
+    // Picks the smaller representation for a set of bits, or None if no bits are set.
+    fn from_words(words: Vec<u64>) -> Option<Container> {
+        let count = words.iter().fold(0, |n, w| n + w.count_ones() as uint);
+        if count == 0 {
+            None
+        } else if count > ARRAY_LIMIT {
+            Some(BitmapContainer(words))
+        } else {
+            let mut values = Vec::with_capacity(count);
+            for (i, &w) in words.iter().enumerate() {
+                for bit in range(0u, 64) {
+                    if w & (1u64 << bit) != 0 {
+                        values.push((i * 64 + bit) as u16);
+                    }
+                }
+            }
+            Some(ArrayContainer(values))
+        }
+    }
+
+    fn len(&self) -> uint {
+        match *self {
+            ArrayContainer(ref values) => values.len(),
+            BitmapContainer(ref words) => words.iter().fold(0, |n, w| n + w.count_ones() as uint),
+        }
+    }
+
+    fn contains(&self, low: u16) -> bool {
+        match *self {
+            ArrayContainer(ref values) => values.iter().any(|&v| v == low),
+            BitmapContainer(ref words) =>
+                *words.get(low as uint / 64) & (1u64 << (low as uint % 64)) != 0,
+        }
+    }
+}
+
+// A compressed set of record positions, split into containers of 2^16 positions that are kept
+// either as sorted arrays or as bitmaps depending on how dense they are.
+#[deriving(Clone)]
+pub struct Bitmap {
+    containers: Vec<(u16, Container)>,
+}
+
+impl Bitmap {
+    pub fn new() -> Bitmap {
+        Bitmap { containers: Vec::new() }
+    }
+
+    fn find_container(&self, high: u16) -> Result<uint, uint> {
+        let (mut lo, mut hi) = (0, self.containers.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            let &(key, _) = self.containers.get(mid);
+            if key == high { return Ok(mid); }
+            if key < high { lo = mid + 1; } else { hi = mid; }
+        }
+        Err(lo)
+    }
+
+    pub fn insert(&mut self, pos: uint) {
+        let (high, low) = ((pos >> 16) as u16, (pos & 0xFFFF) as u16);
+        let i = match self.find_container(high) {
+            Ok(i) => i,
+            Err(i) => {
+                self.containers.insert(i, (high, ArrayContainer(Vec::new())));
+                i
+            },
+        };
+
+        let (_, ref mut container) = *self.containers.get_mut(i);
+        let full_array = match *container {
+            BitmapContainer(ref mut words) => {
+                *words.get_mut(low as uint / 64) |= 1u64 << (low as uint % 64);
+                false
+            },
+            ArrayContainer(ref mut values) => {
+                match values.iter().position(|&v| v >= low) {
+                    Some(j) if *values.get(j) == low => (),
+                    Some(j) => values.insert(j, low),
+                    None => values.push(low),
+                }
+                values.len() > ARRAY_LIMIT
+            },
+        };
+        if full_array {
+            *container = BitmapContainer(container.to_words());
+        }
+    }
+
+    pub fn remove(&mut self, pos: uint) {
+        let (high, low) = ((pos >> 16) as u16, (pos & 0xFFFF) as u16);
+        let i = match self.find_container(high) {
+            Ok(i) => i,
+            Err(_) => return,
+        };
+
+        let now_empty = {
+            let (_, ref mut container) = *self.containers.get_mut(i);
+            match *container {
+                BitmapContainer(ref mut words) =>
+                    *words.get_mut(low as uint / 64) &= !(1u64 << (low as uint % 64)),
+                ArrayContainer(ref mut values) => values.retain(|&v| v != low),
+            }
+            container.len() == 0
+        };
+        if now_empty {
+            self.containers.remove(i);
+        }
+    }
+
+    pub fn contains(&self, pos: uint) -> bool {
+        match self.find_container((pos >> 16) as u16) {
+            Ok(i) => {
+                let &(_, ref container) = self.containers.get(i);
+                container.contains((pos & 0xFFFF) as u16)
+            },
+            Err(_) => false,
+        }
+    }
+
+    pub fn len(&self) -> uint {
+        self.containers.iter().fold(0, |n, &(_, ref c)| n + c.len())
+    }
+
+    // Merges two bitmaps container by container. Containers present on only one side are
+    // kept if the corresponding flag is set.
+    fn combine(&self, other: &Bitmap, keep_left: bool, keep_right: bool, op: |u64, u64| -> u64)
+            -> Bitmap {
+        let mut out = Bitmap::new();
+        let (mut i, mut j) = (0, 0);
+        while i < self.containers.len() || j < other.containers.len() {
+            let left = if i < self.containers.len() { Some(self.containers.get(i)) } else { None };
+            let right = if j < other.containers.len() { Some(other.containers.get(j)) } else { None };
+
+            match (left, right) {
+                (Some(&(lk, ref lc)), Some(&(rk, _))) if lk < rk => {
+                    if keep_left { out.containers.push((lk, lc.clone())); }
+                    i += 1;
+                },
+                (Some(&(lk, _)), Some(&(rk, ref rc))) if rk < lk => {
+                    if keep_right { out.containers.push((rk, rc.clone())); }
+                    j += 1;
+                },
+                (Some(&(lk, ref lc)), Some(&(_, ref rc))) => {
+                    let (left_words, right_words) = (lc.to_words(), rc.to_words());
+                    let mut words = Vec::with_capacity(CONTAINER_WORDS);
+                    for k in range(0, CONTAINER_WORDS) {
+                        words.push(op(*left_words.get(k), *right_words.get(k)));
+                    }
+                    match Container::from_words(words) {
+                        Some(c) => out.containers.push((lk, c)),
+                        None => (),
+                    }
+                    i += 1;
+                    j += 1;
+                },
+                (Some(&(lk, ref lc)), None) => {
+                    if keep_left { out.containers.push((lk, lc.clone())); }
+                    i += 1;
+                },
+                (None, Some(&(rk, ref rc))) => {
+                    if keep_right { out.containers.push((rk, rc.clone())); }
+                    j += 1;
+                },
+                (None, None) => unreachable!(),
+            }
+        }
+        out
+    }
+
+    pub fn and(&self, other: &Bitmap) -> Bitmap {
+        self.combine(other, false, false, |a, b| a & b)
+    }
+
+    pub fn or(&self, other: &Bitmap) -> Bitmap {
+        self.combine(other, true, true, |a, b| a | b)
+    }
+
+    pub fn and_not(&self, other: &Bitmap) -> Bitmap {
+        self.combine(other, true, false, |a, b| a & !b)
+    }
+
+    // Positions in [0, universe) that are not in the bitmap.
+    pub fn not(&self, universe: uint) -> Bitmap {
+        let mut all = Bitmap::new();
+        let mut remaining = universe;
+        let mut high = 0u;
+        while remaining > 0 {
+            let bits = if remaining > 1 << 16 { 1 << 16 } else { remaining };
+            let mut words = Vec::from_elem(CONTAINER_WORDS, 0u64);
+            for w in range(0, bits / 64) {
+                *words.get_mut(w) = !0u64;
+            }
+            if bits % 64 != 0 {
+                *words.get_mut(bits / 64) = (1u64 << (bits % 64)) - 1;
+            }
+            all.containers.push((high as u16, Container::from_words(words).unwrap()));
+
+            remaining -= bits;
+            high += 1;
+        }
+        all.and_not(self)
+    }
+
+    pub fn into_positions(self) -> Positions {
+        Positions { bitmap: self, container: 0, next_low: 0 }
+    }
+
+    fn write_to(&self, writer: &mut Writer) -> io::IoResult<()> {
+        try!(writer.write_be_u32(self.containers.len() as u32));
+        for &(high, ref container) in self.containers.iter() {
+            try!(writer.write_be_u16(high));
+            match *container {
+                ArrayContainer(ref values) => {
+                    try!(writer.write_be_u32(values.len() as u32));
+                    for &v in values.iter() {
+                        try!(writer.write_be_u16(v));
+                    }
+                },
+                BitmapContainer(ref words) => {
+                    try!(writer.write_be_u32(0));
+                    for &w in words.iter() {
+                        try!(writer.write_be_u64(w));
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn read_from(reader: &mut Reader) -> io::IoResult<Bitmap> {
+        let mut bitmap = Bitmap::new();
+        let num_containers = try!(reader.read_be_u32());
+        for _ in range(0, num_containers) {
+            let high = try!(reader.read_be_u16());
+            // Array containers are never empty, so a length of 0 marks a bitmap container.
+            let container = match try!(reader.read_be_u32()) {
+                0 => {
+                    let mut words = Vec::with_capacity(CONTAINER_WORDS);
+                    for _ in range(0, CONTAINER_WORDS) {
+                        words.push(try!(reader.read_be_u64()));
+                    }
+                    BitmapContainer(words)
+                },
+                len => {
+                    let mut values = Vec::with_capacity(len as uint);
+                    for _ in range(0, len) {
+                        values.push(try!(reader.read_be_u16()));
+                    }
+                    ArrayContainer(values)
+                },
+            };
+            bitmap.containers.push((high, container));
+        }
+        Ok(bitmap)
+    }
+}
+
+// Iterates over the positions in a bitmap in increasing order.
+pub struct Positions {
+    bitmap: Bitmap,
+    container: uint,
+    next_low: uint,
+}
+
+impl Iterator<uint> for Positions {
+    fn next(&mut self) -> Option<uint> {
+        while self.container < self.bitmap.containers.len() {
+            let &(high, ref container) = self.bitmap.containers.get(self.container);
+            let found = match *container {
+                ArrayContainer(ref values) => {
+                    if self.next_low < values.len() {
+                        self.next_low += 1;
+                        Some(*values.get(self.next_low - 1) as uint)
+                    } else {
+                        None
+                    }
+                },
+                BitmapContainer(ref words) => {
+                    let mut found = None;
+                    while self.next_low < 1 << 16 {
+                        let low = self.next_low;
+                        self.next_low += 1;
+                        if *words.get(low / 64) & (1u64 << (low % 64)) != 0 {
+                            found = Some(low);
+                            break;
+                        }
+                    }
+                    found
+                },
+            };
+
+            match found {
+                Some(low) => return Some((high as uint) << 16 | low),
+                None => {
+                    self.container += 1;
+                    self.next_low = 0;
+                },
+            }
+        }
+        None
+    }
+}
+
+// A bitmap per distinct value of a field. The whole index is kept in memory, and written to
+// its file by `flush`.
+pub struct BitmapIndex {
+    file: SnapshotFile,
+    key_type: FieldType,
+    key_length: uint,
+
+    bitmaps: TreeMap<Field, Bitmap>,
+    // Number of records in the table the last time the index was flushed.
+    pub universe: uint,
+}
+
+impl BitmapIndex {
+    pub fn create(path: &Path, key_type: FieldType, key_length: uint) -> io::IoResult<BitmapIndex> {
+        let mut index = BitmapIndex {
+            file: SnapshotFile::new(path),
+            key_type: key_type,
+            key_length: key_length,
+
+            bitmaps: TreeMap::new(),
+            universe: 0,
+        };
+        try!(index.flush());
+        Ok(index)
+    }
+
+    pub fn open(path: &Path) -> io::IoResult<BitmapIndex> {
+        let (file, mut reader) = try!(SnapshotFile::open(path));
+
+        let key_type = match try!(reader.read_be_u32()) {
+            0 => IntegerType,
+            1 => TextType,
+            _ => return Err(io::standard_error(io::InvalidInput)),
+        };
+        let key_length = try!(reader.read_be_u32()) as uint;
+        let universe = try!(reader.read_be_u32()) as uint;
+
+        let mut bitmaps = TreeMap::new();
+        let num_keys = try!(reader.read_be_u32());
+        for _ in range(0, num_keys) {
+            let key_buf = try!(reader.read_exact(key_length));
+            let key = match read_value(0, key_type, key_buf.as_slice()) {
+                Ok(k) => k, Err(_) => return Err(io::standard_error(io::InvalidInput)) };
+            bitmaps.insert(key, try!(Bitmap::read_from(&mut reader)));
+        }
+
+        Ok(BitmapIndex {
+            file: file,
+            key_type: key_type,
+            key_length: key_length,
+
+            bitmaps: bitmaps,
+            universe: universe,
+        })
+    }
+
+    // Whether the file missed changes made before the index was last dropped.
+    pub fn is_stale(&self) -> bool {
+        self.file.stale
+    }
+
+    pub fn flush(&mut self) -> io::IoResult<()> {
+        let BitmapIndex { ref mut file, key_type, key_length, ref bitmaps, universe } = *self;
+        file.write(|writer| {
+            try!(writer.write_be_u32(match key_type { IntegerType => 0, TextType => 1 }));
+            try!(writer.write_be_u32(key_length as u32));
+            try!(writer.write_be_u32(universe as u32));
+            try!(writer.write_be_u32(bitmaps.len() as u32));
+
+            let mut key_buf = Vec::from_elem(key_length, 0u8);
+            for (key, bitmap) in bitmaps.iter() {
+                match write_value(0, key, key_buf.as_mut_slice()) {
+                    Ok(()) => (), Err(_) => return Err(io::standard_error(io::InvalidInput)) };
+                try!(writer.write(key_buf.as_slice()));
+                try!(bitmap.write_to(writer));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn insert(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        try!(self.file.mark_dirty());
+        if !self.bitmaps.contains_key(key) {
+            self.bitmaps.insert(key.clone(), Bitmap::new());
+        }
+        self.bitmaps.find_mut(key).unwrap().insert(pos);
+        if pos >= self.universe {
+            self.universe = pos + 1;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        try!(self.file.mark_dirty());
+        let now_empty = match self.bitmaps.find_mut(key) {
+            None => return Ok(()),
+            Some(bitmap) => {
+                bitmap.remove(pos);
+                bitmap.len() == 0
+            },
+        };
+        if now_empty {
+            self.bitmaps.remove(key);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, key: &Field) -> Bitmap {
+        match self.bitmaps.find(key) {
+            Some(bitmap) => bitmap.clone(),
+            None => Bitmap::new(),
+        }
+    }
+}
+
+// A boolean combination of equality predicates, each answered by a bitmap index.
+pub enum BitmapQuery {
+    // (index name, value)
+    Equals(String, Field),
+    And(Box<BitmapQuery>, Box<BitmapQuery>),
+    Or(Box<BitmapQuery>, Box<BitmapQuery>),
+    Not(Box<BitmapQuery>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing;
+    use super::super::{BitmapIndexType, Field, Integer, IntegerType, Table};
+    use super::super::select::SelectPositions;
+    use super::{And, BitmapQuery, Equals, Not, Or};
+
+    fn equals(name: &str, value: u32) -> Box<BitmapQuery> {
+        box Equals(name.to_strbuf(), Integer(value))
+    }
+
+    // Ids of the records a query selects.
+    fn ids(table: &mut Table, query: &BitmapQuery) -> Vec<u32> {
+        let positions = table.bitmap_query(query).unwrap().into_positions();
+        let rows = SelectPositions { base: table.iter(), positions: positions };
+        rows.map(|values| match *values.get(0) {
+            Integer(id) => id,
+            _ => fail!("expected an Integer id"),
+        }).collect()
+    }
+
+    #[test]
+    fn queries_across_indexes() {
+        let db = testing::scratch_db();
+        let records: Vec<Vec<Field>> = range(0, 30u32)
+            .map(|id| vec![Integer(id), Integer(id % 2), Integer(id % 3)])
+            .collect();
+        let mut table = testing::create(db.path(), "T",
+                                        &[("id", IntegerType), ("a", IntegerType),
+                                          ("b", IntegerType)],
+                                        None, records.as_slice());
+        table.create_index("a_bitmap", "a", BitmapIndexType).unwrap();
+        table.create_index("b_bitmap", "b", BitmapIndexType).unwrap();
+
+        let both = And(equals("a_bitmap", 0), equals("b_bitmap", 0));
+        assert_eq!(ids(&mut table, &both), vec![0, 6, 12, 18, 24]);
+        let either = Or(equals("a_bitmap", 1), equals("b_bitmap", 0));
+        assert_eq!(ids(&mut table, &either),
+                   range(0, 30u32).filter(|id| id % 2 == 1 || id % 3 == 0).collect());
+        let odd = Not(equals("a_bitmap", 0));
+        assert_eq!(ids(&mut table, &odd), range(0, 30u32).filter(|id| id % 2 == 1).collect());
+
+        // The complement also holds the slots of deleted records, which are skipped when the
+        // records are fetched.
+        table.delete_entry(1).unwrap();
+        table.delete_entry(3).unwrap();
+        assert!(table.bitmap_query(&odd).unwrap().contains(1));
+        assert_eq!(ids(&mut table, &odd),
+                   range(5, 30u32).filter(|id| id % 2 == 1).collect());
+        let not_either = Not(box either);
+        assert_eq!(ids(&mut table, &not_either), vec![2, 4, 8, 10, 14, 16, 20, 22, 26, 28]);
+    }
+}
@@ -0,0 +1,186 @@
+use std::cmp::max;
+use std::f64::consts::LN_2;
+use std::hash::sip;
+use std::io;
+use std::mem;
+
+use super::Field;
+use super::snapshot::SnapshotFile;
+
+// Smallest number of keys a filter is sized for, so that tiny tables don't need resizing after
+// every few inserts.
+pub static MIN_CAPACITY : uint = 1024;
+
+// A Bloom filter over the values of one field of a table, used to skip index probes for keys
+// that are certainly absent.
+//
+// Keys can't be removed, so deletes and updates only make the filter less selective until the
+// table is compacted. A missing key would instead make it reject records that exist, so a filter
+// whose file missed inserts must be rebuilt.
+pub struct BloomFilter {
+    file: SnapshotFile,
+    pub field: uint,
+    pub false_positive_rate: f64,
+
+    capacity: uint,
+    num_hashes: uint,
+    num_bits: uint,
+    bits: Vec<u64>,
+    len: uint,
+    // Number of records in the table the last time the filter was flushed.
+    pub universe: uint,
+
+    // Probe statistics since the table was opened.
+    pub probes: uint,
+    pub negatives: uint,
+    pub false_positives: uint,
+}
+
+fn hash_key(key: &Field) -> (u64, u64) {
+    // Double hashing: the k bit positions are h1 + i * h2.
+    (sip::hash_with_keys(0, 0, key), sip::hash_with_keys(1, 1, key) | 1)
+}
+
+impl BloomFilter {
+    pub fn create(path: &Path, field: uint, capacity: uint, false_positive_rate: f64)
+            -> io::IoResult<BloomFilter> {
+        let capacity = max(capacity, MIN_CAPACITY);
+        // Optimal sizes for n keys and false positive rate p:
+        // m = -n ln(p) / ln(2)^2 bits and k = m / n ln(2) hash functions.
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (LN_2 * LN_2)).ceil();
+        let num_bits = max(num_bits as uint, 64);
+        let num_hashes = max((num_bits as f64 / capacity as f64 * LN_2).round() as uint, 1);
+
+        let mut filter = BloomFilter {
+            file: SnapshotFile::new(path),
+            field: field,
+            false_positive_rate: false_positive_rate,
+
+            capacity: capacity,
+            num_hashes: num_hashes,
+            num_bits: num_bits,
+            bits: Vec::from_elem((num_bits + 63) / 64, 0u64),
+            len: 0,
+            universe: 0,
+
+            probes: 0,
+            negatives: 0,
+            false_positives: 0,
+        };
+        try!(filter.flush());
+        Ok(filter)
+    }
+
+    pub fn open(path: &Path, field: uint) -> io::IoResult<BloomFilter> {
+        let (file, mut reader) = try!(SnapshotFile::open(path));
+
+        let false_positive_rate: f64 = unsafe { mem::transmute(try!(reader.read_be_u64())) };
+        let capacity = try!(reader.read_be_u32()) as uint;
+        let num_hashes = try!(reader.read_be_u32()) as uint;
+        let num_bits = try!(reader.read_be_u32()) as uint;
+        let len = try!(reader.read_be_u32()) as uint;
+        let universe = try!(reader.read_be_u32()) as uint;
+
+        let mut bits = Vec::with_capacity((num_bits + 63) / 64);
+        for _ in range(0, (num_bits + 63) / 64) {
+            bits.push(try!(reader.read_be_u64()));
+        }
+
+        Ok(BloomFilter {
+            file: file,
+            field: field,
+            false_positive_rate: false_positive_rate,
+
+            capacity: capacity,
+            num_hashes: num_hashes,
+            num_bits: num_bits,
+            bits: bits,
+            len: len,
+            universe: universe,
+
+            probes: 0,
+            negatives: 0,
+            false_positives: 0,
+        })
+    }
+
+    // Whether the file missed inserts made before the filter was last dropped.
+    pub fn is_stale(&self) -> bool {
+        self.file.stale
+    }
+
+    pub fn flush(&mut self) -> io::IoResult<()> {
+        let BloomFilter {
+            ref mut file, false_positive_rate, capacity, num_hashes, num_bits, ref bits, len,
+            universe, ..
+        } = *self;
+        file.write(|writer| {
+            let rate_bits: u64 = unsafe { mem::transmute(false_positive_rate) };
+            try!(writer.write_be_u64(rate_bits));
+            try!(writer.write_be_u32(capacity as u32));
+            try!(writer.write_be_u32(num_hashes as u32));
+            try!(writer.write_be_u32(num_bits as u32));
+            try!(writer.write_be_u32(len as u32));
+            try!(writer.write_be_u32(universe as u32));
+            for word in bits.iter() {
+                try!(writer.write_be_u64(*word));
+            }
+            Ok(())
+        })
+    }
+
+    fn bit_positions(&self, key: &Field) -> Vec<uint> {
+        let (h1, h2) = hash_key(key);
+        range(0, self.num_hashes as u64)
+            .map(|i| ((h1 + i * h2) % self.num_bits as u64) as uint)
+            .collect()
+    }
+
+    pub fn insert(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        try!(self.file.mark_dirty());
+        for bit in self.bit_positions(key).iter() {
+            *self.bits.get_mut(*bit / 64) |= 1u64 << (*bit % 64);
+        }
+        self.len += 1;
+        if pos >= self.universe {
+            self.universe = pos + 1;
+        }
+        Ok(())
+    }
+
+    // Whether the filter has taken more keys than it was sized for, so that its false
+    // positive rate is above the configured one.
+    pub fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    pub fn len(&self) -> uint {
+        self.len
+    }
+
+    // False means the key is certainly absent.
+    pub fn may_contain(&mut self, key: &Field) -> bool {
+        self.probes += 1;
+        let present = self.bit_positions(key).iter()
+            .all(|&bit| *self.bits.get(bit / 64) & (1u64 << (bit % 64)) != 0);
+        if !present {
+            self.negatives += 1;
+        }
+        present
+    }
+
+    // Called when a probe the filter let through found no matching record.
+    pub fn record_false_positive(&mut self) {
+        self.false_positives += 1;
+    }
+
+    // Fraction of probes for absent keys that the filter failed to reject.
+    pub fn observed_false_positive_rate(&self) -> f64 {
+        let absent = self.negatives + self.false_positives;
+        if absent == 0 {
+            0.0
+        } else {
+            self.false_positives as f64 / absent as f64
+        }
+    }
+}
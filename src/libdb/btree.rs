@@ -0,0 +1,568 @@
+use std::io::fs;
+use std::io;
+use std::u32;
+
+use super::{
+    Bound,
+    Field,
+    FieldType,
+    IntegerType,
+    TextType,
+    PhysicalTableIterator,
+    ProbeIterator,
+    TableError,
+    TableIterator,
+    TableSchema,
+    Unbounded,
+    Included,
+    Excluded,
+    read_u32,
+    read_value,
+    write_u32,
+    write_value,
+};
+
+// Maximum number of entries in a node. Nodes are split when they exceed it.
+static NODE_ORDER : uint = 32;
+static NODE_HEADER : uint = 12;
+static META_SIZE : uint = 16;
+
+// Entries are (key, record position) pairs, which makes them unique even when keys repeat.
+pub type Entry = (Field, u32);
+
+struct Node {
+    leaf: bool,
+    // Next leaf for leaves (0 if last), leftmost child for internal nodes.
+    link: u32,
+    keys: Vec<Entry>,
+    // For internal nodes, children[j] holds entries >= keys[j].
+    children: Vec<u32>,
+}
+
+impl Node {
+    // Index of the child that may contain `entry`: 0 for `link`, j + 1 for children[j].
+    fn child_slot(&self, entry: &Entry) -> uint {
+        lower_bound(self.keys.as_slice(), entry, true)
+    }
+
+    fn child(&self, slot: uint) -> u32 {
+        if slot == 0 { self.link } else { *self.children.get(slot - 1) }
+    }
+}
+
+// Number of entries in `keys` that are less than (or, if `inclusive`, equal to) `entry`.
+fn lower_bound(keys: &[Entry], entry: &Entry, inclusive: bool) -> uint {
+    let (mut lo, mut hi) = (0, keys.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        let before = if inclusive { keys[mid] <= *entry } else { keys[mid] < *entry };
+        if before { lo = mid + 1; } else { hi = mid; }
+    }
+    lo
+}
+
+fn corrupt_index() -> io::IoError {
+    io::IoError {
+        kind: io::InvalidInput,
+        desc: "index file contains invalid data",
+        detail: None,
+    }
+}
+
+// A B+tree stored in its own file, mapping field values to record positions.
+//
+// Page 0 holds the tree metadata, every other page is a node. Deletions only remove entries
+// from leaves, without merging underfull nodes.
+pub struct BTree {
+    file: fs::File,
+    key_type: FieldType,
+    key_length: uint,
+    root: u32,
+    num_pages: u32,
+}
+
+impl BTree {
+    pub fn create(path: &Path, key_type: FieldType, key_length: uint) -> io::IoResult<BTree> {
+        let file = try!(fs::File::open_mode(path, io::Truncate, io::ReadWrite));
+        let mut tree = BTree {
+            file: file,
+            key_type: key_type,
+            key_length: key_length,
+            root: 1,
+            num_pages: 2,
+        };
+
+        let root = Node { leaf: true, link: 0, keys: Vec::new(), children: Vec::new() };
+        try!(tree.write_node(1, &root));
+        try!(tree.write_meta());
+        Ok(tree)
+    }
+
+    pub fn open(path: &Path) -> io::IoResult<BTree> {
+        let mut file = try!(fs::File::open_mode(path, io::Open, io::ReadWrite));
+        let meta = try!(file.read_exact(META_SIZE));
+
+        let key_type = match read_u32(meta.slice(8, 12)) {
+            0 => IntegerType,
+            1 => TextType,
+            _ => return Err(corrupt_index()),
+        };
+
+        Ok(BTree {
+            file: file,
+            key_type: key_type,
+            key_length: read_u32(meta.slice(12, 16)) as uint,
+            root: read_u32(meta.slice(0, 4)),
+            num_pages: read_u32(meta.slice(4, 8)),
+        })
+    }
+
+    fn page_size(&self) -> uint {
+        NODE_HEADER + NODE_ORDER * (self.key_length + 8)
+    }
+
+    fn write_meta(&mut self) -> io::IoResult<()> {
+        let mut buf = Vec::from_elem(META_SIZE, 0u8);
+        write_u32(self.root, buf.mut_slice(0, 4));
+        write_u32(self.num_pages, buf.mut_slice(4, 8));
+        write_u32(match self.key_type { IntegerType => 0, TextType => 1 }, buf.mut_slice(8, 12));
+        write_u32(self.key_length as u32, buf.mut_slice(12, 16));
+
+        try!(self.file.seek(0, io::SeekSet));
+        self.file.write(buf.as_slice())
+    }
+
+    fn alloc_page(&mut self) -> u32 {
+        let page = self.num_pages;
+        self.num_pages += 1;
+        page
+    }
+
+    fn read_node(&mut self, page: u32) -> io::IoResult<Node> {
+        let page_size = self.page_size();
+        let slot_size = self.key_length + 8;
+
+        try!(self.file.seek((page as uint * page_size) as i64, io::SeekSet));
+        let buf = try!(self.file.read_exact(page_size));
+
+        let count = read_u32(buf.slice(4, 8)) as uint;
+        let mut node = Node {
+            leaf: read_u32(buf.slice(0, 4)) != 0,
+            link: read_u32(buf.slice(8, 12)),
+            keys: Vec::with_capacity(count),
+            children: Vec::with_capacity(count),
+        };
+
+        for j in range(0, count) {
+            let slot = buf.slice(NODE_HEADER + j * slot_size, NODE_HEADER + (j + 1) * slot_size);
+            let key = match read_value(0, self.key_type, slot.slice_to(self.key_length)) {
+                Ok(k) => k, Err(_) => return Err(corrupt_index()) };
+            let pos = read_u32(slot.slice(self.key_length, self.key_length + 4));
+            node.keys.push((key, pos));
+            if !node.leaf {
+                node.children.push(read_u32(slot.slice(self.key_length + 4, slot_size)));
+            }
+        }
+
+        Ok(node)
+    }
+
+    fn write_node(&mut self, page: u32, node: &Node) -> io::IoResult<()> {
+        let page_size = self.page_size();
+        let slot_size = self.key_length + 8;
+        let mut buf = Vec::from_elem(page_size, 0u8);
+
+        write_u32(if node.leaf { 1 } else { 0 }, buf.mut_slice(0, 4));
+        write_u32(node.keys.len() as u32, buf.mut_slice(4, 8));
+        write_u32(node.link, buf.mut_slice(8, 12));
+
+        for (j, &(ref key, pos)) in node.keys.iter().enumerate() {
+            let slot = buf.mut_slice(NODE_HEADER + j * slot_size,
+                                     NODE_HEADER + (j + 1) * slot_size);
+            match write_value(0, key, slot.mut_slice_to(self.key_length)) {
+                Ok(()) => (), Err(_) => return Err(corrupt_index()) };
+            write_u32(pos, slot.mut_slice(self.key_length, self.key_length + 4));
+            if !node.leaf {
+                write_u32(*node.children.get(j), slot.mut_slice(self.key_length + 4, slot_size));
+            }
+        }
+
+        try!(self.file.seek((page as uint * page_size) as i64, io::SeekSet));
+        self.file.write(buf.as_slice())
+    }
+
+    pub fn insert(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        let root = self.root;
+        match try!(self.insert_into(root, (key.clone(), pos as u32))) {
+            None => Ok(()),
+            Some((separator, right)) => {
+                let new_root = Node {
+                    leaf: false,
+                    link: root,
+                    keys: vec![separator],
+                    children: vec![right],
+                };
+                let page = self.alloc_page();
+                try!(self.write_node(page, &new_root));
+                self.root = page;
+                self.write_meta()
+            },
+        }
+    }
+
+    // Inserts `entry` into the subtree at `page`. If the node had to be split, returns the
+    // separator and page of the new right sibling, which the parent must then insert.
+    fn insert_into(&mut self, page: u32, entry: Entry) -> io::IoResult<Option<(Entry, u32)>> {
+        let mut node = try!(self.read_node(page));
+
+        if node.leaf {
+            let slot = lower_bound(node.keys.as_slice(), &entry, false);
+            node.keys.insert(slot, entry);
+        } else {
+            let slot = node.child_slot(&entry);
+            match try!(self.insert_into(node.child(slot), entry)) {
+                None => return Ok(None),
+                Some((separator, right)) => {
+                    node.keys.insert(slot, separator);
+                    node.children.insert(slot, right);
+                },
+            }
+        }
+
+        if node.keys.len() <= NODE_ORDER {
+            try!(self.write_node(page, &node));
+            return Ok(None);
+        }
+
+        let mid = node.keys.len() / 2;
+        let right_page = self.alloc_page();
+        let (separator, right) = if node.leaf {
+            let right = Node {
+                leaf: true,
+                link: node.link,
+                keys: Vec::from_slice(node.keys.slice_from(mid)),
+                children: Vec::new(),
+            };
+            node.link = right_page;
+            let separator = right.keys.get(0).clone();
+            (separator, right)
+        } else {
+            let right = Node {
+                leaf: false,
+                link: *node.children.get(mid),
+                keys: Vec::from_slice(node.keys.slice_from(mid + 1)),
+                children: Vec::from_slice(node.children.slice_from(mid + 1)),
+            };
+            let separator = node.keys.get(mid).clone();
+            (separator, right)
+        };
+        node.keys.truncate(mid);
+        node.children.truncate(if node.leaf { 0 } else { mid });
+
+        try!(self.write_node(page, &node));
+        try!(self.write_node(right_page, &right));
+        try!(self.write_meta());
+        Ok(Some((separator, right_page)))
+    }
+
+    pub fn remove(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        let entry = (key.clone(), pos as u32);
+        let mut page = self.root;
+        let mut node = try!(self.read_node(page));
+        while !node.leaf {
+            page = node.child(node.child_slot(&entry));
+            node = try!(self.read_node(page));
+        }
+
+        let slot = lower_bound(node.keys.as_slice(), &entry, false);
+        if slot < node.keys.len() && *node.keys.get(slot) == entry {
+            node.keys.remove(slot);
+            try!(self.write_node(page, &node));
+        }
+        Ok(())
+    }
+
+    // Returns the position of some record with the given key, plus the number of nodes read.
+    pub fn find(&mut self, key: &Field) -> io::IoResult<(Option<uint>, uint)> {
+        let entry = (key.clone(), 0);
+        let root = self.root;
+        let mut node = try!(self.read_node(root));
+        let mut nodes_read = 1;
+        while !node.leaf {
+            node = try!(self.read_node(node.child(node.child_slot(&entry))));
+            nodes_read += 1;
+        }
+
+        // Entries with `key` may start on a following leaf, possibly after empty ones.
+        let mut slot = lower_bound(node.keys.as_slice(), &entry, false);
+        while slot == node.keys.len() && node.link != 0 {
+            node = try!(self.read_node(node.link));
+            nodes_read += 1;
+            slot = 0;
+        }
+
+        if slot < node.keys.len() {
+            let &(ref found, pos) = node.keys.get(slot);
+            if *found == *key {
+                return Ok((Some(pos as uint), nodes_read));
+            }
+        }
+        Ok((None, nodes_read))
+    }
+}
+
+// Iterates over the records whose indexed field lies between two bounds, in key order.
+pub struct IndexScan<'table> {
+    rows: PhysicalTableIterator<'table>,
+    index: uint,
+    low: Bound,
+    high: Bound,
+
+    leaf: Option<Node>,
+    slot: uint,
+    started: bool,
+    // Whether the current probe has passed the Bloom filter but not yet produced a record.
+    probe_pending: bool,
+
+    pub index_blocks_accessed: uint,
+}
+
+impl<'table> IndexScan<'table> {
+    // Fails if `index` isn't one of the table's B+trees.
+    pub fn new(mut rows: PhysicalTableIterator<'table>, index: uint, low: Bound, high: Bound)
+            -> Result<IndexScan<'table>, TableError> {
+        try!(rows.table.indexes.get_mut(index).btree());
+        Ok(IndexScan {
+            rows: rows,
+            index: index,
+            low: low,
+            high: high,
+
+            leaf: None,
+            slot: 0,
+            started: false,
+            probe_pending: false,
+
+            index_blocks_accessed: 0,
+        })
+    }
+
+    fn read_node(&mut self, page: u32) -> Node {
+        self.index_blocks_accessed += 1;
+        self.rows.table.indexes.get_mut(self.index).btree().unwrap().read_node(page).unwrap()
+    }
+
+    // Descends to the first leaf entry that can satisfy the lower bound.
+    fn seek_low(&mut self) {
+        let start = match self.low {
+            Unbounded => None,
+            Included(ref k) => Some((k.clone(), 0)),
+            Excluded(ref k) => Some((k.clone(), u32::MAX)),
+        };
+
+        let root = self.rows.table.indexes.get_mut(self.index).btree().unwrap().root;
+        let mut node = self.read_node(root);
+        while !node.leaf {
+            let child = match start {
+                None => node.link,
+                Some(ref entry) => node.child(node.child_slot(entry)),
+            };
+            node = self.read_node(child);
+        }
+
+        self.slot = match start {
+            None => 0,
+            Some(ref entry) => lower_bound(node.keys.as_slice(), entry, false),
+        };
+        self.leaf = Some(node);
+    }
+
+    fn next_position(&mut self) -> Option<uint> {
+        if !self.started {
+            self.started = true;
+            self.seek_low();
+        }
+
+        loop {
+            let next_leaf = match self.leaf {
+                None => return None,
+                Some(ref leaf) => {
+                    if self.slot < leaf.keys.len() {
+                        let &(ref key, pos) = leaf.keys.get(self.slot);
+                        if !self.high.admits_below(key) {
+                            None
+                        } else if !self.low.admits_above(key) {
+                            self.slot += 1;
+                            continue;
+                        } else {
+                            self.slot += 1;
+                            return Some(pos as uint);
+                        }
+                    } else if leaf.link != 0 {
+                        Some(leaf.link)
+                    } else {
+                        None
+                    }
+                },
+            };
+
+            match next_leaf {
+                None => {
+                    self.leaf = None;
+                    return None;
+                },
+                Some(page) => {
+                    let node = self.read_node(page);
+                    self.leaf = Some(node);
+                    self.slot = 0;
+                },
+            }
+        }
+    }
+}
+
+impl<'table> Iterator<Vec<Field>> for IndexScan<'table> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            match self.next_position() {
+                None => {
+                    if self.probe_pending {
+                        self.probe_pending = false;
+                        let field = self.rows.table.indexes.get(self.index).field;
+                        self.rows.table.bloom_false_positive(field);
+                    }
+                    return None;
+                },
+                Some(pos) => match self.rows.idx(pos) {
+                    None => continue,
+                    Some(values) => {
+                        self.probe_pending = false;
+                        return Some(values);
+                    },
+                },
+            }
+        }
+    }
+}
+
+impl<'table> TableIterator for IndexScan<'table> {
+    fn blocks_accessed(&self) -> uint {
+        self.rows.blocks_accessed() + self.index_blocks_accessed
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.rows.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.rows.schema()
+    }
+}
+
+impl<'table> ProbeIterator for IndexScan<'table> {
+    fn probe(&mut self, key: &Field) {
+        self.low = Included(key.clone());
+        self.high = Included(key.clone());
+        self.leaf = None;
+
+        // A key rejected by the Bloom filter ends the scan without reading any nodes.
+        let field = self.rows.table.indexes.get(self.index).field;
+        let may_contain = self.rows.table.bloom_may_contain(field, key);
+        self.started = !may_contain;
+        self.probe_pending = may_contain;
+    }
+
+    fn index_blocks_accessed(&self) -> uint {
+        self.index_blocks_accessed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing;
+    use super::super::{
+        BTreeIndexType,
+        Bound,
+        Excluded,
+        Included,
+        Integer,
+        IntegerType,
+        Table,
+        Unbounded,
+    };
+    use super::{BTree, NODE_ORDER};
+
+    #[test]
+    fn splits_keep_every_Integer() {
+        let db = testing::scratch_db();
+        let mut tree = BTree::create(&db.path().join("t.btree"), IntegerType, 4).unwrap();
+        // Inserted out of order, enough for the root to split twice.
+        let n = NODE_ORDER * NODE_ORDER * 2;
+        for i in range(0, n) {
+            let key = (i * 7919) % n;
+            tree.insert(&Integer(key as u32), key).unwrap();
+        }
+        assert!(tree.root != 1);
+
+        let mut tree = BTree::open(&db.path().join("t.btree")).unwrap();
+        for key in range(0, n) {
+            assert_eq!(tree.find(&Integer(key as u32)).unwrap().val0(), Some(key));
+        }
+        // The root, an inner node and a leaf.
+        assert_eq!(tree.find(&Integer(0)).unwrap().val1(), 3);
+        assert_eq!(tree.find(&Integer(n as u32)).unwrap().val0(), None);
+    }
+
+    #[test]
+    fn duplicate_keys_across_leaves() {
+        let db = testing::scratch_db();
+        let mut tree = BTree::create(&db.path().join("t.btree"), IntegerType, 4).unwrap();
+        for pos in range(0, NODE_ORDER * 3) {
+            tree.insert(&Integer(5), pos).unwrap();
+        }
+        tree.insert(&Integer(4), 1000).unwrap();
+        tree.insert(&Integer(6), 1001).unwrap();
+
+        // With the first leaves holding the key emptied, lookups go on to later ones.
+        for pos in range(0, NODE_ORDER * 3 - 1) {
+            tree.remove(&Integer(5), pos).unwrap();
+        }
+        assert_eq!(tree.find(&Integer(5)).unwrap().val0(), Some(NODE_ORDER * 3 - 1));
+        tree.remove(&Integer(5), NODE_ORDER * 3 - 1).unwrap();
+        assert_eq!(tree.find(&Integer(5)).unwrap().val0(), None);
+        assert_eq!(tree.find(&Integer(6)).unwrap().val0(), Some(1001));
+
+        // Removing an entry that isn't there changes nothing.
+        tree.remove(&Integer(4), 999).unwrap();
+        assert_eq!(tree.find(&Integer(4)).unwrap().val0(), Some(1000));
+    }
+
+    fn ids(table: &mut Table, name: &str, low: Bound, high: Bound) -> Vec<u32> {
+        table.index_scan(name, low, high).unwrap().map(|values| match *values.get(0) {
+            Integer(id) => id,
+            _ => fail!("expected an Integer id"),
+        }).collect()
+    }
+
+    #[test]
+    fn range_bounds() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 10);
+        table.create_index("id_btree", "id", BTreeIndexType).unwrap();
+        table.create_index("value_btree", "value", BTreeIndexType).unwrap();
+
+        assert_eq!(ids(&mut table, "id_btree", Included(Integer(10)), Excluded(Integer(15))),
+                   vec![10, 11, 12, 13, 14]);
+        assert_eq!(ids(&mut table, "id_btree", Excluded(Integer(10)), Included(Integer(13))),
+                   vec![11, 12, 13]);
+        assert_eq!(ids(&mut table, "id_btree", Unbounded, Excluded(Integer(3))), vec![0, 1, 2]);
+        assert_eq!(ids(&mut table, "id_btree", Included(Integer(97)), Unbounded), vec![97, 98, 99]);
+        assert_eq!(ids(&mut table, "id_btree", Excluded(Integer(99)), Unbounded), vec![]);
+        assert_eq!(ids(&mut table, "id_btree", Unbounded, Unbounded).len(), 100);
+
+        // Records with equal keys come in position order, and deleted ones are gone.
+        table.delete_entry(23).unwrap();
+        assert_eq!(ids(&mut table, "value_btree", Included(Integer(3)), Included(Integer(3))),
+                   vec![3, 13, 33, 43, 53, 63, 73, 83, 93]);
+    }
+}
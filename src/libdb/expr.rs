@@ -0,0 +1,966 @@
+use collections::HashMap;
+use regex::Regex;
+use std::cmp::{max, min};
+use std::fmt;
+use std::num::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
+
+use super::pattern::like_match;
+use super::{
+    ArgumentCountError,
+    ExprTypeError,
+    Field,
+    FieldIndexError,
+    FieldSchema,
+    FieldType,
+    Integer,
+    IntegerType,
+    Null,
+    PatternError,
+    TableError,
+    TableSchema,
+    Text,
+    TextType,
+};
+
+#[deriving(Clone, Decodable, Encodable, Eq)]
+pub enum Comparison {
+    EqualTo,
+    NotEqualTo,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
+impl fmt::Show for Comparison {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match *self {
+            EqualTo => "=",
+            NotEqualTo => "<>",
+            LessThan => "<",
+            LessOrEqual => "<=",
+            GreaterThan => ">",
+            GreaterOrEqual => ">=",
+        })
+    }
+}
+
+// Integer arithmetic. Fields are unsigned, so results below zero are out of range like
+// overflows are, and give NULL, as does division by zero.
+#[deriving(Clone, Decodable, Encodable, Eq)]
+pub enum ArithmeticOp {
+    AddOp,
+    SubtractOp,
+    MultiplyOp,
+    DivideOp,
+    ModuloOp,
+}
+
+impl fmt::Show for ArithmeticOp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match *self {
+            AddOp => "+",
+            SubtractOp => "-",
+            MultiplyOp => "*",
+            DivideOp => "/",
+            ModuloOp => "%",
+        })
+    }
+}
+
+#[deriving(Clone, Decodable, Encodable, Eq)]
+pub enum Function {
+    UpperFn,
+    LowerFn,
+    // Length in characters.
+    LengthFn,
+    // Concatenation of any number of values, Integers included.
+    ConcatFn,
+    // substr(text, start, length), with `start` counted in characters from 1. Without a length,
+    // goes to the end of the text.
+    SubstrFn,
+}
+
+impl fmt::Show for Function {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match *self {
+            UpperFn => "upper",
+            LowerFn => "lower",
+            LengthFn => "length",
+            ConcatFn => "concat",
+            SubstrFn => "substr",
+        })
+    }
+}
+
+// Expressions over the fields of a record. Unlike closures they can be inspected, such as for
+// index selection, and serialized along with schemas.
+//
+// Comparisons and boolean operators follow SQL's three-valued logic: a comparison with NULL is
+// unknown, and a condition that ends up unknown rejects the record. Arithmetic and functions
+// return NULL if any operand is NULL.
+#[deriving(Clone, Decodable, Encodable, Eq, Show)]
+pub enum Expr {
+    FieldExpr(String),
+    // Field by position, which is what `bind` turns names into.
+    ColumnExpr(uint),
+    LiteralExpr(Field),
+    CompareExpr(Comparison, Box<Expr>, Box<Expr>),
+    AndExpr(Box<Expr>, Box<Expr>),
+    OrExpr(Box<Expr>, Box<Expr>),
+    NotExpr(Box<Expr>),
+    IsNullExpr(Box<Expr>),
+    ArithmeticExpr(ArithmeticOp, Box<Expr>, Box<Expr>),
+    CallExpr(Function, Vec<Expr>),
+    // CASE WHEN condition THEN value ... ELSE value END. Without an ELSE, records matching no
+    // condition get NULL.
+    CaseExpr(Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    // Pattern matches on Text. LIKE patterns use `%` and `_` as wildcards, and ILIKE ignores
+    // case. Regexes match anywhere in the text unless anchored.
+    LikeExpr(Box<Expr>, String),
+    ILikeExpr(Box<Expr>, String),
+    RegexMatchExpr(Box<Expr>, String),
+}
+
+#[deriving(Clone, Eq, Show)]
+pub enum ExprType {
+    ValueType(FieldType),
+    // The NULL literal, which fits wherever a value does.
+    NullType,
+    BooleanType,
+}
+
+fn expect_value(op: String, t: ExprType, expected: FieldType) -> Result<(), TableError> {
+    match t {
+        ValueType(actual) if actual == expected => Ok(()),
+        NullType => Ok(()),
+        _ => Err(ExprTypeError(op, t)),
+    }
+}
+
+fn expect_any_value(op: String, t: ExprType) -> Result<(), TableError> {
+    match t {
+        BooleanType => Err(ExprTypeError(op, t)),
+        _ => Ok(()),
+    }
+}
+
+fn expect_boolean(op: String, t: ExprType) -> Result<(), TableError> {
+    match t {
+        BooleanType | NullType => Ok(()),
+        _ => Err(ExprTypeError(op, t)),
+    }
+}
+
+fn check_call(f: Function, types: &[ExprType]) -> Result<ExprType, TableError> {
+    let name = format!("{}", f);
+    let arity_ok = match f {
+        UpperFn | LowerFn | LengthFn => types.len() == 1,
+        ConcatFn => types.len() >= 1,
+        SubstrFn => types.len() == 2 || types.len() == 3,
+    };
+    if !arity_ok {
+        return Err(ArgumentCountError(name, types.len()));
+    }
+
+    match f {
+        UpperFn | LowerFn => {
+            try!(expect_value(name, types[0], TextType));
+            Ok(ValueType(TextType))
+        },
+        LengthFn => {
+            try!(expect_value(name, types[0], TextType));
+            Ok(ValueType(IntegerType))
+        },
+        ConcatFn => {
+            for &t in types.iter() {
+                try!(expect_any_value(name.clone(), t));
+            }
+            Ok(ValueType(TextType))
+        },
+        SubstrFn => {
+            try!(expect_value(name.clone(), types[0], TextType));
+            for &t in types.slice_from(1).iter() {
+                try!(expect_value(name.clone(), t, IntegerType));
+            }
+            Ok(ValueType(TextType))
+        },
+    }
+}
+
+// Type checks `expr` against `schema` and replaces its field names by positions.
+fn resolve(expr: &Expr, schema: &TableSchema) -> Result<(Expr, ExprType), TableError> {
+    match *expr {
+        FieldExpr(ref name) => {
+            let i = try!(schema.find_field(name.as_slice()));
+            Ok((ColumnExpr(i), ValueType(schema.fields.get(i).data_type)))
+        },
+        ColumnExpr(i) => {
+            if i >= schema.fields.len() {
+                return Err(FieldIndexError(i, schema.fields.len()));
+            }
+            Ok((ColumnExpr(i), ValueType(schema.fields.get(i).data_type)))
+        },
+        LiteralExpr(ref value) => {
+            let t = match value.get_type() {
+                Some(t) => ValueType(t),
+                None => NullType,
+            };
+            Ok((LiteralExpr(value.clone()), t))
+        },
+        CompareExpr(op, ref l, ref r) => {
+            let (l, lt) = try!(resolve(&**l, schema));
+            let (r, rt) = try!(resolve(&**r, schema));
+            try!(expect_any_value(format!("{}", op), lt));
+            match lt {
+                ValueType(t) => try!(expect_value(format!("{}", op), rt, t)),
+                _ => try!(expect_any_value(format!("{}", op), rt)),
+            }
+            Ok((CompareExpr(op, box l, box r), BooleanType))
+        },
+        AndExpr(ref l, ref r) => {
+            let (l, lt) = try!(resolve(&**l, schema));
+            let (r, rt) = try!(resolve(&**r, schema));
+            try!(expect_boolean("AND".to_strbuf(), lt));
+            try!(expect_boolean("AND".to_strbuf(), rt));
+            Ok((AndExpr(box l, box r), BooleanType))
+        },
+        OrExpr(ref l, ref r) => {
+            let (l, lt) = try!(resolve(&**l, schema));
+            let (r, rt) = try!(resolve(&**r, schema));
+            try!(expect_boolean("OR".to_strbuf(), lt));
+            try!(expect_boolean("OR".to_strbuf(), rt));
+            Ok((OrExpr(box l, box r), BooleanType))
+        },
+        NotExpr(ref e) => {
+            let (e, t) = try!(resolve(&**e, schema));
+            try!(expect_boolean("NOT".to_strbuf(), t));
+            Ok((NotExpr(box e), BooleanType))
+        },
+        IsNullExpr(ref e) => {
+            let (e, t) = try!(resolve(&**e, schema));
+            try!(expect_any_value("IS NULL".to_strbuf(), t));
+            Ok((IsNullExpr(box e), BooleanType))
+        },
+        ArithmeticExpr(op, ref l, ref r) => {
+            let (l, lt) = try!(resolve(&**l, schema));
+            let (r, rt) = try!(resolve(&**r, schema));
+            try!(expect_value(format!("{}", op), lt, IntegerType));
+            try!(expect_value(format!("{}", op), rt, IntegerType));
+            Ok((ArithmeticExpr(op, box l, box r), ValueType(IntegerType)))
+        },
+        CallExpr(f, ref args) => {
+            let mut resolved = Vec::with_capacity(args.len());
+            let mut types = Vec::with_capacity(args.len());
+            for arg in args.iter() {
+                let (arg, t) = try!(resolve(arg, schema));
+                resolved.push(arg);
+                types.push(t);
+            }
+            let t = try!(check_call(f, types.as_slice()));
+            Ok((CallExpr(f, resolved), t))
+        },
+        CaseExpr(ref branches, ref default) => {
+            let mut case_type = NullType;
+            let mut resolved = Vec::with_capacity(branches.len());
+            for &(ref condition, ref value) in branches.iter() {
+                let (condition, t) = try!(resolve(condition, schema));
+                try!(expect_boolean("CASE".to_strbuf(), t));
+                let value = try!(resolve_case_value(value, schema, &mut case_type));
+                resolved.push((condition, value));
+            }
+            let default = match *default {
+                Some(ref value) =>
+                    Some(box try!(resolve_case_value(&**value, schema, &mut case_type))),
+                None => None,
+            };
+            Ok((CaseExpr(resolved, default), case_type))
+        },
+        LikeExpr(ref e, ref pattern) => {
+            let (e, t) = try!(resolve(&**e, schema));
+            try!(expect_value("LIKE".to_strbuf(), t, TextType));
+            Ok((LikeExpr(box e, pattern.clone()), BooleanType))
+        },
+        ILikeExpr(ref e, ref pattern) => {
+            let (e, t) = try!(resolve(&**e, schema));
+            try!(expect_value("ILIKE".to_strbuf(), t, TextType));
+            Ok((ILikeExpr(box e, pattern.clone()), BooleanType))
+        },
+        RegexMatchExpr(ref e, ref pattern) => {
+            let (e, t) = try!(resolve(&**e, schema));
+            try!(expect_value("~".to_strbuf(), t, TextType));
+            Ok((RegexMatchExpr(box e, pattern.clone()), BooleanType))
+        },
+    }
+}
+
+// Compiles each distinct regex of an expression once, so records don't have to.
+fn compile_regexes(expr: &Expr, regexes: &mut HashMap<String, Regex>) -> Result<(), TableError> {
+    match *expr {
+        RegexMatchExpr(ref e, ref pattern) => {
+            if !regexes.contains_key(pattern) {
+                let regex = match Regex::new(pattern.as_slice()) {
+                    Ok(regex) => regex,
+                    Err(e) => return Err(PatternError(pattern.clone(), format!("{}", e))),
+                };
+                regexes.insert(pattern.clone(), regex);
+            }
+            compile_regexes(&**e, regexes)
+        },
+        CompareExpr(_, ref l, ref r) | ArithmeticExpr(_, ref l, ref r) |
+        AndExpr(ref l, ref r) | OrExpr(ref l, ref r) => {
+            try!(compile_regexes(&**l, regexes));
+            compile_regexes(&**r, regexes)
+        },
+        NotExpr(ref e) | IsNullExpr(ref e) | LikeExpr(ref e, _) | ILikeExpr(ref e, _) =>
+            compile_regexes(&**e, regexes),
+        CallExpr(_, ref args) => {
+            for arg in args.iter() {
+                try!(compile_regexes(arg, regexes));
+            }
+            Ok(())
+        },
+        CaseExpr(ref branches, ref default) => {
+            for &(ref condition, ref value) in branches.iter() {
+                try!(compile_regexes(condition, regexes));
+                try!(compile_regexes(value, regexes));
+            }
+            match *default {
+                Some(ref value) => compile_regexes(&**value, regexes),
+                None => Ok(()),
+            }
+        },
+        FieldExpr(_) | ColumnExpr(_) | LiteralExpr(_) => Ok(()),
+    }
+}
+
+// Resolves a result of a CASE expression. Its type is that of the first result that isn't NULL,
+// which all the others must share.
+fn resolve_case_value(value: &Expr, schema: &TableSchema, case_type: &mut ExprType)
+        -> Result<Expr, TableError> {
+    let (value, t) = try!(resolve(value, schema));
+    match *case_type {
+        ValueType(expected) => try!(expect_value("CASE".to_strbuf(), t, expected)),
+        _ => {
+            try!(expect_any_value("CASE".to_strbuf(), t));
+            *case_type = t;
+        },
+    }
+    Ok(value)
+}
+
+// Largest number of characters in the decimal form of an Integer.
+static INTEGER_DIGITS : uint = 10;
+
+// Bytes needed to store a result of a bound expression, including the length byte of Text
+// fields. Case mapping can lengthen some characters and lengths are capped at what a Text field
+// holds, so `truncate_text` cuts longer results down.
+fn value_length(expr: &Expr, schema: &TableSchema) -> uint {
+    let length = match *expr {
+        ColumnExpr(i) => return schema.fields.get(i).length,
+        LiteralExpr(Text(ref s)) => s.len() + 1,
+        CallExpr(UpperFn, ref args) | CallExpr(LowerFn, ref args) |
+        CallExpr(SubstrFn, ref args) => value_length(args.get(0), schema),
+        CallExpr(ConcatFn, ref args) =>
+            args.iter().map(|arg| text_chars(arg, schema)).fold(1, |a, b| a + b),
+        CaseExpr(ref branches, ref default) => {
+            let lengths = branches.iter().map(|&(_, ref value)| value_length(value, schema));
+            let length = lengths.fold(0, |a, b| max(a, b));
+            match *default {
+                Some(ref value) => max(length, value_length(&**value, schema)),
+                None => length,
+            }
+        },
+        _ => 4,
+    };
+    // Text fields can't hold more than 255 bytes.
+    min(length, 256)
+}
+
+// Cuts Text values longer than a field of `length` bytes holds at the last character that fits.
+// A field of no bytes has no room for the terminator either, and holds the empty string.
+pub fn truncate_text(value: Field, length: uint) -> Field {
+    match value {
+        Text(ref s) if s.len() >= length => {
+            let mut end = if length == 0 { 0 } else { length - 1 };
+            while !s.as_slice().is_char_boundary(end) {
+                end -= 1;
+            }
+            Text(s.as_slice().slice_to(end).to_strbuf())
+        },
+        value => value,
+    }
+}
+
+// Most characters the value of a bound expression can take as text.
+fn text_chars(expr: &Expr, schema: &TableSchema) -> uint {
+    match resolve(expr, schema) {
+        Ok((_, ValueType(TextType))) => value_length(expr, schema) - 1,
+        _ => INTEGER_DIGITS,
+    }
+}
+
+// The record an expression is evaluated on. Join conditions see the records of both inputs as
+// one, without concatenating them.
+struct Row<'a> {
+    first: &'a Vec<Field>,
+    second: Option<&'a Vec<Field>>,
+    regexes: &'a HashMap<String, Regex>,
+}
+
+impl<'a> Row<'a> {
+    fn get(&self, i: uint) -> &'a Field {
+        match self.second {
+            Some(second) if i >= self.first.len() => second.get(i - self.first.len()),
+            _ => self.first.get(i),
+        }
+    }
+}
+
+// None if the result is out of range or undefined.
+fn arithmetic(op: ArithmeticOp, x: u32, y: u32) -> Option<u32> {
+    match op {
+        AddOp => x.checked_add(&y),
+        SubtractOp => x.checked_sub(&y),
+        MultiplyOp => x.checked_mul(&y),
+        DivideOp => x.checked_div(&y),
+        ModuloOp => if y == 0 { None } else { Some(x % y) },
+    }
+}
+
+fn call(f: Function, args: Vec<Field>) -> Field {
+    if args.iter().any(|arg| *arg == Null) {
+        return Null;
+    }
+
+    match (f, args.as_slice()) {
+        (UpperFn, [Text(ref s)]) => Text(s.as_slice().chars().map(|c| c.to_uppercase()).collect()),
+        (LowerFn, [Text(ref s)]) => Text(s.as_slice().chars().map(|c| c.to_lowercase()).collect()),
+        (LengthFn, [Text(ref s)]) => Integer(s.as_slice().char_len() as u32),
+        (ConcatFn, args) => {
+            let mut result = String::new();
+            for arg in args.iter() {
+                result.push_str(format!("{}", arg).as_slice());
+            }
+            Text(result)
+        },
+        (SubstrFn, [Text(ref s), Integer(start), ..rest]) => {
+            let skip = if start == 0 { 0 } else { start as uint - 1 };
+            let chars = s.as_slice().chars().skip(skip);
+            Text(match rest {
+                [Integer(length)] => chars.take(length as uint).collect(),
+                _ => chars.collect(),
+            })
+        },
+        _ => fail!("Invalid arguments to `{}`.", f),
+    }
+}
+
+fn eval(expr: &Expr, row: &Row) -> Field {
+    match *expr {
+        ColumnExpr(i) => row.get(i).clone(),
+        LiteralExpr(ref value) => value.clone(),
+        ArithmeticExpr(op, ref l, ref r) => match (eval(&**l, row), eval(&**r, row)) {
+            (Integer(x), Integer(y)) => arithmetic(op, x, y).map_or(Null, |z| Integer(z)),
+            _ => Null,
+        },
+        CallExpr(f, ref args) => call(f, args.iter().map(|arg| eval(arg, row)).collect()),
+        CaseExpr(ref branches, ref default) => {
+            for &(ref condition, ref value) in branches.iter() {
+                if truth(condition, row) == Some(true) {
+                    return eval(value, row);
+                }
+            }
+            match *default {
+                Some(ref value) => eval(&**value, row),
+                None => Null,
+            }
+        },
+        FieldExpr(ref name) => fail!("Field `{}` was never bound to a schema.", name),
+        _ => fail!("Condition `{}` used as a value.", expr),
+    }
+}
+
+// None is SQL's unknown.
+fn truth(expr: &Expr, row: &Row) -> Option<bool> {
+    match *expr {
+        LiteralExpr(Null) => None,
+        CompareExpr(op, ref l, ref r) => {
+            let (l, r) = (eval(&**l, row), eval(&**r, row));
+            if l == Null || r == Null {
+                return None;
+            }
+            Some(match op {
+                EqualTo => l == r,
+                NotEqualTo => l != r,
+                LessThan => l < r,
+                LessOrEqual => l <= r,
+                GreaterThan => l > r,
+                GreaterOrEqual => l >= r,
+            })
+        },
+        AndExpr(ref l, ref r) => match truth(&**l, row) {
+            Some(false) => Some(false),
+            lt => match (lt, truth(&**r, row)) {
+                (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+        },
+        OrExpr(ref l, ref r) => match truth(&**l, row) {
+            Some(true) => Some(true),
+            lt => match (lt, truth(&**r, row)) {
+                (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        },
+        NotExpr(ref e) => truth(&**e, row).map(|b| !b),
+        IsNullExpr(ref e) => Some(eval(&**e, row) == Null),
+        LikeExpr(ref e, ref pattern) => match eval(&**e, row) {
+            Text(ref s) => Some(like_match(pattern.as_slice(), s.as_slice(), false)),
+            _ => None,
+        },
+        ILikeExpr(ref e, ref pattern) => match eval(&**e, row) {
+            Text(ref s) => Some(like_match(pattern.as_slice(), s.as_slice(), true)),
+            _ => None,
+        },
+        RegexMatchExpr(ref e, ref pattern) => match eval(&**e, row) {
+            Text(ref s) => Some(row.regexes.get(pattern).is_match(s.as_slice())),
+            _ => None,
+        },
+        _ => fail!("Value `{}` used as a condition.", expr),
+    }
+}
+
+// An expression that passed type checking against a schema, ready to be evaluated on its
+// records.
+pub struct BoundExpr {
+    expr: Expr,
+    pub expr_type: ExprType,
+    regexes: HashMap<String, Regex>,
+}
+
+pub fn bind(expr: &Expr, schema: &TableSchema) -> Result<BoundExpr, TableError> {
+    let (expr, expr_type) = try!(resolve(expr, schema));
+    let mut regexes = HashMap::new();
+    try!(compile_regexes(&expr, &mut regexes));
+    Ok(BoundExpr { expr: expr, expr_type: expr_type, regexes: regexes })
+}
+
+// Like `bind`, but also requires the expression to be a condition.
+pub fn bind_condition(expr: &Expr, schema: &TableSchema) -> Result<BoundExpr, TableError> {
+    let bound = try!(bind(expr, schema));
+    try!(expect_boolean("condition".to_strbuf(), bound.expr_type));
+    Ok(bound)
+}
+
+impl BoundExpr {
+    // The expression with its fields referred to by position.
+    pub fn expr<'s>(&'s self) -> &'s Expr {
+        &self.expr
+    }
+
+    // Schema of a field holding the values of the expression, which must have been bound to
+    // `schema`. Fails for conditions and the NULL literal, whose type isn't a field type.
+    pub fn field_schema(&self, name: &str, offset: uint, schema: &TableSchema)
+            -> Result<FieldSchema, TableError> {
+        let data_type = match self.expr_type {
+            ValueType(t) => t,
+            t => return Err(ExprTypeError(name.to_strbuf(), t)),
+        };
+        Ok(FieldSchema {
+            name: name.to_strbuf(),
+            offset: offset,
+            data_type: data_type,
+            length: value_length(&self.expr, schema),
+            auto_increment: None,
+        })
+    }
+
+    pub fn eval(&self, values: &Vec<Field>) -> Field {
+        eval(&self.expr, &Row { first: values, second: None, regexes: &self.regexes })
+    }
+
+    // Whether a record satisfies the condition. Unknown counts as false.
+    pub fn test(&self, values: &Vec<Field>) -> bool {
+        let row = Row { first: values, second: None, regexes: &self.regexes };
+        truth(&self.expr, &row) == Some(true)
+    }
+
+    // Tests a condition bound to the concatenated schema of two inputs on a pair of their
+    // records.
+    pub fn test_pair(&self, a: &Vec<Field>, b: &Vec<Field>) -> bool {
+        let row = Row { first: a, second: Some(b), regexes: &self.regexes };
+        truth(&self.expr, &row) == Some(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{
+        ArgumentCountError,
+        ExprTypeError,
+        Field,
+        FieldIndexError,
+        FieldSchema,
+        FieldType,
+        Integer,
+        IntegerType,
+        Null,
+        PatternError,
+        TableError,
+        TableSchema,
+        Text,
+        TextType,
+    };
+    use super::{
+        AddOp,
+        AndExpr,
+        ArithmeticExpr,
+        ArithmeticOp,
+        BooleanType,
+        CallExpr,
+        CaseExpr,
+        ColumnExpr,
+        CompareExpr,
+        ConcatFn,
+        DivideOp,
+        EqualTo,
+        Expr,
+        FieldExpr,
+        GreaterThan,
+        IsNullExpr,
+        LengthFn,
+        LessThan,
+        LiteralExpr,
+        ModuloOp,
+        MultiplyOp,
+        NotExpr,
+        NullType,
+        OrExpr,
+        RegexMatchExpr,
+        Row,
+        SubstrFn,
+        SubtractOp,
+        UpperFn,
+        ValueType,
+        bind,
+        truncate_text,
+        truth,
+    };
+
+    fn field_schema(name: &str, offset: uint, data_type: FieldType, length: uint) -> FieldSchema {
+        FieldSchema {
+            name: name.to_strbuf(),
+            offset: offset,
+            data_type: data_type,
+            length: length,
+            auto_increment: None,
+        }
+    }
+
+    // A single Integer `x`.
+    fn schema() -> TableSchema {
+        TableSchema {
+            name: "T".to_strbuf(),
+            fields: vec![field_schema("x", 0, IntegerType, 4)],
+            entry_stride: 4,
+            primary_key: None,
+        }
+    }
+
+    // An Integer `x` and a Text `name` of 20 bytes.
+    fn mixed_schema() -> TableSchema {
+        TableSchema {
+            name: "T".to_strbuf(),
+            fields: vec![field_schema("x", 0, IntegerType, 4),
+                         field_schema("name", 4, TextType, 20)],
+            entry_stride: 24,
+            primary_key: None,
+        }
+    }
+
+    fn field(name: &str) -> Box<Expr> {
+        box FieldExpr(name.to_strbuf())
+    }
+
+    fn int(x: u32) -> Box<Expr> {
+        box LiteralExpr(Integer(x))
+    }
+
+    fn text(s: &str) -> Box<Expr> {
+        box LiteralExpr(Text(s.to_strbuf()))
+    }
+
+    // A record of `mixed_schema()`.
+    fn record(x: Field, name: &str) -> Vec<Field> {
+        vec![x, Text(name.to_strbuf())]
+    }
+
+    // The error binding `expr` to `mixed_schema()` fails with.
+    fn bind_error(expr: Expr) -> TableError {
+        match bind(&expr, &mixed_schema()) {
+            Err(e) => e,
+            Ok(_) => fail!("expected {} not to bind", expr),
+        }
+    }
+
+    // Truth value of a condition on the record `[x]`, with None for unknown.
+    fn truth_of(condition: Expr, x: Field) -> Option<bool> {
+        let bound = bind(&condition, &schema()).unwrap();
+        let values = vec![x];
+        truth(bound.expr(), &Row { first: &values, second: None, regexes: &bound.regexes })
+    }
+
+    fn eval_x(op: ArithmeticOp, x: u32, y: u32) -> Field {
+        let expr = ArithmeticExpr(op, box ColumnExpr(0), box LiteralExpr(Integer(y)));
+        bind(&expr, &schema()).unwrap().eval(&vec![Integer(x)])
+    }
+
+    #[test]
+    fn arithmetic_out_of_range() {
+        assert_eq!(eval_x(SubtractOp, 7, 5), Integer(2));
+        assert_eq!(eval_x(SubtractOp, 3, 5), Null);
+        assert_eq!(eval_x(MultiplyOp, 1 << 30, 10), Null);
+        assert_eq!(eval_x(DivideOp, 7, 0), Null);
+        assert_eq!(eval_x(ModuloOp, 7, 0), Null);
+        assert_eq!(eval_x(ModuloOp, 7, 4), Integer(3));
+    }
+
+    #[test]
+    fn three_valued_logic() {
+        // With x NULL, comparing it gives unknown, while testing it for NULL is true.
+        let unknown = CompareExpr(EqualTo, box ColumnExpr(0), box LiteralExpr(Integer(1)));
+        let yes = IsNullExpr(box ColumnExpr(0));
+        let no = NotExpr(box yes.clone());
+        assert_eq!(truth_of(unknown.clone(), Null), None);
+        assert_eq!(truth_of(NotExpr(box unknown.clone()), Null), None);
+        assert_eq!(truth_of(LiteralExpr(Null), Null), None);
+
+        // AND is false if either side is, OR is true if either side is, and otherwise an
+        // unknown side makes the result unknown.
+        assert_eq!(truth_of(AndExpr(box unknown.clone(), box no.clone()), Null), Some(false));
+        assert_eq!(truth_of(AndExpr(box no.clone(), box unknown.clone()), Null), Some(false));
+        assert_eq!(truth_of(AndExpr(box unknown.clone(), box yes.clone()), Null), None);
+        assert_eq!(truth_of(OrExpr(box unknown.clone(), box yes.clone()), Null), Some(true));
+        assert_eq!(truth_of(OrExpr(box yes.clone(), box unknown.clone()), Null), Some(true));
+        assert_eq!(truth_of(OrExpr(box unknown.clone(), box no.clone()), Null), None);
+        assert_eq!(truth_of(NotExpr(box AndExpr(box unknown.clone(), box no.clone())), Null),
+                   Some(true));
+
+        // An unknown condition rejects the record, and so does its negation.
+        let bound = bind(&unknown, &schema()).unwrap();
+        let negated = bind(&NotExpr(box unknown), &schema()).unwrap();
+        assert!(!bound.test(&vec![Null]));
+        assert!(!negated.test(&vec![Null]));
+        assert!(negated.test(&vec![Integer(2)]));
+    }
+
+    #[test]
+    fn comparisons_across_types() {
+        let schema = mixed_schema();
+        match bind(&CompareExpr(EqualTo, field("x"), field("name")), &schema) {
+            Err(ExprTypeError(ref op, ValueType(TextType))) => assert_eq!(op.as_slice(), "="),
+            _ => fail!("expected ExprTypeError"),
+        }
+        match bind(&CompareExpr(LessThan, field("name"), box LiteralExpr(Integer(3))), &schema) {
+            Err(ExprTypeError(ref op, ValueType(IntegerType))) => assert_eq!(op.as_slice(), "<"),
+            _ => fail!("expected ExprTypeError"),
+        }
+        // Conditions aren't values, so they can't be compared either.
+        let is_null = box IsNullExpr(field("x"));
+        match bind(&CompareExpr(EqualTo, is_null, box LiteralExpr(Integer(1))), &schema) {
+            Err(ExprTypeError(_, BooleanType)) => (),
+            _ => fail!("expected ExprTypeError"),
+        }
+
+        // NULL compares with either type.
+        assert!(bind(&CompareExpr(EqualTo, field("name"), box LiteralExpr(Null)), &schema).is_ok());
+        let b = box LiteralExpr(Text("b".to_strbuf()));
+        assert!(bind(&CompareExpr(GreaterThan, field("name"), b), &schema).is_ok());
+    }
+
+    #[test]
+    fn case_expressions() {
+        let schema = mixed_schema();
+        let big = |x| box CompareExpr(GreaterThan, field("x"), int(x));
+
+        // The type is that of the first result that isn't NULL, and the length that of the
+        // longest result.
+        let expr = CaseExpr(vec![(*big(10), LiteralExpr(Null)), (*big(5), *field("name"))],
+                            Some(text("small")));
+        let bound = bind(&expr, &schema).unwrap();
+        assert_eq!(bound.expr_type, ValueType(TextType));
+        assert_eq!(bound.field_schema("c", 0, &schema).unwrap().length, 20);
+        assert_eq!(bound.eval(&record(Integer(11), "abc")), Null);
+        assert_eq!(bound.eval(&record(Integer(7), "abc")), Text("abc".to_strbuf()));
+        assert_eq!(bound.eval(&record(Integer(1), "abc")), Text("small".to_strbuf()));
+        // An unknown condition doesn't select its result.
+        assert_eq!(bound.eval(&record(Null, "abc")), Text("small".to_strbuf()));
+
+        // Without ELSE, no matching branch gives NULL.
+        let bound = bind(&CaseExpr(vec![(*big(5), *field("x"))], None), &schema).unwrap();
+        assert_eq!(bound.expr_type, ValueType(IntegerType));
+        assert_eq!(bound.eval(&record(Integer(7), "")), Integer(7));
+        assert_eq!(bound.eval(&record(Integer(1), "")), Null);
+
+        // Only NULL results leave nothing to store.
+        let bound = bind(&CaseExpr(vec![(*big(5), LiteralExpr(Null))], None), &schema).unwrap();
+        assert_eq!(bound.expr_type, NullType);
+        match bound.field_schema("c", 0, &schema) {
+            Err(ExprTypeError(ref name, NullType)) => assert_eq!(name.as_slice(), "c"),
+            _ => fail!("expected ExprTypeError"),
+        }
+
+        // Results of different types, and conditions that are values, are rejected.
+        match bind_error(CaseExpr(vec![(*big(5), *field("x"))], Some(field("name")))) {
+            ExprTypeError(ref op, ValueType(TextType)) => assert_eq!(op.as_slice(), "CASE"),
+            e => fail!("expected ExprTypeError, got {}", e),
+        }
+        match bind_error(CaseExpr(vec![(*field("x"), *int(1))], None)) {
+            ExprTypeError(ref op, ValueType(IntegerType)) => assert_eq!(op.as_slice(), "CASE"),
+            e => fail!("expected ExprTypeError, got {}", e),
+        }
+    }
+
+    #[test]
+    fn function_types() {
+        let schema = mixed_schema();
+        let mut repeated = String::new();
+        for _ in range(0, 15) {
+            repeated.push_str("abcdef");
+        }
+        // (expression, type, stored length, result on the record [42, "abcdef"])
+        let cases = vec![
+            (CallExpr(UpperFn, vec![*field("name")]), TextType, 20, Text("ABCDEF".to_strbuf())),
+            (CallExpr(LengthFn, vec![*field("name")]), IntegerType, 4, Integer(6)),
+            (CallExpr(SubstrFn, vec![*field("name"), *int(2), *int(3)]), TextType, 20,
+             Text("bcd".to_strbuf())),
+            (CallExpr(SubstrFn, vec![*field("name"), *int(2)]), TextType, 20,
+             Text("bcdef".to_strbuf())),
+            // 19 characters of `name`, 1 of the literal and 10 digits of `x`.
+            (CallExpr(ConcatFn, vec![*field("name"), *text("-"), *field("x")]), TextType, 31,
+             Text("abcdef-42".to_strbuf())),
+            // Lengths are capped at what a Text field holds.
+            (CallExpr(ConcatFn, Vec::from_elem(15, *field("name"))), TextType, 256,
+             Text(repeated)),
+        ];
+        for (expr, data_type, length, result) in cases.move_iter() {
+            let bound = bind(&expr, &schema).unwrap();
+            assert_eq!(bound.expr_type, ValueType(data_type));
+            assert_eq!(bound.field_schema("f", 0, &schema).unwrap().length, length);
+            assert_eq!(bound.eval(&record(Integer(42), "abcdef")), result);
+        }
+
+        // Any NULL argument makes the result NULL.
+        let expr = CallExpr(ConcatFn, vec![*field("name"), *field("x")]);
+        assert_eq!(bind(&expr, &schema).unwrap().eval(&record(Null, "abc")), Null);
+        let expr = CallExpr(SubstrFn, vec![*field("name"), LiteralExpr(Null)]);
+        assert_eq!(bind(&expr, &schema).unwrap().eval(&record(Integer(1), "abc")), Null);
+    }
+
+    #[test]
+    fn type_errors_reported_at_bind() {
+        // (expression, operator, actual type)
+        let cases = vec![
+            (CallExpr(UpperFn, vec![*field("x")]), "upper", ValueType(IntegerType)),
+            (CallExpr(SubstrFn, vec![*field("name"), *field("name")]), "substr",
+             ValueType(TextType)),
+            (CallExpr(ConcatFn, vec![*field("name"), IsNullExpr(field("x"))]), "concat",
+             BooleanType),
+            (ArithmeticExpr(AddOp, field("x"), field("name")), "+", ValueType(TextType)),
+            (AndExpr(field("x"), box IsNullExpr(field("x"))), "AND", ValueType(IntegerType)),
+            (NotExpr(field("name")), "NOT", ValueType(TextType)),
+            (IsNullExpr(box IsNullExpr(field("x"))), "IS NULL", BooleanType),
+        ];
+        for (expr, op, actual) in cases.move_iter() {
+            match bind_error(expr) {
+                ExprTypeError(ref o, t) if o.as_slice() == op && t == actual => (),
+                e => fail!("expected ExprTypeError for `{}`, got {}", op, e),
+            }
+        }
+
+        // (expression, function, argument count)
+        let cases = vec![
+            (CallExpr(UpperFn, vec![*field("name"), *field("name")]), "upper", 2u),
+            (CallExpr(SubstrFn, vec![*field("name")]), "substr", 1),
+            (CallExpr(ConcatFn, vec![]), "concat", 0),
+        ];
+        for (expr, function, count) in cases.move_iter() {
+            match bind_error(expr) {
+                ArgumentCountError(ref f, n) if f.as_slice() == function && n == count => (),
+                e => fail!("expected ArgumentCountError for `{}`, got {}", function, e),
+            }
+        }
+    }
+
+    #[test]
+    fn regex_matching() {
+        let schema = mixed_schema();
+        let regex = |pattern: &str| RegexMatchExpr(field("name"), pattern.to_strbuf());
+
+        // Patterns match anywhere in the value unless anchored.
+        let anchored = bind(&regex("^a.c$"), &schema).unwrap();
+        let unanchored = bind(&regex("b+"), &schema).unwrap();
+        assert!(anchored.test(&record(Integer(0), "abc")));
+        assert!(!anchored.test(&record(Integer(0), "abcd")));
+        assert!(unanchored.test(&record(Integer(0), "abbc")));
+        assert!(!unanchored.test(&record(Integer(0), "ac")));
+
+        // Matching NULL is unknown, so neither the condition nor its negation holds.
+        let negated = bind(&NotExpr(box regex("b+")), &schema).unwrap();
+        assert!(!unanchored.test(&vec![Integer(0), Null]));
+        assert!(!negated.test(&vec![Integer(0), Null]));
+        assert!(negated.test(&record(Integer(0), "ac")));
+
+        // Each distinct pattern is compiled once, wherever it appears.
+        let expr = AndExpr(box regex("b+"), box OrExpr(box regex("b+"), box regex("^a")));
+        assert_eq!(bind(&expr, &schema).unwrap().regexes.len(), 2);
+        let expr = CaseExpr(vec![(regex("^a"), *int(1))], Some(int(2)));
+        let bound = bind(&expr, &schema).unwrap();
+        assert_eq!(bound.eval(&record(Integer(0), "abc")), Integer(1));
+        assert_eq!(bound.eval(&record(Integer(0), "cba")), Integer(2));
+
+        // Only Text can be matched.
+        match bind_error(RegexMatchExpr(field("x"), "1".to_strbuf())) {
+            ExprTypeError(ref op, ValueType(IntegerType)) => assert_eq!(op.as_slice(), "~"),
+            e => fail!("expected ExprTypeError, got {}", e),
+        }
+    }
+
+    #[test]
+    fn invalid_regex_rejected_at_bind() {
+        let invalid = RegexMatchExpr(field("name"), "a(b".to_strbuf());
+        match bind_error(invalid.clone()) {
+            PatternError(ref pattern, _) => assert_eq!(pattern.as_slice(), "a(b"),
+            e => fail!("expected PatternError, got {}", e),
+        }
+        // Also when nested, and before any record is evaluated.
+        let nested = CaseExpr(vec![(NotExpr(box invalid), *int(1))], None);
+        match bind_error(nested) {
+            PatternError(ref pattern, _) => assert_eq!(pattern.as_slice(), "a(b"),
+            e => fail!("expected PatternError, got {}", e),
+        }
+    }
+
+    #[test]
+    fn truncate_text_at_character_boundaries() {
+        let t = |s: &str| Text(s.to_strbuf());
+        // A field of `length` bytes holds `length - 1` bytes of text.
+        assert_eq!(truncate_text(t("abcdef"), 4), t("abc"));
+        assert_eq!(truncate_text(t("abc"), 4), t("abc"));
+        assert_eq!(truncate_text(t("aé"), 3), t("a"));
+        assert_eq!(truncate_text(t("abc"), 0), t(""));
+        assert_eq!(truncate_text(t(""), 0), t(""));
+        assert_eq!(truncate_text(Integer(12345), 1), Integer(12345));
+    }
+
+    #[test]
+    fn column_out_of_range() {
+        match bind(&ColumnExpr(1), &schema()) {
+            Err(FieldIndexError(1, 1)) => (),
+            _ => fail!("expected FieldIndexError"),
+        }
+    }
+}
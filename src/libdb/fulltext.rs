@@ -0,0 +1,402 @@
+use collections::{HashSet, TreeMap};
+use std::default::Default;
+use std::io;
+use std::str;
+
+use super::snapshot::SnapshotFile;
+use super::{
+    Field,
+    FieldType,
+    PhysicalTableIterator,
+    TableIterator,
+    TableSchema,
+    Text,
+    TextType,
+};
+
+#[deriving(Clone, Decodable, Encodable, Eq)]
+pub enum SplitMode {
+    // Words are runs of letters and digits.
+    SplitOnPunctuation,
+    // Words are runs of anything but whitespace, so "e-mail" or "R$10" stay whole.
+    SplitOnWhitespace,
+}
+
+#[deriving(Clone, Decodable, Encodable)]
+pub struct TokenizerConfig {
+    pub case_fold: bool,
+    pub strip_accents: bool,
+    // SplitOnPunctuation if missing, as in catalogs written before it was configurable.
+    pub split: Option<SplitMode>,
+}
+
+impl Default for TokenizerConfig {
+    fn default() -> TokenizerConfig {
+        TokenizerConfig { case_fold: true, strip_accents: true, split: None }
+    }
+}
+
+fn strip_accent(c: char) -> char {
+    match c {
+        'á' | 'à' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+        'Á' | 'À' | 'Â' | 'Ã' | 'Ä' | 'Å' => 'A',
+        'é' | 'è' | 'ê' | 'ë' => 'e',
+        'É' | 'È' | 'Ê' | 'Ë' => 'E',
+        'í' | 'ì' | 'î' | 'ï' => 'i',
+        'Í' | 'Ì' | 'Î' | 'Ï' => 'I',
+        'ó' | 'ò' | 'ô' | 'õ' | 'ö' => 'o',
+        'Ó' | 'Ò' | 'Ô' | 'Õ' | 'Ö' => 'O',
+        'ú' | 'ù' | 'û' | 'ü' => 'u',
+        'Ú' | 'Ù' | 'Û' | 'Ü' => 'U',
+        'ç' => 'c',
+        'Ç' => 'C',
+        'ñ' => 'n',
+        'Ñ' => 'N',
+        _ => c,
+    }
+}
+
+// Splits text into words as configured, normalizing each word.
+pub fn tokenize(config: &TokenizerConfig, text: &str) -> Vec<String> {
+    let split_on_whitespace = config.split == Some(SplitOnWhitespace);
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        let separator = if split_on_whitespace { c.is_whitespace() } else { !c.is_alphanumeric() };
+        if separator {
+            if word.len() > 0 {
+                words.push(word);
+                word = String::new();
+            }
+            continue;
+        }
+
+        let c = if config.strip_accents { strip_accent(c) } else { c };
+        word.push_char(if config.case_fold { c.to_lowercase() } else { c });
+    }
+    if word.len() > 0 {
+        words.push(word);
+    }
+    words
+}
+
+pub enum TextQuery {
+    // Records containing the word.
+    Word(String),
+    // Records containing every word of the text, consecutively and in order.
+    Phrase(String),
+    // Records containing a word that starts with the last word of the text, right after the
+    // other words of the text, as in a phrase.
+    Prefix(String),
+}
+
+// (record position, word offset within the field)
+type Posting = (u32, u32);
+
+// Maps each word of a Text field to the places it occurs. The tokenizer settings live in the
+// index catalog, so the file only holds the postings.
+pub struct InvertedIndex {
+    file: SnapshotFile,
+    config: TokenizerConfig,
+
+    postings: TreeMap<String, Vec<Posting>>,
+    // Number of records in the table the last time the index was flushed.
+    pub universe: uint,
+}
+
+fn invalid_data() -> io::IoError {
+    io::standard_error(io::InvalidInput)
+}
+
+impl InvertedIndex {
+    pub fn create(path: &Path, key_type: FieldType, config: TokenizerConfig)
+            -> io::IoResult<InvertedIndex> {
+        if key_type != TextType {
+            return Err(invalid_data());
+        }
+        let mut index = InvertedIndex {
+            file: SnapshotFile::new(path),
+            config: config,
+
+            postings: TreeMap::new(),
+            universe: 0,
+        };
+        try!(index.flush());
+        Ok(index)
+    }
+
+    pub fn open(path: &Path, config: TokenizerConfig) -> io::IoResult<InvertedIndex> {
+        let (file, mut reader) = try!(SnapshotFile::open(path));
+
+        let universe = try!(reader.read_be_u32()) as uint;
+        let mut postings = TreeMap::new();
+        let num_words = try!(reader.read_be_u32());
+        for _ in range(0, num_words) {
+            let word_len = try!(reader.read_be_u32()) as uint;
+            let word_buf = try!(reader.read_exact(word_len));
+            let word = match str::from_utf8(word_buf.as_slice()) {
+                Some(w) => w.to_strbuf(), None => return Err(invalid_data()) };
+
+            let num_postings = try!(reader.read_be_u32()) as uint;
+            let mut word_postings = Vec::with_capacity(num_postings);
+            for _ in range(0, num_postings) {
+                let pos = try!(reader.read_be_u32());
+                let offset = try!(reader.read_be_u32());
+                word_postings.push((pos, offset));
+            }
+            postings.insert(word, word_postings);
+        }
+
+        Ok(InvertedIndex {
+            file: file,
+            config: config,
+
+            postings: postings,
+            universe: universe,
+        })
+    }
+
+    // Whether the file missed changes made before the index was last dropped.
+    pub fn is_stale(&self) -> bool {
+        self.file.stale
+    }
+
+    pub fn flush(&mut self) -> io::IoResult<()> {
+        let InvertedIndex { ref mut file, ref postings, universe, .. } = *self;
+        file.write(|writer| {
+            try!(writer.write_be_u32(universe as u32));
+            try!(writer.write_be_u32(postings.len() as u32));
+            for (word, word_postings) in postings.iter() {
+                try!(writer.write_be_u32(word.len() as u32));
+                try!(writer.write_str(word.as_slice()));
+                try!(writer.write_be_u32(word_postings.len() as u32));
+                for &(pos, offset) in word_postings.iter() {
+                    try!(writer.write_be_u32(pos));
+                    try!(writer.write_be_u32(offset));
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn words(&self, key: &Field) -> Vec<String> {
+        match *key {
+            Text(ref s) => tokenize(&self.config, s.as_slice()),
+            // Not reachable, since `create` only accepts Text fields.
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        try!(self.file.mark_dirty());
+        for (offset, word) in self.words(key).move_iter().enumerate() {
+            let posting = (pos as u32, offset as u32);
+            if !self.postings.contains_key(&word) {
+                self.postings.insert(word.clone(), Vec::new());
+            }
+            let word_postings = self.postings.find_mut(&word).unwrap();
+            // Postings are kept sorted, and records are usually appended at the end.
+            let i = word_postings.iter().rposition(|p| *p < posting).map_or(0, |i| i + 1);
+            word_postings.insert(i, posting);
+        }
+        if pos >= self.universe {
+            self.universe = pos + 1;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        try!(self.file.mark_dirty());
+        for word in self.words(key).iter() {
+            let now_empty = match self.postings.find_mut(word) {
+                None => continue,
+                Some(word_postings) => {
+                    word_postings.retain(|&(p, _)| p as uint != pos);
+                    word_postings.is_empty()
+                },
+            };
+            if now_empty {
+                self.postings.remove(word);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the positions of the records matching the query, in increasing order.
+    pub fn search(&self, query: &TextQuery) -> Vec<uint> {
+        let mut positions: Vec<uint> = match *query {
+            Word(ref text) => {
+                let mut positions = Vec::new();
+                for word in tokenize(&self.config, text.as_slice()).iter() {
+                    match self.postings.find(word) {
+                        Some(word_postings) =>
+                            positions.extend(word_postings.iter().map(|&(p, _)| p as uint)),
+                        None => (),
+                    }
+                }
+                positions
+            },
+            Prefix(ref text) => {
+                let mut words = tokenize(&self.config, text.as_slice());
+                match words.pop() {
+                    Some(prefix) => self.search_phrase(words.as_slice(), Some(&prefix)),
+                    None => Vec::new(),
+                }
+            },
+            Phrase(ref text) =>
+                self.search_phrase(tokenize(&self.config, text.as_slice()).as_slice(), None),
+        };
+
+        positions.as_mut_slice().sort();
+        positions.dedup();
+        positions
+    }
+
+    // Postings of all the words that start with the prefix.
+    fn prefix_postings(&self, prefix: &String) -> Vec<Posting> {
+        let mut postings = Vec::new();
+        for (word, word_postings) in self.postings.lower_bound(prefix) {
+            if !word.as_slice().starts_with(prefix.as_slice()) {
+                break;
+            }
+            postings.push_all(word_postings.as_slice());
+        }
+        postings
+    }
+
+    // Records containing the words consecutively and in order, followed by a word that starts
+    // with the prefix, if any.
+    fn search_phrase(&self, words: &[String], prefix: Option<&String>) -> Vec<uint> {
+        let mut positions = Vec::new();
+        let mut word_postings = Vec::with_capacity(words.len() + 1);
+        for word in words.iter() {
+            match self.postings.find(word) {
+                Some(p) => word_postings.push(p.clone()),
+                None => return positions,
+            }
+        }
+        match prefix {
+            Some(prefix) => word_postings.push(self.prefix_postings(prefix)),
+            None => (),
+        }
+        if word_postings.is_empty() {
+            return positions;
+        }
+
+        let following: Vec<HashSet<Posting>> = word_postings.slice_from(1).iter()
+            .map(|p| p.iter().map(|&posting| posting).collect())
+            .collect();
+        for &(pos, offset) in word_postings.get(0).iter() {
+            let matches = following.iter().enumerate()
+                .all(|(k, set)| set.contains(&(pos, offset + k as u32 + 1)));
+            if matches {
+                positions.push(pos as uint);
+            }
+        }
+        positions
+    }
+}
+
+// Iterates over the records matching a full-text query.
+pub struct TextSearch<'table> {
+    rows: PhysicalTableIterator<'table>,
+    positions: Vec<uint>,
+    next_position: uint,
+}
+
+impl<'table> TextSearch<'table> {
+    pub fn new(rows: PhysicalTableIterator<'table>, positions: Vec<uint>) -> TextSearch<'table> {
+        TextSearch {
+            rows: rows,
+            positions: positions,
+            next_position: 0,
+        }
+    }
+}
+
+impl<'table> Iterator<Vec<Field>> for TextSearch<'table> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        while self.next_position < self.positions.len() {
+            let pos = *self.positions.get(self.next_position);
+            self.next_position += 1;
+            match self.rows.idx(pos) {
+                None => continue,
+                Some(values) => return Some(values),
+            }
+        }
+        None
+    }
+}
+
+impl<'table> TableIterator for TextSearch<'table> {
+    fn blocks_accessed(&self) -> uint {
+        self.rows.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.rows.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.rows.schema()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::default::Default;
+
+    use super::super::testing;
+    use super::super::{IntegerType, Text, TextType};
+    use super::{
+        InvertedIndex,
+        Phrase,
+        Prefix,
+        SplitOnWhitespace,
+        TokenizerConfig,
+        tokenize,
+    };
+
+    fn index(names: &[&str]) -> InvertedIndex {
+        let db = testing::scratch_db();
+        let mut index = InvertedIndex::create(&db.path().join("names.text"), TextType,
+                                              Default::default()).unwrap();
+        for (pos, name) in names.iter().enumerate() {
+            index.insert(&Text(name.to_strbuf()), pos).unwrap();
+        }
+        index
+    }
+
+    #[test]
+    fn split_on_whitespace() {
+        let config = TokenizerConfig { case_fold: true, strip_accents: true,
+                                       split: Some(SplitOnWhitespace) };
+        assert_eq!(tokenize(&config, "  E-mail  São\tPaulo "),
+                   vec!["e-mail".to_strbuf(), "sao".to_strbuf(), "paulo".to_strbuf()]);
+        assert_eq!(tokenize(&Default::default(), "E-mail"),
+                   vec!["e".to_strbuf(), "mail".to_strbuf()]);
+    }
+
+    #[test]
+    fn multi_word_prefix() {
+        let index = index(&["João Silva", "João Souza", "Ana Silveira", "Silvio João"]);
+        assert_eq!(index.search(&Prefix("joão si".to_strbuf())), vec![0]);
+        assert_eq!(index.search(&Prefix("sil".to_strbuf())), vec![0, 2, 3]);
+        assert_eq!(index.search(&Prefix("joaosi".to_strbuf())), vec![]);
+        assert_eq!(index.search(&Prefix("".to_strbuf())), vec![]);
+    }
+
+    #[test]
+    fn phrase() {
+        let index = index(&["João Silva", "Silva João"]);
+        assert_eq!(index.search(&Phrase("joao silva".to_strbuf())), vec![0]);
+        assert_eq!(index.search(&Phrase("joao".to_strbuf())), vec![0, 1]);
+    }
+
+    #[test]
+    fn create_over_integer_field() {
+        let db = testing::scratch_db();
+        assert!(InvertedIndex::create(&db.path().join("ids.text"), IntegerType,
+                                      Default::default()).is_err());
+    }
+}
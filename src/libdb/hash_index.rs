@@ -0,0 +1,426 @@
+use std::hash::sip;
+use std::io::fs;
+use std::io;
+
+use super::{
+    Field,
+    FieldType,
+    IntegerType,
+    TextType,
+    PhysicalTableIterator,
+    ProbeIterator,
+    TableError,
+    TableIterator,
+    TableSchema,
+    read_u32,
+    read_value,
+    write_u32,
+    write_value,
+};
+
+// Number of entries that fit in a bucket page, primary or overflow.
+static BUCKET_CAPACITY : uint = 32;
+static INITIAL_BUCKETS : uint = 4;
+static PAGE_HEADER : uint = 8;
+static META_SIZE : uint = 28;
+
+type Entry = (Field, u32);
+
+fn hash_key(key: &Field) -> u64 {
+    sip::hash_with_keys(0, 0, key)
+}
+
+fn corrupt_index() -> io::IoError {
+    io::IoError {
+        kind: io::InvalidInput,
+        desc: "index file contains invalid data",
+        detail: None,
+    }
+}
+
+// A linear hashing index mapping field values to record positions.
+//
+// Primary bucket pages live in the main file, after the metadata page, and grow by one page on
+// each split. Overflow pages are kept in a separate file so that bucket numbers map directly to
+// pages. Overflow pages freed by splits are not reused until the index is rebuilt.
+pub struct HashIndex {
+    file: fs::File,
+    overflow_file: fs::File,
+    key_type: FieldType,
+    key_length: uint,
+
+    level: uint,
+    split: uint,
+    num_entries: uint,
+    num_overflow: u32,
+}
+
+impl HashIndex {
+    pub fn create(path: &Path, key_type: FieldType, key_length: uint) -> io::IoResult<HashIndex> {
+        let mut index = HashIndex {
+            file: try!(fs::File::open_mode(path, io::Truncate, io::ReadWrite)),
+            overflow_file: try!(fs::File::open_mode(
+                    &path.with_extension("overflow"), io::Truncate, io::ReadWrite)),
+            key_type: key_type,
+            key_length: key_length,
+
+            level: 0,
+            split: 0,
+            num_entries: 0,
+            num_overflow: 0,
+        };
+
+        for bucket in range(0, INITIAL_BUCKETS) {
+            try!(index.write_page(false, bucket as u32 + 1, &[], 0));
+        }
+        try!(index.write_meta());
+        Ok(index)
+    }
+
+    pub fn open(path: &Path) -> io::IoResult<HashIndex> {
+        let mut file = try!(fs::File::open_mode(path, io::Open, io::ReadWrite));
+        let overflow_file = try!(fs::File::open_mode(
+                &path.with_extension("overflow"), io::Open, io::ReadWrite));
+        let meta = try!(file.read_exact(META_SIZE));
+
+        let key_type = match read_u32(meta.slice(20, 24)) {
+            0 => IntegerType,
+            1 => TextType,
+            _ => return Err(corrupt_index()),
+        };
+
+        Ok(HashIndex {
+            file: file,
+            overflow_file: overflow_file,
+            key_type: key_type,
+            key_length: read_u32(meta.slice(24, 28)) as uint,
+
+            level: read_u32(meta.slice(0, 4)) as uint,
+            split: read_u32(meta.slice(4, 8)) as uint,
+            num_entries: read_u32(meta.slice(12, 16)) as uint,
+            num_overflow: read_u32(meta.slice(16, 20)),
+        })
+    }
+
+    fn page_size(&self) -> uint {
+        PAGE_HEADER + BUCKET_CAPACITY * (self.key_length + 4)
+    }
+
+    fn num_buckets(&self) -> uint {
+        (INITIAL_BUCKETS << self.level) + self.split
+    }
+
+    fn bucket_for(&self, key: &Field) -> uint {
+        let hash = hash_key(key);
+        let bucket = (hash % (INITIAL_BUCKETS << self.level) as u64) as uint;
+        if bucket < self.split {
+            (hash % (INITIAL_BUCKETS << (self.level + 1)) as u64) as uint
+        } else {
+            bucket
+        }
+    }
+
+    fn write_meta(&mut self) -> io::IoResult<()> {
+        let mut buf = Vec::from_elem(META_SIZE, 0u8);
+        write_u32(self.level as u32, buf.mut_slice(0, 4));
+        write_u32(self.split as u32, buf.mut_slice(4, 8));
+        write_u32(self.num_buckets() as u32, buf.mut_slice(8, 12));
+        write_u32(self.num_entries as u32, buf.mut_slice(12, 16));
+        write_u32(self.num_overflow, buf.mut_slice(16, 20));
+        write_u32(match self.key_type { IntegerType => 0, TextType => 1 }, buf.mut_slice(20, 24));
+        write_u32(self.key_length as u32, buf.mut_slice(24, 28));
+
+        try!(self.file.seek(0, io::SeekSet));
+        self.file.write(buf.as_slice())
+    }
+
+    // Reads a page, returning its entries and the next overflow page (0 if none).
+    fn read_page(&mut self, overflow: bool, page: u32) -> io::IoResult<(Vec<Entry>, u32)> {
+        let page_size = self.page_size();
+        let slot_size = self.key_length + 4;
+
+        let file = if overflow { &mut self.overflow_file } else { &mut self.file };
+        try!(file.seek((page as uint * page_size) as i64, io::SeekSet));
+        let buf = try!(file.read_exact(page_size));
+
+        let count = read_u32(buf.slice(0, 4)) as uint;
+        let mut entries = Vec::with_capacity(count);
+        for j in range(0, count) {
+            let slot = buf.slice(PAGE_HEADER + j * slot_size, PAGE_HEADER + (j + 1) * slot_size);
+            let key = match read_value(0, self.key_type, slot.slice_to(self.key_length)) {
+                Ok(k) => k, Err(_) => return Err(corrupt_index()) };
+            entries.push((key, read_u32(slot.slice_from(self.key_length))));
+        }
+
+        Ok((entries, read_u32(buf.slice(4, 8))))
+    }
+
+    fn write_page(&mut self, overflow: bool, page: u32, entries: &[Entry], next: u32)
+            -> io::IoResult<()> {
+        let page_size = self.page_size();
+        let slot_size = self.key_length + 4;
+        let mut buf = Vec::from_elem(page_size, 0u8);
+
+        write_u32(entries.len() as u32, buf.mut_slice(0, 4));
+        write_u32(next, buf.mut_slice(4, 8));
+        for (j, &(ref key, pos)) in entries.iter().enumerate() {
+            let slot = buf.mut_slice(PAGE_HEADER + j * slot_size,
+                                     PAGE_HEADER + (j + 1) * slot_size);
+            match write_value(0, key, slot.mut_slice_to(self.key_length)) {
+                Ok(()) => (), Err(_) => return Err(corrupt_index()) };
+            write_u32(pos, slot.mut_slice_from(self.key_length));
+        }
+
+        let file = if overflow { &mut self.overflow_file } else { &mut self.file };
+        try!(file.seek((page as uint * page_size) as i64, io::SeekSet));
+        file.write(buf.as_slice())
+    }
+
+    // Reads every entry in a bucket's chain, also returning the overflow pages it spans.
+    fn read_bucket(&mut self, bucket: uint) -> io::IoResult<(Vec<Entry>, Vec<u32>)> {
+        let (mut entries, mut next) = try!(self.read_page(false, bucket as u32 + 1));
+        let mut overflow_pages = Vec::new();
+        while next != 0 {
+            let (more, following) = try!(self.read_page(true, next));
+            overflow_pages.push(next);
+            entries.push_all_move(more);
+            next = following;
+        }
+        Ok((entries, overflow_pages))
+    }
+
+    // Rewrites a bucket's chain, reusing its overflow pages and allocating more if needed.
+    fn write_bucket(&mut self, bucket: uint, entries: &[Entry], overflow_pages: &[u32])
+            -> io::IoResult<()> {
+        let mut chunks: Vec<&[Entry]> = entries.chunks(BUCKET_CAPACITY).collect();
+        if chunks.is_empty() {
+            chunks.push(entries);
+        }
+
+        let mut pages = Vec::from_slice(overflow_pages);
+        while pages.len() < chunks.len() - 1 {
+            self.num_overflow += 1;
+            pages.push(self.num_overflow);
+        }
+
+        for (j, chunk) in chunks.iter().enumerate() {
+            let next = if j + 1 < chunks.len() { *pages.get(j) } else { 0 };
+            if j == 0 {
+                try!(self.write_page(false, bucket as u32 + 1, *chunk, next));
+            } else {
+                try!(self.write_page(true, *pages.get(j - 1), *chunk, next));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        let bucket = self.bucket_for(key);
+        let (mut entries, overflow_pages) = try!(self.read_bucket(bucket));
+        entries.push((key.clone(), pos as u32));
+        try!(self.write_bucket(bucket, entries.as_slice(), overflow_pages.as_slice()));
+
+        self.num_entries += 1;
+        if self.num_entries * 4 > self.num_buckets() * BUCKET_CAPACITY * 3 {
+            try!(self.split_bucket());
+        }
+        self.write_meta()
+    }
+
+    fn split_bucket(&mut self) -> io::IoResult<()> {
+        let old_bucket = self.split;
+        let new_bucket = old_bucket + (INITIAL_BUCKETS << self.level);
+        let (entries, overflow_pages) = try!(self.read_bucket(old_bucket));
+
+        self.split += 1;
+        if self.split == INITIAL_BUCKETS << self.level {
+            self.level += 1;
+            self.split = 0;
+        }
+
+        let (moved, kept) = entries.partition(|&(ref key, _)| self.bucket_for(key) == new_bucket);
+        try!(self.write_bucket(old_bucket, kept.as_slice(), overflow_pages.as_slice()));
+        self.write_bucket(new_bucket, moved.as_slice(), &[])
+    }
+
+    pub fn remove(&mut self, key: &Field, pos: uint) -> io::IoResult<()> {
+        let bucket = self.bucket_for(key);
+        let (mut entries, overflow_pages) = try!(self.read_bucket(bucket));
+
+        let entry = (key.clone(), pos as u32);
+        match entries.iter().position(|e| *e == entry) {
+            None => Ok(()),
+            Some(i) => {
+                entries.swap_remove(i);
+                try!(self.write_bucket(bucket, entries.as_slice(), overflow_pages.as_slice()));
+                self.num_entries -= 1;
+                self.write_meta()
+            },
+        }
+    }
+
+    // Returns the positions of all records with the given key, plus the number of pages read.
+    pub fn lookup(&mut self, key: &Field) -> io::IoResult<(Vec<uint>, uint)> {
+        let bucket = self.bucket_for(key);
+        let (entries, overflow_pages) = try!(self.read_bucket(bucket));
+        let positions = entries.iter()
+            .filter(|&&(ref k, _)| *k == *key)
+            .map(|&(_, pos)| pos as uint)
+            .collect();
+        Ok((positions, 1 + overflow_pages.len()))
+    }
+}
+
+// Iterates over the records whose indexed field is equal to a key.
+pub struct HashScan<'table> {
+    rows: PhysicalTableIterator<'table>,
+    index: uint,
+
+    positions: Vec<uint>,
+    next_position: uint,
+
+    pub index_blocks_accessed: uint,
+}
+
+impl<'table> HashScan<'table> {
+    // Fails if `index` isn't one of the table's hash indexes.
+    pub fn new(mut rows: PhysicalTableIterator<'table>, index: uint, key: &Field)
+            -> Result<HashScan<'table>, TableError> {
+        try!(rows.table.indexes.get_mut(index).hash_index());
+        let mut scan = HashScan {
+            rows: rows,
+            index: index,
+
+            positions: Vec::new(),
+            next_position: 0,
+
+            index_blocks_accessed: 0,
+        };
+        scan.probe(key);
+        Ok(scan)
+    }
+}
+
+impl<'table> Iterator<Vec<Field>> for HashScan<'table> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        while self.next_position < self.positions.len() {
+            let pos = *self.positions.get(self.next_position);
+            self.next_position += 1;
+            match self.rows.idx(pos) {
+                None => continue,
+                Some(values) => return Some(values),
+            }
+        }
+        None
+    }
+}
+
+impl<'table> TableIterator for HashScan<'table> {
+    fn blocks_accessed(&self) -> uint {
+        self.rows.blocks_accessed() + self.index_blocks_accessed
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.rows.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.rows.schema()
+    }
+}
+
+impl<'table> ProbeIterator for HashScan<'table> {
+    fn probe(&mut self, key: &Field) {
+        let field = self.rows.table.indexes.get(self.index).field;
+        if !self.rows.table.bloom_may_contain(field, key) {
+            self.positions = Vec::new();
+            self.next_position = 0;
+            return;
+        }
+
+        let (mut positions, pages_read) =
+            self.rows.table.indexes.get_mut(self.index).hash_index().unwrap().lookup(key).unwrap();
+        if positions.is_empty() {
+            self.rows.table.bloom_false_positive(field);
+        }
+        // Visit records in file order so neighbouring matches share block loads.
+        positions.as_mut_slice().sort();
+
+        self.positions = positions;
+        self.next_position = 0;
+        self.index_blocks_accessed += pages_read;
+    }
+
+    fn index_blocks_accessed(&self) -> uint {
+        self.index_blocks_accessed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing;
+    use super::super::{HashIndexType, Integer, IntegerType};
+    use super::{BUCKET_CAPACITY, HashIndex, INITIAL_BUCKETS};
+
+    #[test]
+    fn bucket_splits() {
+        let db = testing::scratch_db();
+        let path = db.path().join("t.hash");
+        let mut index = HashIndex::create(&path, IntegerType, 4).unwrap();
+        for key in range(0, 1000u) {
+            index.insert(&Integer(key as u32), key).unwrap();
+        }
+        // Buckets are split to keep them at most three quarters full on average.
+        assert!(index.num_buckets() > INITIAL_BUCKETS);
+        assert!(1000 * 4 <= index.num_buckets() * BUCKET_CAPACITY * 3);
+
+        let mut index = HashIndex::open(&path).unwrap();
+        assert_eq!(index.num_entries, 1000);
+        for key in range(0, 1000u) {
+            assert_eq!(index.lookup(&Integer(key as u32)).unwrap().val0(), vec![key]);
+        }
+        assert_eq!(index.lookup(&Integer(1000)).unwrap().val0(), vec![]);
+    }
+
+    #[test]
+    fn overflow_pages() {
+        let db = testing::scratch_db();
+        let mut index = HashIndex::create(&db.path().join("t.hash"), IntegerType, 4).unwrap();
+        for pos in range(0, 100u) {
+            index.insert(&Integer(7), pos).unwrap();
+        }
+        let (mut positions, pages_read) = index.lookup(&Integer(7)).unwrap();
+        positions.as_mut_slice().sort();
+        assert_eq!(positions, range(0, 100u).collect());
+        assert_eq!(pages_read, 4);
+
+        // Removals shorten the chain.
+        for pos in range(0, 40u) {
+            index.remove(&Integer(7), pos).unwrap();
+        }
+        let (mut positions, pages_read) = index.lookup(&Integer(7)).unwrap();
+        positions.as_mut_slice().sort();
+        assert_eq!(positions, range(40, 100u).collect());
+        assert_eq!(pages_read, 2);
+    }
+
+    #[test]
+    fn rebuild_frees_overflow_pages() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 200, 1);
+        table.create_index("value_hash", "value", HashIndexType).unwrap();
+        let i = table.find_index("value_hash").unwrap();
+        assert!(table.indexes.get_mut(i).hash_index().unwrap().num_overflow >= 6);
+
+        for pos in range(0, 150u) {
+            table.delete_entry(pos).unwrap();
+        }
+        table.rebuild_index("value_hash").unwrap();
+        assert_eq!(table.indexes.get_mut(i).hash_index().unwrap().num_overflow, 1);
+
+        let mut scan = table.hash_scan("value_hash", &Integer(0)).unwrap();
+        let ids: Vec<_> = scan.by_ref().map(|values| values.get(0).clone()).collect();
+        assert_eq!(ids, range(150, 200u32).map(|id| Integer(id)).collect());
+        assert_eq!(scan.index_blocks_accessed, 2);
+    }
+}
@@ -1,9 +1,12 @@
 #![crate_id="github.com/yuriks/ibt-t1/db"]
 #![crate_type="lib"]
 
+extern crate collections;
 extern crate core;
+extern crate regex;
 extern crate serialize;
 
+use collections::HashSet;
 use std::cmp::min;
 use std::fmt;
 use std::io::fs;
@@ -17,25 +20,41 @@ use serialize::{
     Encoder
 };
 
+pub mod bitmap;
+pub mod bloom;
+pub mod btree;
+pub mod expr;
+pub mod fulltext;
+pub mod hash_index;
+pub mod pattern;
 pub mod select;
+pub mod snapshot;
+pub mod spill;
+pub mod zonemap;
 
-#[deriving(Decodable, Encodable, Eq, Show)]
+#[cfg(test)]
+mod testing;
+
+#[deriving(Clone, Decodable, Encodable, Eq, Show)]
 pub enum FieldType {
     IntegerType,
     TextType,
 }
 
-#[deriving(Clone, Eq)]
+#[deriving(Clone, Decodable, Encodable, Eq, TotalEq, Ord, TotalOrd, Hash)]
 pub enum Field {
     Integer(u32),
     Text(String),
+    // Missing value, such as the padding of outer joins. Can't be stored in tables.
+    Null,
 }
 
 impl Field {
-    fn get_type(&self) -> FieldType {
+    fn get_type(&self) -> Option<FieldType> {
         match *self {
-            Integer(_) => IntegerType,
-            Text(_) => TextType,
+            Integer(_) => Some(IntegerType),
+            Text(_) => Some(TextType),
+            Null => None,
         }
     }
 }
@@ -45,10 +64,36 @@ impl fmt::Show for Field {
         match *self {
             Integer(x) => write!(fmt, "{}", x),
             Text(ref s) => write!(fmt, "{}", s),
+            Null => write!(fmt, "NULL"),
         }
     }
 }
 
+// One end of a key range, used by index scans.
+#[deriving(Clone, Show)]
+pub enum Bound {
+    Unbounded,
+    Included(Field),
+    Excluded(Field),
+}
+
+impl Bound {
+    pub fn admits_above(&self, key: &Field) -> bool {
+        match *self {
+            Unbounded => true,
+            Included(ref low) => key >= low,
+            Excluded(ref low) => key > low,
+        }
+    }
+
+    pub fn admits_below(&self, key: &Field) -> bool {
+        match *self {
+            Unbounded => true,
+            Included(ref high) => key <= high,
+            Excluded(ref high) => key < high,
+        }
+    }
+}
 
 #[deriving(Decodable, Encodable)]
 pub struct FieldSchema {
@@ -56,6 +101,14 @@ pub struct FieldSchema {
     pub offset: uint,
     pub data_type: FieldType,
     pub length: uint,
+    // Optional so that schemas written before sequences existed still decode.
+    pub auto_increment: Option<bool>,
+}
+
+impl FieldSchema {
+    pub fn is_auto_increment(&self) -> bool {
+        self.auto_increment.unwrap_or(false)
+    }
 }
 
 #[deriving(Decodable, Encodable)]
@@ -63,17 +116,232 @@ pub struct TableSchema {
     pub name: String,
     pub fields: Vec<FieldSchema>,
     pub entry_stride: uint,
+    pub primary_key: Option<String>,
 }
 
 impl TableSchema {
-    pub fn map_field(&self, name: &str) -> Option<uint> {
-        self.fields.iter().position(|f| f.name.as_slice() == name)
+    // Looks a field up by its exact name, or else by its name without the table qualifier, so
+    // that `nome` finds `Clientes.nome` in a join. Fails if more than one field fits.
+    pub fn find_field(&self, name: &str) -> Result<uint, TableError> {
+        let mut matches: Vec<uint> = self.fields.iter().enumerate()
+            .filter(|&(_, f)| f.name.as_slice() == name)
+            .map(|(i, _)| i)
+            .collect();
+        if matches.is_empty() {
+            matches = self.fields.iter().enumerate()
+                .filter(|&(_, f)| match split_field_name(f.name.as_slice()) {
+                    (Some(_), unqualified) => unqualified == name,
+                    (None, _) => false,
+                })
+                .map(|(i, _)| i)
+                .collect();
+        }
+
+        match matches.len() {
+            0 => Err(FieldNameError(name.to_strbuf())),
+            1 => Ok(*matches.get(0)),
+            _ => Err(AmbiguousFieldError(name.to_strbuf())),
+        }
+    }
+}
+
+// Splits `Clientes.nome` into the table qualifier and the field name.
+pub fn split_field_name<'a>(name: &'a str) -> (Option<&'a str>, &'a str) {
+    match name.find('.') {
+        Some(i) => (Some(name.slice_to(i)), name.slice_from(i + 1)),
+        None => (None, name),
+    }
+}
+
+#[deriving(Clone, Decodable, Encodable, Eq, Show)]
+pub enum IndexType {
+    BTreeIndexType,
+    HashIndexType,
+    BitmapIndexType,
+    TextIndexType,
+}
+
+#[deriving(Clone, Decodable, Encodable)]
+pub struct IndexSchema {
+    pub name: String,
+    pub field: String,
+    pub index_type: IndexType,
+    pub unique: bool,
+    // Only used by full-text indexes.
+    pub tokenizer: Option<fulltext::TokenizerConfig>,
+}
+
+impl IndexSchema {
+    fn tokenizer_config(&self) -> fulltext::TokenizerConfig {
+        self.tokenizer.clone().unwrap_or_default()
+    }
+}
+
+// Name of the index created for tables that declare a primary key.
+pub static PRIMARY_KEY_INDEX : &'static str = "primary_key";
+
+enum IndexData {
+    BTreeData(btree::BTree),
+    HashData(hash_index::HashIndex),
+    BitmapData(bitmap::BitmapIndex),
+    TextData(fulltext::InvertedIndex),
+}
+
+struct Index {
+    schema: IndexSchema,
+    field: uint,
+    data: IndexData,
+}
+
+impl Index {
+    fn file_path(table_path: &Path, schema: &IndexSchema) -> Path {
+        let extension = match schema.index_type {
+            BTreeIndexType => "btree",
+            HashIndexType => "hash",
+            BitmapIndexType => "bitmap",
+            TextIndexType => "text",
+        };
+        table_path.join(format!("{}.{}", schema.name, extension).as_slice())
+    }
+
+    fn create(table_path: &Path, schema: IndexSchema, field: uint, field_schema: &FieldSchema)
+            -> io::IoResult<Index> {
+        let path = Index::file_path(table_path, &schema);
+        let (key_type, key_length) = (field_schema.data_type, field_schema.length);
+        let data = match schema.index_type {
+            BTreeIndexType => BTreeData(try!(btree::BTree::create(&path, key_type, key_length))),
+            HashIndexType => HashData(try!(
+                    hash_index::HashIndex::create(&path, key_type, key_length))),
+            BitmapIndexType => BitmapData(try!(
+                    bitmap::BitmapIndex::create(&path, key_type, key_length))),
+            TextIndexType => TextData(try!(
+                    fulltext::InvertedIndex::create(&path, key_type, schema.tokenizer_config()))),
+        };
+        Ok(Index { schema: schema, field: field, data: data })
+    }
+
+    fn open(table_path: &Path, schema: IndexSchema, field: uint) -> io::IoResult<Index> {
+        let path = Index::file_path(table_path, &schema);
+        let data = match schema.index_type {
+            BTreeIndexType => BTreeData(try!(btree::BTree::open(&path))),
+            HashIndexType => HashData(try!(hash_index::HashIndex::open(&path))),
+            BitmapIndexType => BitmapData(try!(bitmap::BitmapIndex::open(&path))),
+            TextIndexType => TextData(try!(
+                    fulltext::InvertedIndex::open(&path, schema.tokenizer_config()))),
+        };
+        Ok(Index { schema: schema, field: field, data: data })
+    }
+
+    fn insert(&mut self, values: &[Field], pos: uint) -> io::IoResult<()> {
+        let key = &values[self.field];
+        match self.data {
+            BTreeData(ref mut tree) => tree.insert(key, pos),
+            HashData(ref mut index) => index.insert(key, pos),
+            BitmapData(ref mut index) => index.insert(key, pos),
+            TextData(ref mut index) => index.insert(key, pos),
+        }
+    }
+
+    fn remove(&mut self, values: &[Field], pos: uint) -> io::IoResult<()> {
+        let key = &values[self.field];
+        match self.data {
+            BTreeData(ref mut tree) => tree.remove(key, pos),
+            HashData(ref mut index) => index.remove(key, pos),
+            BitmapData(ref mut index) => index.remove(key, pos),
+            TextData(ref mut index) => index.remove(key, pos),
+        }
+    }
+
+    // Returns the position of some record with the given key, plus the number of blocks read.
+    fn find_first(&mut self, key: &Field) -> io::IoResult<(Option<uint>, uint)> {
+        match self.data {
+            BTreeData(ref mut tree) => tree.find(key),
+            HashData(ref mut index) => {
+                let (positions, blocks_read) = try!(index.lookup(key));
+                Ok((positions.iter().next().map(|&pos| pos), blocks_read))
+            },
+            // Bitmap indexes are kept in memory, so lookups don't read any blocks.
+            BitmapData(ref index) => Ok((index.lookup(key).into_positions().next(), 0)),
+            TextData(ref index) => match *key {
+                Text(ref s) => Ok((index.search(&fulltext::Word(s.clone())).move_iter().next(), 0)),
+                _ => Ok((None, 0)),
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::IoResult<()> {
+        match self.data {
+            BitmapData(ref mut index) => index.flush(),
+            TextData(ref mut index) => index.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether an index kept in memory missed records appended since its last flush, or changes
+    // made before it was dropped without one.
+    fn is_stale(&self, num_entries: uint) -> bool {
+        match self.data {
+            BitmapData(ref index) => index.is_stale() || index.universe != num_entries,
+            TextData(ref index) => index.is_stale() || index.universe != num_entries,
+            _ => false,
+        }
+    }
+
+    fn set_universe(&mut self, universe: uint) {
+        match self.data {
+            BitmapData(ref mut index) => index.universe = universe,
+            TextData(ref mut index) => index.universe = universe,
+            _ => (),
+        }
+    }
+
+    fn btree<'a>(&'a mut self) -> Result<&'a mut btree::BTree, TableError> {
+        match self.data {
+            BTreeData(ref mut tree) => Ok(tree),
+            _ => Err(IndexTypeError(self.schema.name.clone(), BTreeIndexType)),
+        }
+    }
+
+    fn hash_index<'a>(&'a mut self) -> Result<&'a mut hash_index::HashIndex, TableError> {
+        match self.data {
+            HashData(ref mut index) => Ok(index),
+            _ => Err(IndexTypeError(self.schema.name.clone(), HashIndexType)),
+        }
+    }
+
+    fn bitmap_index<'a>(&'a mut self) -> Result<&'a mut bitmap::BitmapIndex, TableError> {
+        match self.data {
+            BitmapData(ref mut index) => Ok(index),
+            _ => Err(IndexTypeError(self.schema.name.clone(), BitmapIndexType)),
+        }
+    }
+
+    fn inverted_index<'a>(&'a mut self) -> Result<&'a mut fulltext::InvertedIndex, TableError> {
+        match self.data {
+            TextData(ref mut index) => Ok(index),
+            _ => Err(IndexTypeError(self.schema.name.clone(), TextIndexType)),
+        }
     }
 }
 
+// Sequences aren't transactional: a value is never handed out twice, even if the record it was
+// meant for never makes it to disk, so failed inserts only leave gaps.
+struct Sequence {
+    field: uint,
+    next: u32,
+}
+
 pub struct Table {
     pub schema: TableSchema,
     pub file: fs::File,
+
+    path: Path,
+    deleted: HashSet<uint>,
+    deleted_file: fs::File,
+    indexes: Vec<Index>,
+    sequences: Vec<Sequence>,
+    zone_map: zonemap::ZoneMap,
+    bloom_filters: Vec<bloom::BloomFilter>,
 }
 
 pub struct PhysicalTableIterator<'table> {
@@ -88,6 +356,8 @@ pub struct PhysicalTableIterator<'table> {
     pub records_accessed: uint,
 }
 
+// Iterators that can start over from their first record. Statistics aren't reset, so after
+// several passes `blocks_accessed` and `records_accessed` are totals over all of them.
 pub trait RewindableIterator<T> : Iterator<T> {
     fn rewind(&mut self);
 }
@@ -97,22 +367,54 @@ pub trait TableIterator : Iterator<Vec<Field>> {
     fn records_accessed(&self) -> uint;
 
     fn schema<'s>(&'s self) -> &'s TableSchema;
+
+    // The error that made iteration end early, if any. Operators that can fail partway through,
+    // such as those spilling to disk, stop yielding records and keep the error for this, so a
+    // `None` from `next` only means all records were seen if this returns `None` as well.
+    fn take_error(&mut self) -> Option<TableError> {
+        None
+    }
+
+    // Reads the remaining records, or fails with the error that cut them short.
+    fn collect_records(&mut self) -> Result<Vec<Vec<Field>>, TableError> {
+        let records = self.by_ref().collect();
+        match self.take_error() {
+            Some(e) => Err(e),
+            None => Ok(records),
+        }
+    }
 }
 
-static BLOCK_SIZE : uint = 10;
+// Iterators that can fetch a record by the value of its table's primary key. Fails if the
+// table has none.
+pub trait KeyLookupIterator : TableIterator {
+    fn lookup_key(&mut self, key: &Field) -> Result<Option<Vec<Field>>, TableError>;
+}
+
+// Index scans that can be repositioned to yield only the records matching a given key.
+pub trait ProbeIterator : TableIterator {
+    fn probe(&mut self, key: &Field);
+
+    // Index blocks read so far. These are also included in `blocks_accessed`.
+    fn index_blocks_accessed(&self) -> uint;
+}
+
+pub static BLOCK_SIZE : uint = 10;
 
 impl<'table> PhysicalTableIterator<'table> {
     fn load_block(&mut self, i: uint) -> io::IoResult<()> {
         let need_reload = match self.block_base {
-            Some((base, limit)) => self.i < base || self.i >= limit,
+            Some((base, limit)) => i < base || i >= limit,
             None => true
         };
         if !need_reload {
             return Ok(())
         }
 
+        // Blocks are aligned, so they line up with the table's zone map.
+        let block_start = i - i % BLOCK_SIZE;
         let stride = self.table.schema.entry_stride;
-        let load_base = (i * stride) as i64;
+        let load_base = (block_start * stride) as i64;
         let load_size = BLOCK_SIZE * stride;
 
         self.block_base = None;
@@ -120,8 +422,8 @@ impl<'table> PhysicalTableIterator<'table> {
         try!(self.table.file.seek(load_base, io::SeekSet));
         let bytes_loaded = try!(self.table.file.push(load_size, &mut self.block_data));
         let records_loaded = bytes_loaded / stride;
-        assert!(records_loaded >= 1);
-        self.block_base = Some((i, i + records_loaded));
+        assert!(block_start + records_loaded > i);
+        self.block_base = Some((block_start, block_start + records_loaded));
 
         self.blocks_accessed += 1;
 
@@ -131,6 +433,9 @@ impl<'table> PhysicalTableIterator<'table> {
 
 impl<'table> Iterator<Vec<Field>> for PhysicalTableIterator<'table> {
     fn next(&mut self) -> Option<Vec<Field>> {
+        while self.i < self.len && self.table.is_deleted(self.i) {
+            self.i += 1;
+        }
         let r = self.idx(self.i);
         self.i += 1;
         r
@@ -149,7 +454,7 @@ impl<'table> RandomAccessIterator<Vec<Field>> for PhysicalTableIterator<'table>
     }
 
     fn idx(&mut self, i: uint) -> Option<Vec<Field>> {
-        if i >= self.len {
+        if i >= self.len || self.table.is_deleted(i) {
             return None;
         }
 
@@ -183,6 +488,29 @@ impl<'table> TableIterator for PhysicalTableIterator<'table> {
     }
 }
 
+impl<'table> KeyLookupIterator for PhysicalTableIterator<'table> {
+    fn lookup_key(&mut self, key: &Field) -> Result<Option<Vec<Field>>, TableError> {
+        let index = match self.table.find_index(PRIMARY_KEY_INDEX) {
+            Some(i) => i,
+            None => return Err(NoPrimaryKeyError(self.table.schema.name.clone())),
+        };
+
+        let field = self.table.indexes.get(index).field;
+        if !self.table.bloom_may_contain(field, key) {
+            return Ok(None);
+        }
+
+        let (pos, blocks_read) =
+            try!(self.table.indexes.get_mut(index).find_first(key).map_err(IoError));
+        self.blocks_accessed += blocks_read;
+        let values = pos.and_then(|pos| self.idx(pos));
+        if values.is_none() {
+            self.table.bloom_false_positive(field);
+        }
+        Ok(values)
+    }
+}
+
 fn read_u32(buf: &[u8]) -> u32 {
     buf[0] as u32 << 24 |
     buf[1] as u32 << 16 |
@@ -202,6 +530,8 @@ pub enum TableOpenError {
     OpenIoError(io::IoError),
     ParserError(json::ParserError),
     DecoderError(json::DecoderError),
+    UnknownFieldError(String),
+    OpenTableError(TableError),
 }
 
 pub enum TableError {
@@ -209,6 +539,22 @@ pub enum TableError {
     TypeError(uint, FieldType, FieldType), // (index, actual, expected)
     LengthError(uint, uint, uint), // (index, actual, expected)
     ValueError(uint),
+    RecordIndexError(uint),
+    FieldNameError(String),
+    FieldIndexError(uint, uint), // (index, number of fields)
+    IndexNameError(String),
+    DuplicateIndexError(String),
+    IndexTypeError(String, IndexType),
+    NoPrimaryKeyError(String),
+    DuplicateKeyError(uint),
+    NullValueError(uint),
+    OverflowError(String),
+    FieldCountError(uint, uint), // (actual, expected)
+    AmbiguousFieldError(String),
+    ExprTypeError(String, expr::ExprType), // (operator, actual)
+    ArgumentCountError(String, uint), // (function, actual)
+    PatternError(String, String), // (pattern, reason)
+    MemoryBudgetError(String, uint), // (operator, minimum blocks)
 }
 
 impl fmt::Show for TableError {
@@ -223,6 +569,38 @@ impl fmt::Show for TableError {
                     index, expected, actual),
             ValueError(index) => write!(fmt,
                     "Field {} contains invalid data.", index),
+            RecordIndexError(index) => write!(fmt,
+                    "Record {} does not exist.", index),
+            FieldNameError(ref name) => write!(fmt,
+                    "Table has no field named `{}`.", name),
+            FieldIndexError(index, num_fields) => write!(fmt,
+                    "Field {} does not exist in a table with {} fields.", index, num_fields),
+            IndexNameError(ref name) => write!(fmt,
+                    "Table has no index named `{}`.", name),
+            DuplicateIndexError(ref name) => write!(fmt,
+                    "Index `{}` already exists.", name),
+            IndexTypeError(ref name, expected) => write!(fmt,
+                    "Index `{}` does not have type {}.", name, expected),
+            NoPrimaryKeyError(ref name) => write!(fmt,
+                    "Table `{}` has no primary key.", name),
+            DuplicateKeyError(index) => write!(fmt,
+                    "Field {} duplicates the key of another record.", index),
+            NullValueError(index) => write!(fmt,
+                    "Field {} cannot be NULL.", index),
+            OverflowError(ref name) => write!(fmt,
+                    "Value of `{}` does not fit in an Integer field.", name),
+            FieldCountError(actual, expected) => write!(fmt,
+                    "Expected {} fields but got {}.", expected, actual),
+            AmbiguousFieldError(ref name) => write!(fmt,
+                    "Field name `{}` matches more than one field.", name),
+            ExprTypeError(ref op, actual) => write!(fmt,
+                    "Operand of `{}` has incorrect type {}.", op, actual),
+            ArgumentCountError(ref function, actual) => write!(fmt,
+                    "Function `{}` cannot take {} arguments.", function, actual),
+            PatternError(ref pattern, ref reason) => write!(fmt,
+                    "Pattern `{}` is invalid: {}", pattern, reason),
+            MemoryBudgetError(ref op, min_blocks) => write!(fmt,
+                    "{} needs a memory budget of at least {} blocks.", op, min_blocks),
         }
     }
 }
@@ -257,6 +635,7 @@ fn write_value(i: uint, value: &Field, buf: &mut [u8]) -> Result<(), TableError>
             buf.mut_slice_from(1).copy_from(s.as_bytes());
             Ok(())
         },
+        Null => Err(NullValueError(i)),
     }
 }
 
@@ -276,8 +655,10 @@ fn read_fields(values: &mut Vec<Field>, fields: &[FieldSchema], buffer: &[u8])
 fn write_fields(values: &[Field], fields: &[FieldSchema], buffer: &mut [u8]) -> Result<(), TableError> {
     for (i, (value, field)) in values.iter().zip(fields.iter()).enumerate() {
         let field_buf = buffer.mut_slice(field.offset, field.offset + field.length);
-        if value.get_type() != field.data_type {
-            return Err(TypeError(i, value.get_type(), field.data_type));
+        match value.get_type() {
+            None => return Err(NullValueError(i)),
+            Some(t) if t != field.data_type => return Err(TypeError(i, t, field.data_type)),
+            Some(_) => (),
         }
         try!(write_value(i, value, field_buf));
     }
@@ -297,18 +678,205 @@ impl Table {
 
         let schema_json = match json::from_reader(&mut schema_file) {
             Ok(j) => j, Err(e) => return Err(ParserError(e)) };
-        let schema = match Decodable::decode(&mut json::Decoder::new(schema_json)) {
+        let schema: TableSchema = match Decodable::decode(&mut json::Decoder::new(schema_json)) {
             Ok(s) => s, Err(e) => return Err(DecoderError(e)) };
 
-        Ok(Table { schema: schema, file: data_file })
+        let mut deleted_file = match fs::File::open_mode(
+                &table_path.join("deleted.bin"), io::Open, io::ReadWrite) {
+            Ok(f) => f, Err(e) => return Err(OpenIoError(e)) };
+        let deleted_buf = match deleted_file.read_to_end() {
+            Ok(b) => b, Err(e) => return Err(OpenIoError(e)) };
+        let deleted = deleted_buf.as_slice().chunks(4).map(|b| read_u32(b) as uint).collect();
+
+        let mut indexes = Vec::new();
+        for index_schema in try!(read_index_catalog(&table_path)).move_iter() {
+            let field = match schema.find_field(index_schema.field.as_slice()) {
+                Ok(f) => f, Err(_) => return Err(UnknownFieldError(index_schema.field)) };
+            indexes.push(match Index::open(&table_path, index_schema, field) {
+                Ok(i) => i, Err(e) => return Err(OpenIoError(e)) });
+        }
+
+        let zone_map = match zonemap::ZoneMap::open(
+                &table_path.join("zonemap.bin"), schema.fields.as_slice()) {
+            Ok(z) => z, Err(e) => return Err(OpenIoError(e)) };
+
+        let mut bloom_filters = Vec::new();
+        for (field, field_schema) in schema.fields.iter().enumerate() {
+            let bloom_path = bloom_filter_path(&table_path, field_schema.name.as_slice());
+            if bloom_path.exists() {
+                bloom_filters.push(match bloom::BloomFilter::open(&bloom_path, field) {
+                    Ok(b) => b, Err(e) => return Err(OpenIoError(e)) });
+            }
+        }
+
+        let mut table = Table {
+            schema: schema,
+            file: data_file,
+
+            path: table_path,
+            deleted: deleted,
+            deleted_file: deleted_file,
+            indexes: indexes,
+            sequences: Vec::new(),
+            zone_map: zone_map,
+            bloom_filters: bloom_filters,
+        };
+        match table.load_sequences() {
+            Ok(()) => (), Err(e) => return Err(OpenIoError(e)) };
+
+        // Bitmap and full-text indexes are only written out when flushed, so they may have
+        // missed changes made before a crash.
+        let num_entries = table.num_entries();
+        let stale_indexes: Vec<String> = table.indexes.iter()
+            .filter(|index| index.is_stale(num_entries))
+            .map(|index| index.schema.name.clone())
+            .collect();
+        for name in stale_indexes.iter() {
+            match table.rebuild_index(name.as_slice()) {
+                Ok(()) => (), Err(e) => return Err(OpenTableError(e)) };
+        }
+        for j in range(0, table.bloom_filters.len()) {
+            let stale = {
+                let filter = table.bloom_filters.get(j);
+                filter.is_stale() || filter.universe != num_entries
+            };
+            if stale {
+                match table.rebuild_bloom_filter(j) {
+                    Ok(()) => (), Err(e) => return Err(OpenTableError(e)) };
+            }
+        }
+
+        let num_blocks = (table.num_entries() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        if table.zone_map.num_blocks() != num_blocks {
+            match table.rebuild_zone_map() {
+                Ok(()) => (), Err(e) => return Err(OpenIoError(e)) };
+        }
+
+        Ok(table)
+    }
+
+    fn rebuild_zone_map(&mut self) -> io::IoResult<()> {
+        try!(self.zone_map.clear());
+
+        let num_entries = self.num_entries();
+        let stride = self.schema.entry_stride;
+        let fields = self.schema.fields.as_slice();
+        let mut data = io::BufferedReader::new(try!(fs::File::open(&self.path.join("data.bin"))));
+
+        let mut values = Vec::new();
+        for i in range(0, num_entries) {
+            let buffer = try!(data.read_exact(stride));
+            if self.deleted.contains(&i) {
+                continue;
+            }
+            match read_fields(&mut values, fields, buffer.as_slice()) {
+                Ok(()) => (), Err(_) => return Err(io::standard_error(io::InvalidInput)) };
+            try!(self.zone_map.add(i, values.as_slice()));
+        }
+        Ok(())
+    }
+
+    // Rewrites the data file without deleted records. Since this moves records, all indexes,
+    // Bloom filters and the zone map are rebuilt afterwards.
+    pub fn compact(&mut self) -> Result<(), TableError> {
+        let data_path = self.path.join("data.bin");
+        let tmp_path = self.path.join("data.bin.tmp");
+
+        {
+            let mut tmp_file = try!(fs::File::create(&tmp_path).map_err(IoError));
+            let num_entries = self.num_entries();
+            for i in range(0, num_entries) {
+                if self.is_deleted(i) {
+                    continue;
+                }
+                let values = try!(self.read_entry(i));
+                let (buffer, _) = try!(self.encode_entry(values.as_slice()));
+                try!(tmp_file.write(buffer.as_slice()).map_err(IoError));
+            }
+            try!(tmp_file.fsync().map_err(IoError));
+        }
+        try!(fs::rename(&tmp_path, &data_path).map_err(IoError));
+        self.file = try!(fs::File::open_mode(&data_path, io::Open, io::ReadWrite).map_err(IoError));
+
+        self.deleted.clear();
+        self.deleted_file = try!(fs::File::open_mode(
+                &self.path.join("deleted.bin"), io::Truncate, io::ReadWrite).map_err(IoError));
+
+        let index_names: Vec<String> = self.indexes.iter().map(|i| i.schema.name.clone()).collect();
+        for name in index_names.iter() {
+            try!(self.rebuild_index(name.as_slice()));
+        }
+        for j in range(0, self.bloom_filters.len()) {
+            try!(self.rebuild_bloom_filter(j));
+        }
+        self.rebuild_zone_map().map_err(IoError)
+    }
+
+    // Writes out the indexes and Bloom filters that are kept in memory. This also happens when
+    // the table is dropped, but then errors can only be logged.
+    pub fn flush(&mut self) -> Result<(), TableError> {
+        for index in self.indexes.mut_iter() {
+            try!(index.flush().map_err(IoError));
+        }
+        for filter in self.bloom_filters.mut_iter() {
+            try!(filter.flush().map_err(IoError));
+        }
+        Ok(())
+    }
+
+    fn load_sequences(&mut self) -> io::IoResult<()> {
+        let auto_fields: Vec<uint> = self.schema.fields.iter().enumerate()
+            .filter(|&(_, f)| f.is_auto_increment()).map(|(i, _)| i).collect();
+
+        let sequences_path = self.path.join("sequences.bin");
+        if sequences_path.exists() {
+            let buf = try!(try!(fs::File::open(&sequences_path)).read_to_end());
+            self.sequences = auto_fields.iter().zip(buf.as_slice().chunks(4))
+                .map(|(&field, b)| Sequence { field: field, next: read_u32(b) }).collect();
+        }
+
+        if self.sequences.len() != auto_fields.len() {
+            // The sequence file is missing or doesn't match the schema, so restart each
+            // sequence after the largest value already in the table.
+            let mut next = Vec::from_elem(auto_fields.len(), 0u32);
+            for values in self.iter() {
+                for (j, &field) in auto_fields.iter().enumerate() {
+                    match *values.get(field) {
+                        Integer(x) if x >= *next.get(j) => *next.get_mut(j) = x + 1,
+                        _ => (),
+                    }
+                }
+            }
+            self.sequences = auto_fields.iter().zip(next.iter())
+                .map(|(&field, &next)| Sequence { field: field, next: next }).collect();
+            try!(self.write_sequences());
+        }
+
+        Ok(())
+    }
+
+    fn write_sequences(&self) -> io::IoResult<()> {
+        let mut buf = Vec::from_elem(self.sequences.len() * 4, 0u8);
+        for (j, sequence) in self.sequences.iter().enumerate() {
+            write_u32(sequence.next, buf.mut_slice(j * 4, j * 4 + 4));
+        }
+
+        // Replace the file atomically, so a crash leaves either the old or the new values.
+        let tmp_path = self.path.join("sequences.bin.tmp");
+        {
+            let mut tmp_file = try!(fs::File::create(&tmp_path));
+            try!(tmp_file.write(buf.as_slice()));
+            try!(tmp_file.fsync());
+        }
+        fs::rename(&tmp_path, &self.path.join("sequences.bin"))
     }
 
     pub fn iter<'s>(&'s mut self) -> PhysicalTableIterator<'s> {
-        let num_entries = self.file.stat().unwrap().size / self.schema.entry_stride as u64;
+        let num_entries = self.num_entries();
         PhysicalTableIterator {
             table: self,
             i: 0,
-            len: num_entries as uint,
+            len: num_entries,
 
             block_base: None,
             block_data: Vec::new(),
@@ -318,18 +886,466 @@ impl Table {
         }
     }
 
-    pub fn append_entry(&mut self, values: &[Field]) -> Result<(), TableError> {
+    // Number of record slots in the data file, including deleted ones.
+    pub fn num_entries(&mut self) -> uint {
+        (self.file.stat().unwrap().size / self.schema.entry_stride as u64) as uint
+    }
+
+    pub fn is_deleted(&self, i: uint) -> bool {
+        self.deleted.contains(&i)
+    }
+
+    // Returns the entry's bytes along with the values as they will be read back, which can
+    // differ from `values` when Text fields get truncated.
+    fn encode_entry(&self, values: &[Field]) -> Result<(Vec<u8>, Vec<Field>), TableError> {
+        let fields = self.schema.fields.as_slice();
         let mut buffer = Vec::from_elem(self.schema.entry_stride, 0u8);
+        try!(write_fields(values, fields, buffer.as_mut_slice()));
+
+        let mut stored = Vec::new();
+        try!(read_fields(&mut stored, fields, buffer.as_slice()));
+        Ok((buffer, stored))
+    }
+
+    pub fn read_entry(&mut self, i: uint) -> Result<Vec<Field>, TableError> {
+        if i >= self.num_entries() || self.is_deleted(i) {
+            return Err(RecordIndexError(i));
+        }
+
+        let stride = self.schema.entry_stride;
+        try!(self.file.seek((i * stride) as i64, io::SeekSet).map_err(IoError));
+        let buffer = try!(self.file.read_exact(stride).map_err(IoError));
+
+        let mut values = Vec::new();
+        try!(read_fields(&mut values, self.schema.fields.as_slice(), buffer.as_slice()));
+        Ok(values)
+    }
+
+    // An auto-increment field given as NULL gets the next value of its sequence. A value given
+    // explicitly is kept, and moves the sequence past it as in `update_entry`. Returns the value
+    // of the table's auto-increment field, if it has one.
+    pub fn append_entry(&mut self, values: &[Field]) -> Result<Option<u32>, TableError> {
+        let mut values = Vec::from_slice(values);
+        for sequence in self.sequences.iter() {
+            if sequence.field < values.len() && *values.get(sequence.field) == Null {
+                *values.get_mut(sequence.field) = Integer(sequence.next);
+            }
+        }
 
-        try!(write_fields(values, self.schema.fields.as_slice(), buffer.as_mut_slice()));
+        let (buffer, stored) = try!(self.encode_entry(values.as_slice()));
+        try!(self.check_unique(stored.as_slice(), None));
+        let pos = self.num_entries();
+
+        // Sequences are saved before the record is written, so a crash in between may skip
+        // values but never hands out the same one twice.
+        try!(self.advance_sequences(stored.as_slice()).map_err(IoError));
+        let assigned = self.sequences.iter().next().and_then(|sequence| {
+            match *stored.get(sequence.field) { Integer(x) => Some(x), _ => None }
+        });
 
         match self.file.seek(0, io::SeekEnd) {
             Ok(()) => (), Err(e) => return Err(IoError(e)) };
         match self.file.write(buffer.as_slice()) {
             Ok(()) => (), Err(e) => return Err(IoError(e)) };
 
+        for index in self.indexes.mut_iter() {
+            try!(index.insert(stored.as_slice(), pos).map_err(IoError));
+        }
+        try!(self.zone_map.add(pos, stored.as_slice()).map_err(IoError));
+        try!(self.add_to_bloom_filters(stored.as_slice(), pos));
+
+        Ok(assigned)
+    }
+
+    // Values stored past the end of a sequence move it along, so that it doesn't hand them out
+    // again.
+    fn advance_sequences(&mut self, stored: &[Field]) -> io::IoResult<()> {
+        let mut advanced = false;
+        for sequence in self.sequences.mut_iter() {
+            match stored[sequence.field] {
+                Integer(x) if x >= sequence.next => {
+                    sequence.next = x + 1;
+                    advanced = true;
+                },
+                _ => (),
+            }
+        }
+        if advanced {
+            try!(self.write_sequences());
+        }
+        Ok(())
+    }
+
+    pub fn update_entry(&mut self, i: uint, values: &[Field]) -> Result<(), TableError> {
+        let old_values = try!(self.read_entry(i));
+        let (buffer, stored) = try!(self.encode_entry(values));
+        try!(self.check_unique(stored.as_slice(), Some(i)));
+
+        // As in `append_entry`, this is saved before the record.
+        try!(self.advance_sequences(stored.as_slice()).map_err(IoError));
+
+        let stride = self.schema.entry_stride;
+        try!(self.file.seek((i * stride) as i64, io::SeekSet).map_err(IoError));
+        try!(self.file.write(buffer.as_slice()).map_err(IoError));
+
+        for index in self.indexes.mut_iter() {
+            if old_values.get(index.field) != stored.get(index.field) {
+                try!(index.remove(old_values.as_slice(), i).map_err(IoError));
+                try!(index.insert(stored.as_slice(), i).map_err(IoError));
+            }
+        }
+        try!(self.zone_map.add(i, stored.as_slice()).map_err(IoError));
+        try!(self.add_to_bloom_filters(stored.as_slice(), i));
+
+        Ok(())
+    }
+
+    // Deleted records keep their slot in data.bin and are only skipped by iterators.
+    pub fn delete_entry(&mut self, i: uint) -> Result<(), TableError> {
+        let old_values = try!(self.read_entry(i));
+
+        let mut buf = [0u8, ..4];
+        write_u32(i as u32, buf.as_mut_slice());
+        try!(self.deleted_file.seek(0, io::SeekEnd).map_err(IoError));
+        try!(self.deleted_file.write(buf.as_slice()).map_err(IoError));
+        self.deleted.insert(i);
+
+        for index in self.indexes.mut_iter() {
+            try!(index.remove(old_values.as_slice(), i).map_err(IoError));
+        }
+
+        let block_start = i - i % BLOCK_SIZE;
+        let block_end = min(block_start + BLOCK_SIZE, self.num_entries());
+        let emptied = range(block_start, block_end).all(|j| self.is_deleted(j));
+        if emptied {
+            try!(self.zone_map.clear_block(i / BLOCK_SIZE).map_err(IoError));
+        }
+
+        Ok(())
+    }
+
+    // Fails if a unique index already holds one of the keys in `values` for a record other
+    // than `ignored`.
+    fn check_unique(&mut self, values: &[Field], ignored: Option<uint>) -> Result<(), TableError> {
+        for index in self.indexes.mut_iter() {
+            if !index.schema.unique {
+                continue;
+            }
+            let field = index.field;
+            match try!(index.find_first(&values[field]).map_err(IoError)) {
+                (Some(pos), _) if Some(pos) != ignored => return Err(DuplicateKeyError(field)),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn find_index(&self, name: &str) -> Option<uint> {
+        self.indexes.iter().position(|i| i.schema.name.as_slice() == name)
+    }
+
+    pub fn create_index(&mut self, name: &str, field_name: &str, index_type: IndexType)
+            -> Result<(), TableError> {
+        self.add_index(IndexSchema {
+            name: name.to_strbuf(),
+            field: field_name.to_strbuf(),
+            index_type: index_type,
+            unique: false,
+            tokenizer: None,
+        })
+    }
+
+    pub fn create_text_index(&mut self, name: &str, field_name: &str,
+                             tokenizer: fulltext::TokenizerConfig) -> Result<(), TableError> {
+        self.add_index(IndexSchema {
+            name: name.to_strbuf(),
+            field: field_name.to_strbuf(),
+            index_type: TextIndexType,
+            unique: false,
+            tokenizer: Some(tokenizer),
+        })
+    }
+
+    fn add_index(&mut self, index_schema: IndexSchema) -> Result<(), TableError> {
+        if self.find_index(index_schema.name.as_slice()).is_some() {
+            return Err(DuplicateIndexError(index_schema.name));
+        }
+        let field = try!(self.schema.find_field(index_schema.field.as_slice()));
+
+        let data_type = self.schema.fields.get(field).data_type;
+        if index_schema.index_type == TextIndexType && data_type != TextType {
+            return Err(TypeError(field, data_type, TextType));
+        }
+
+        let mut index = try!(Index::create(&self.path, index_schema, field,
+                self.schema.fields.get(field)).map_err(IoError));
+        try!(self.populate_index(&mut index));
+        try!(index.flush().map_err(IoError));
+
+        self.indexes.push(index);
+        self.write_index_catalog().map_err(IoError)
+    }
+
+    // Recreates an index from a full scan of the table.
+    pub fn rebuild_index(&mut self, name: &str) -> Result<(), TableError> {
+        let i = match self.find_index(name) {
+            Some(i) => i, None => return Err(IndexNameError(name.to_strbuf())) };
+
+        let (index_schema, field) = {
+            let old = self.indexes.get(i);
+            (old.schema.clone(), old.field)
+        };
+        let mut index = try!(Index::create(&self.path, index_schema, field,
+                self.schema.fields.get(field)).map_err(IoError));
+        try!(self.populate_index(&mut index));
+        try!(index.flush().map_err(IoError));
+
+        *self.indexes.get_mut(i) = index;
+        Ok(())
+    }
+
+    fn populate_index(&mut self, index: &mut Index) -> Result<(), TableError> {
+        let mut rows = self.iter();
+        let num_entries = rows.indexable();
+        for i in range(0, num_entries) {
+            match rows.idx(i) {
+                Some(values) => try!(index.insert(values.as_slice(), i).map_err(IoError)),
+                None => (),
+            }
+        }
+
+        index.set_universe(num_entries);
         Ok(())
     }
+
+    fn find_typed_index(&self, name: &str, index_type: IndexType) -> Result<uint, TableError> {
+        match self.find_index(name) {
+            None => Err(IndexNameError(name.to_strbuf())),
+            Some(i) if self.indexes.get(i).schema.index_type != index_type =>
+                Err(IndexTypeError(name.to_strbuf(), index_type)),
+            Some(i) => Ok(i),
+        }
+    }
+
+    pub fn index_scan<'s>(&'s mut self, name: &str, low: Bound, high: Bound)
+            -> Result<btree::IndexScan<'s>, TableError> {
+        let index = try!(self.find_typed_index(name, BTreeIndexType));
+        btree::IndexScan::new(self.iter(), index, low, high)
+    }
+
+    pub fn hash_scan<'s>(&'s mut self, name: &str, key: &Field)
+            -> Result<hash_index::HashScan<'s>, TableError> {
+        let index = try!(self.find_typed_index(name, HashIndexType));
+        hash_index::HashScan::new(self.iter(), index, key)
+    }
+
+    pub fn zone_scan<'s>(&'s mut self, field_name: &str, low: Bound, high: Bound)
+            -> Result<zonemap::ZoneScan<'s>, TableError> {
+        let field = try!(self.schema.find_field(field_name));
+        Ok(zonemap::ZoneScan::new(self.iter(), field, low, high))
+    }
+
+    // Scans for the records whose Text field matches a LIKE pattern, or ILIKE if
+    // `case_insensitive`. Uses a B-tree index on the field when the pattern has a literal prefix.
+    pub fn like_scan<'s>(&'s mut self, field_name: &str, pattern: &str, case_insensitive: bool)
+            -> Result<pattern::PatternScan<'s>, TableError> {
+        let field = try!(self.schema.find_field(field_name));
+        let data_type = self.schema.fields.get(field).data_type;
+        if data_type != TextType {
+            return Err(TypeError(field, data_type, TextType));
+        }
+        self.pattern_scan(field, pattern, case_insensitive)
+    }
+
+    fn pattern_scan<'s>(&'s mut self, field: uint, pattern: &str, case_insensitive: bool)
+            -> Result<pattern::PatternScan<'s>, TableError> {
+        let prefix = pattern::like_prefix(pattern);
+        let btree = self.indexes.iter()
+            .position(|i| i.field == field && i.schema.index_type == BTreeIndexType);
+        match btree {
+            Some(index) if !case_insensitive && !prefix.is_empty() => {
+                let low = Included(Text(prefix.clone()));
+                let high = pattern::prefix_end(prefix.as_slice());
+                let rows = try!(btree::IndexScan::new(self.iter(), index, low, high));
+                Ok(pattern::PatternScan::with_index(rows, field, pattern))
+            },
+            _ => Ok(pattern::PatternScan::without_index(self.iter(), field, pattern,
+                                                        case_insensitive)),
+        }
+    }
+
+    // Records satisfying a condition. Like `like_scan`, reads only part of a B-tree index when
+    // the condition requires a case-sensitive LIKE with a literal prefix on the indexed field;
+    // `select::select_expr` over `iter()` always reads the whole table.
+    pub fn select_where<'s>(&'s mut self, condition: &expr::Expr)
+            -> Result<select::SelectExpr<pattern::PatternScan<'s>>, TableError> {
+        let bound = try!(expr::bind_condition(condition, &self.schema));
+        let like = pattern::required_like(bound.expr());
+        let rows = match like {
+            Some((field, pattern)) => try!(self.pattern_scan(field, pattern.as_slice(), false)),
+            None => pattern::PatternScan::all(self.iter()),
+        };
+        select::select_expr(rows, condition)
+    }
+
+    // Evaluates a query over the table's bitmap indexes, returning the matching positions.
+    pub fn bitmap_query(&mut self, query: &bitmap::BitmapQuery)
+            -> Result<bitmap::Bitmap, TableError> {
+        Ok(match *query {
+            bitmap::Equals(ref name, ref value) => {
+                let index = try!(self.find_typed_index(name.as_slice(), BitmapIndexType));
+                try!(self.indexes.get_mut(index).bitmap_index()).lookup(value)
+            },
+            bitmap::And(ref a, ref b) =>
+                try!(self.bitmap_query(&**a)).and(&try!(self.bitmap_query(&**b))),
+            bitmap::Or(ref a, ref b) =>
+                try!(self.bitmap_query(&**a)).or(&try!(self.bitmap_query(&**b))),
+            bitmap::Not(ref a) => {
+                let universe = self.num_entries();
+                try!(self.bitmap_query(&**a)).not(universe)
+            },
+        })
+    }
+
+    pub fn text_search<'s>(&'s mut self, name: &str, query: &fulltext::TextQuery)
+            -> Result<fulltext::TextSearch<'s>, TableError> {
+        let index = try!(self.find_typed_index(name, TextIndexType));
+        let positions = try!(self.indexes.get_mut(index).inverted_index()).search(query);
+        Ok(fulltext::TextSearch::new(self.iter(), positions))
+    }
+
+    // Builds a Bloom filter over a field, replacing any existing one. Probes through the
+    // field's indexes then skip keys the filter rejects.
+    pub fn create_bloom_filter(&mut self, field_name: &str, false_positive_rate: f64)
+            -> Result<(), TableError> {
+        let field = try!(self.schema.find_field(field_name));
+
+        let num_entries = self.num_entries();
+        let path = bloom_filter_path(&self.path, field_name);
+        let mut filter = try!(bloom::BloomFilter::create(&path, field, num_entries,
+                false_positive_rate).map_err(IoError));
+        try!(self.populate_bloom_filter(&mut filter));
+
+        match self.find_bloom_filter(field) {
+            Some(j) => *self.bloom_filters.get_mut(j) = filter,
+            None => self.bloom_filters.push(filter),
+        }
+        Ok(())
+    }
+
+    // The Bloom filter over a field, if it has one. Fails if there is no such field.
+    pub fn bloom_filter<'s>(&'s self, field_name: &str)
+            -> Result<Option<&'s bloom::BloomFilter>, TableError> {
+        let field = try!(self.schema.find_field(field_name));
+        Ok(self.find_bloom_filter(field).map(|j| self.bloom_filters.get(j)))
+    }
+
+    fn find_bloom_filter(&self, field: uint) -> Option<uint> {
+        self.bloom_filters.iter().position(|b| b.field == field)
+    }
+
+    // Recreates a filter with the same false positive rate, sized for twice the live records.
+    fn rebuild_bloom_filter(&mut self, j: uint) -> Result<(), TableError> {
+        let (field, false_positive_rate) = {
+            let old = self.bloom_filters.get(j);
+            (old.field, old.false_positive_rate)
+        };
+        let capacity = 2 * (self.num_entries() - self.deleted.len());
+        let path = bloom_filter_path(&self.path, self.schema.fields.get(field).name.as_slice());
+        let mut filter = try!(bloom::BloomFilter::create(&path, field, capacity,
+                false_positive_rate).map_err(IoError));
+        try!(self.populate_bloom_filter(&mut filter));
+
+        *self.bloom_filters.get_mut(j) = filter;
+        Ok(())
+    }
+
+    fn populate_bloom_filter(&mut self, filter: &mut bloom::BloomFilter)
+            -> Result<(), TableError> {
+        let mut rows = self.iter();
+        let num_entries = rows.indexable();
+        for i in range(0, num_entries) {
+            match rows.idx(i) {
+                Some(values) =>
+                    try!(filter.insert(values.get(filter.field), i).map_err(IoError)),
+                None => (),
+            }
+        }
+
+        filter.universe = num_entries;
+        filter.flush().map_err(IoError)
+    }
+
+    fn add_to_bloom_filters(&mut self, values: &[Field], pos: uint) -> Result<(), TableError> {
+        let mut full = Vec::new();
+        for (j, filter) in self.bloom_filters.mut_iter().enumerate() {
+            try!(filter.insert(&values[filter.field], pos).map_err(IoError));
+            if filter.is_full() {
+                full.push(j);
+            }
+        }
+        for &j in full.iter() {
+            try!(self.rebuild_bloom_filter(j));
+        }
+        Ok(())
+    }
+
+    // False if the field has a Bloom filter and it rules out the key.
+    fn bloom_may_contain(&mut self, field: uint, key: &Field) -> bool {
+        match self.find_bloom_filter(field) {
+            Some(j) => self.bloom_filters.get_mut(j).may_contain(key),
+            None => true,
+        }
+    }
+
+    fn bloom_false_positive(&mut self, field: uint) {
+        match self.find_bloom_filter(field) {
+            Some(j) => self.bloom_filters.get_mut(j).record_false_positive(),
+            None => (),
+        }
+    }
+
+    fn write_index_catalog(&self) -> io::IoResult<()> {
+        let catalog: Vec<IndexSchema> = self.indexes.iter().map(|i| i.schema.clone()).collect();
+        write_index_catalog(&self.path, &catalog)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        match self.flush() {
+            Ok(()) => (),
+            // Whatever wasn't written out is still marked dirty, and gets rebuilt by `open`.
+            Err(e) => {
+                let mut stderr = io::stderr();
+                let _ = writeln!(stderr, "Failed to flush table `{}`: {}", self.schema.name, e);
+            },
+        }
+    }
+}
+
+fn bloom_filter_path(table_path: &Path, field_name: &str) -> Path {
+    table_path.join(format!("{}.bloom", field_name).as_slice())
+}
+
+fn write_index_catalog(table_path: &Path, catalog: &Vec<IndexSchema>) -> io::IoResult<()> {
+    let mut catalog_file = try!(fs::File::create(&table_path.join("indexes.json")));
+    catalog.encode(&mut json::Encoder::new(&mut catalog_file))
+}
+
+fn read_index_catalog(table_path: &Path) -> Result<Vec<IndexSchema>, TableOpenError> {
+    let catalog_path = table_path.join("indexes.json");
+    if !catalog_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut catalog_file = match fs::File::open(&catalog_path) {
+        Ok(f) => f, Err(e) => return Err(OpenIoError(e)) };
+    let catalog_json = match json::from_reader(&mut catalog_file) {
+        Ok(j) => j, Err(e) => return Err(ParserError(e)) };
+    match Decodable::decode(&mut json::Decoder::new(catalog_json)) {
+        Ok(c) => Ok(c), Err(e) => Err(DecoderError(e))
+    }
 }
 
 pub fn validate_schema(schema: &TableSchema) -> Result<(), String> {
@@ -354,6 +1370,10 @@ pub fn validate_schema(schema: &TableSchema) -> Result<(), String> {
                     return Err(format!(
                             "Field `{}` is Text and must have length of at most 256.", field.name));
                 }
+                if field.is_auto_increment() {
+                    return Err(format!(
+                            "Field `{}` is Text and can't be auto-increment.", field.name));
+                }
             },
         }
 
@@ -367,17 +1387,335 @@ pub fn validate_schema(schema: &TableSchema) -> Result<(), String> {
         }
     }
 
+    // `append_entry` reports a single assigned value.
+    if schema.fields.iter().filter(|field| field.is_auto_increment()).count() > 1 {
+        return Err(format!("Table `{}` has more than one auto-increment field.", schema.name));
+    }
+
+    match schema.primary_key {
+        Some(ref key) => match schema.find_field(key.as_slice()) {
+            Ok(_) => (),
+            Err(AmbiguousFieldError(_)) => return Err(format!(
+                    "Primary key `{}` matches more than one field.", key)),
+            Err(_) => return Err(format!("Primary key `{}` is not a field of the table.", key)),
+        },
+        None => (),
+    }
+
     Ok(())
 }
 
-pub fn create_table(db_path: &Path, schema: &TableSchema) -> io::IoResult<()> {
+pub fn create_table(db_path: &Path, schema: &TableSchema) -> Result<(), TableError> {
+    // Checked before anything is written, so a bad key doesn't leave half a table behind.
+    let primary_key = match schema.primary_key {
+        Some(ref key) => Some((key.clone(), try!(schema.find_field(key.as_slice())))),
+        None => None,
+    };
+
     let table_path = db_path.join("tables").join(schema.name.as_slice());
-    try!(fs::mkdir_recursive(&table_path, io::UserDir));
+    try!(fs::mkdir_recursive(&table_path, io::UserDir).map_err(IoError));
+
+    try!(fs::File::create(&table_path.join("data.bin")).map_err(IoError));
+
+    let mut schema_file = try!(fs::File::create(&table_path.join("schema.json")).map_err(IoError));
+    try!(schema.encode(&mut json::Encoder::new(&mut schema_file)).map_err(IoError));
+
+    let mut catalog = Vec::new();
+    match primary_key {
+        Some((key, field)) => {
+            let index_schema = IndexSchema {
+                name: PRIMARY_KEY_INDEX.to_strbuf(),
+                field: key,
+                index_type: BTreeIndexType,
+                unique: true,
+                tokenizer: None,
+            };
+            let index = try!(Index::create(&table_path, index_schema, field,
+                    schema.fields.get(field)).map_err(IoError));
+            catalog.push(index.schema);
+        },
+        None => (),
+    }
+    write_index_catalog(&table_path, &catalog).map_err(IoError)
+}
 
-    try!(fs::File::create(&table_path.join("data.bin")));
+#[cfg(test)]
+mod test {
+    use serialize::Decodable;
+    use serialize::json;
+    use std::default::Default;
+    use std::mem;
+
+    use super::testing;
+    use super::{
+        AmbiguousFieldError,
+        BTreeIndexType,
+        BitmapIndexType,
+        DuplicateKeyError,
+        Field,
+        FieldNameError,
+        FieldSchema,
+        HashIndexType,
+        IndexTypeError,
+        Integer,
+        IntegerType,
+        KeyLookupIterator,
+        NoPrimaryKeyError,
+        Null,
+        Table,
+        TableIterator,
+        TableSchema,
+        Text,
+        TextType,
+        Unbounded,
+        bitmap,
+        btree,
+        create_table,
+        expr,
+        fulltext,
+        validate_schema,
+    };
+
+    #[test]
+    fn lookup_key_without_primary_key() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T", &[("id", IntegerType)], None,
+                                        &[vec![Integer(1)]]);
+        match table.iter().lookup_key(&Integer(1)) {
+            Err(NoPrimaryKeyError(ref name)) => assert_eq!(name.as_slice(), "T"),
+            _ => fail!("expected NoPrimaryKeyError"),
+        }
+    }
 
-    let mut schema_file = try!(fs::File::create(&table_path.join("schema.json")));
-    try!(schema.encode(&mut json::Encoder::new(&mut schema_file)));
+    #[test]
+    fn create_table_with_unknown_primary_key() {
+        let db = testing::scratch_db();
+        let schema = TableSchema {
+            name: "T".to_strbuf(),
+            fields: vec![FieldSchema {
+                name: "id".to_strbuf(),
+                offset: 0,
+                data_type: IntegerType,
+                length: 4,
+                auto_increment: None,
+            }],
+            entry_stride: 4,
+            primary_key: Some("key".to_strbuf()),
+        };
+        match create_table(db.path(), &schema) {
+            Err(FieldNameError(ref name)) => assert_eq!(name.as_slice(), "key"),
+            _ => fail!("expected FieldNameError"),
+        }
+        assert!(!db.path().join("tables").join("T").exists());
+    }
 
-    Ok(())
+    #[test]
+    fn index_scan_over_hash_index() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 20, 3);
+        table.create_index("value_hash", "value", HashIndexType).unwrap();
+        let index = table.find_index("value_hash").unwrap();
+        match btree::IndexScan::new(table.iter(), index, Unbounded, Unbounded) {
+            Err(IndexTypeError(ref name, _)) => assert_eq!(name.as_slice(), "value_hash"),
+            _ => fail!("expected IndexTypeError"),
+        }
+    }
+
+    fn auto_increment_field(name: &str, offset: uint) -> FieldSchema {
+        FieldSchema {
+            name: name.to_strbuf(),
+            offset: offset,
+            data_type: IntegerType,
+            length: 4,
+            auto_increment: Some(true),
+        }
+    }
+
+    // Table with an auto-increment `id` primary key and nothing else.
+    fn auto_increment_table(db_path: &Path) -> Table {
+        let schema = TableSchema {
+            name: "T".to_strbuf(),
+            fields: vec![auto_increment_field("id", 0)],
+            entry_stride: 4,
+            primary_key: Some("id".to_strbuf()),
+        };
+        create_table(db_path, &schema).unwrap();
+        Table::open(db_path, "T").unwrap()
+    }
+
+    #[test]
+    fn update_past_end_of_sequence() {
+        let db = testing::scratch_db();
+        let mut table = auto_increment_table(db.path());
+        assert_eq!(table.append_entry(&[Null]).unwrap(), Some(0));
+        table.update_entry(0, &[Integer(5)]).unwrap();
+        assert_eq!(table.append_entry(&[Null]).unwrap(), Some(6));
+        drop(table);
+
+        let mut table = Table::open(db.path(), "T").unwrap();
+        assert_eq!(table.append_entry(&[Null]).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn explicit_auto_increment_values() {
+        let db = testing::scratch_db();
+        let mut table = auto_increment_table(db.path());
+
+        // Values given by hand are kept, and later ones continue after them.
+        assert_eq!(table.append_entry(&[Integer(10)]).unwrap(), Some(10));
+        assert_eq!(table.append_entry(&[Null]).unwrap(), Some(11));
+        assert_eq!(table.append_entry(&[Integer(3)]).unwrap(), Some(3));
+        assert_eq!(table.append_entry(&[Null]).unwrap(), Some(12));
+        match table.append_entry(&[Integer(11)]) {
+            Err(DuplicateKeyError(0)) => (),
+            _ => fail!("expected DuplicateKeyError"),
+        }
+    }
+
+    #[test]
+    fn several_auto_increment_fields() {
+        let schema = TableSchema {
+            name: "T".to_strbuf(),
+            fields: vec![auto_increment_field("a", 0), auto_increment_field("b", 4)],
+            entry_stride: 8,
+            primary_key: None,
+        };
+        assert!(validate_schema(&schema).is_err());
+    }
+
+    #[test]
+    fn ambiguous_field_names() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T",
+                                        &[("A.key", IntegerType), ("B.key", IntegerType)], None,
+                                        &[vec![Integer(1), Integer(2)]]);
+
+        // The unqualified name fits both fields, which is reported as such rather than as a
+        // missing field.
+        match table.create_index("key_btree", "key", BTreeIndexType) {
+            Err(AmbiguousFieldError(ref name)) => assert_eq!(name.as_slice(), "key"),
+            _ => fail!("expected AmbiguousFieldError"),
+        }
+        match table.create_bloom_filter("key", 0.01) {
+            Err(AmbiguousFieldError(_)) => (),
+            _ => fail!("expected AmbiguousFieldError"),
+        }
+        match table.bloom_filter("key") {
+            Err(AmbiguousFieldError(_)) => (),
+            _ => fail!("expected AmbiguousFieldError"),
+        }
+        match table.zone_scan("key", Unbounded, Unbounded) {
+            Err(AmbiguousFieldError(_)) => (),
+            _ => fail!("expected AmbiguousFieldError"),
+        }
+        assert!(table.bloom_filter("A.key").unwrap().is_none());
+
+        let fields = ["A.key", "B.key"].iter().enumerate().map(|(i, name)| FieldSchema {
+            name: name.to_strbuf(),
+            offset: i * 4,
+            data_type: IntegerType,
+            length: 4,
+            auto_increment: None,
+        }).collect();
+        let schema = TableSchema {
+            name: "U".to_strbuf(),
+            fields: fields,
+            entry_stride: 8,
+            primary_key: Some("key".to_strbuf()),
+        };
+        assert!(validate_schema(&schema).is_err());
+    }
+
+    #[test]
+    fn schema_without_auto_increment() {
+        let schema_json = json::from_str(r#"{
+            "name": "T",
+            "fields": [{"name": "id", "offset": 0, "data_type": "IntegerType", "length": 4}],
+            "entry_stride": 4,
+            "primary_key": null
+        }"#).unwrap();
+        let schema: TableSchema =
+            Decodable::decode(&mut json::Decoder::new(schema_json)).unwrap();
+        assert!(!schema.fields.get(0).is_auto_increment());
+    }
+
+    #[test]
+    fn bitmap_index_changes_lost_before_flush() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 20, 3);
+        table.create_index("value_bitmap", "value", BitmapIndexType).unwrap();
+        table.update_entry(0, &[Integer(0), Integer(1)]).unwrap();
+        // As if the process died before the index was flushed.
+        unsafe { mem::forget(table); }
+
+        let mut table = Table::open(db.path(), "T").unwrap();
+        let ones = table.bitmap_query(
+                &bitmap::Equals("value_bitmap".to_strbuf(), Integer(1))).unwrap();
+        let zeros = table.bitmap_query(
+                &bitmap::Equals("value_bitmap".to_strbuf(), Integer(0))).unwrap();
+        assert!(ones.contains(0));
+        assert!(!zeros.contains(0));
+    }
+
+    #[test]
+    fn text_index_changes_lost_before_flush() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T", &[("id", IntegerType), ("name", TextType)],
+                Some("id"), &[vec![Integer(0), Text("ana".to_strbuf())],
+                              vec![Integer(1), Text("bia".to_strbuf())]]);
+        table.create_text_index("name_text", "name", Default::default()).unwrap();
+        table.update_entry(1, &[Integer(1), Text("carla".to_strbuf())]).unwrap();
+        unsafe { mem::forget(table); }
+
+        let mut table = Table::open(db.path(), "T").unwrap();
+        let old = fulltext::Word("bia".to_strbuf());
+        let new = fulltext::Word("carla".to_strbuf());
+        assert_eq!(table.text_search("name_text", &old).unwrap().count(), 0);
+        assert_eq!(table.text_search("name_text", &new).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn bloom_filter_inserts_lost_before_flush() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 20, 3);
+        table.create_bloom_filter("value", 0.01).unwrap();
+        table.update_entry(0, &[Integer(0), Integer(100)]).unwrap();
+        unsafe { mem::forget(table); }
+
+        let mut table = Table::open(db.path(), "T").unwrap();
+        assert!(table.bloom_may_contain(1, &Integer(100)));
+    }
+
+    // Matching rows and records read by `select_where`.
+    fn select_where_counts(table: &mut Table, condition: &expr::Expr) -> (uint, uint) {
+        let mut rows = table.select_where(condition).unwrap();
+        let count = rows.by_ref().count();
+        (count, rows.records_accessed())
+    }
+
+    #[test]
+    fn select_where_uses_prefix_index() {
+        let db = testing::scratch_db();
+        let records: Vec<Vec<Field>> = range(0, 30u32).map(|id| {
+            let prefix = if id % 3 == 0 { "ab" } else { "cd" };
+            vec![Integer(id), Text(format!("{}{}", prefix, id))]
+        }).collect();
+        let mut table = testing::create(db.path(), "T", &[("id", IntegerType), ("name", TextType)],
+                                        Some("id"), records.as_slice());
+        table.create_index("name_btree", "name", BTreeIndexType).unwrap();
+
+        let name = || box expr::FieldExpr("name".to_strbuf());
+        let like = expr::LikeExpr(name(), "ab%".to_strbuf());
+        assert_eq!(select_where_counts(&mut table, &like), (10, 10));
+
+        let id_below = expr::CompareExpr(expr::LessThan, box expr::FieldExpr("id".to_strbuf()),
+                                         box expr::LiteralExpr(Integer(15)));
+        let both = expr::AndExpr(box id_below, box like);
+        assert_eq!(select_where_counts(&mut table, &both), (5, 10));
+
+        let ilike = expr::ILikeExpr(name(), "AB%".to_strbuf());
+        assert_eq!(select_where_counts(&mut table, &ilike), (10, 30));
+        let suffix = expr::LikeExpr(name(), "%1".to_strbuf());
+        assert_eq!(select_where_counts(&mut table, &suffix), (3, 30));
+    }
 }
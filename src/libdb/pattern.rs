@@ -0,0 +1,290 @@
+use std::char;
+
+use super::btree::IndexScan;
+use super::expr::{AndExpr, ColumnExpr, Expr, LikeExpr};
+use super::{
+    Bound,
+    Excluded,
+    Field,
+    PhysicalTableIterator,
+    TableIterator,
+    TableSchema,
+    Text,
+    Unbounded,
+};
+
+#[deriving(Eq)]
+enum LikeToken {
+    AnyString,
+    AnyChar,
+    Literal(char),
+}
+
+// Splits a LIKE pattern: `%` stands for any sequence of characters and `_` for any single one.
+// A backslash makes the character after it literal.
+fn like_tokens(pattern: &str) -> Vec<LikeToken> {
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    loop {
+        tokens.push(match chars.next() {
+            None => return tokens,
+            Some('%') => AnyString,
+            Some('_') => AnyChar,
+            Some('\\') => match chars.next() {
+                Some(c) => Literal(c),
+                None => Literal('\\'),
+            },
+            Some(c) => Literal(c),
+        });
+    }
+}
+
+pub fn like_match(pattern: &str, text: &str, case_insensitive: bool) -> bool {
+    let tokens = like_tokens(pattern);
+    let text: Vec<char> = if case_insensitive {
+        text.chars().map(|c| c.to_lowercase()).collect()
+    } else {
+        text.chars().collect()
+    };
+
+    // Matches greedily, going back to the last `%` on a mismatch to let it take one more
+    // character.
+    let (mut p, mut t) = (0, 0);
+    let mut last_any_string = None;
+    while t < text.len() {
+        if p < tokens.len() {
+            match *tokens.get(p) {
+                AnyString => {
+                    last_any_string = Some((p, t));
+                    p += 1;
+                    continue;
+                },
+                AnyChar => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                },
+                Literal(c) => {
+                    let c = if case_insensitive { c.to_lowercase() } else { c };
+                    if c == *text.get(t) {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                },
+            }
+        }
+
+        match last_any_string {
+            Some((any_p, any_t)) => {
+                last_any_string = Some((any_p, any_t + 1));
+                p = any_p + 1;
+                t = any_t + 1;
+            },
+            None => return false,
+        }
+    }
+
+    tokens.slice_from(p).iter().all(|token| *token == AnyString)
+}
+
+// The literal text that every match of a LIKE pattern starts with.
+pub fn like_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    for token in like_tokens(pattern).iter() {
+        match *token {
+            Literal(c) => prefix.push_char(c),
+            _ => break,
+        }
+    }
+    prefix
+}
+
+// Smallest Text key above all those starting with `prefix`.
+pub fn prefix_end(prefix: &str) -> Bound {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    loop {
+        let last = match chars.pop() {
+            Some(c) => c, None => return Unbounded };
+        // The character after the last one below the surrogates is the first one above them.
+        let next = if last == '\ud7ff' { Some('\ue000') } else { char::from_u32(last as u32 + 1) };
+        match next {
+            Some(c) => {
+                chars.push(c);
+                return Excluded(Text(String::from_chars(chars.as_slice())));
+            },
+            None => (),
+        }
+    }
+}
+
+// A case-sensitive LIKE with a literal prefix that every record satisfying a bound condition
+// must match, as the field and pattern. Such a LIKE may be one side of an AND.
+pub fn required_like(condition: &Expr) -> Option<(uint, String)> {
+    match *condition {
+        LikeExpr(ref e, ref pattern) if !like_prefix(pattern.as_slice()).is_empty() => match **e {
+            ColumnExpr(field) => Some((field, pattern.clone())),
+            _ => None,
+        },
+        AndExpr(ref a, ref b) => required_like(&**a).or_else(|| required_like(&**b)),
+        _ => None,
+    }
+}
+
+enum PatternSource<'table> {
+    IndexSource(IndexScan<'table>),
+    TableSource(PhysicalTableIterator<'table>),
+}
+
+// Records whose Text field matches a LIKE or ILIKE pattern. Case-sensitive patterns with a
+// literal prefix read only the range of a B-tree index holding that prefix, if the field has
+// one; anything else is a full table scan. Without a pattern, all records are returned.
+pub struct PatternScan<'table> {
+    source: PatternSource<'table>,
+    field: uint,
+    pattern: Option<String>,
+    case_insensitive: bool,
+}
+
+impl<'table> PatternScan<'table> {
+    pub fn with_index(rows: IndexScan<'table>, field: uint, pattern: &str) -> PatternScan<'table> {
+        PatternScan {
+            source: IndexSource(rows),
+            field: field,
+            pattern: Some(pattern.to_strbuf()),
+            case_insensitive: false,
+        }
+    }
+
+    pub fn without_index(rows: PhysicalTableIterator<'table>, field: uint, pattern: &str,
+                         case_insensitive: bool) -> PatternScan<'table> {
+        PatternScan {
+            source: TableSource(rows),
+            field: field,
+            pattern: Some(pattern.to_strbuf()),
+            case_insensitive: case_insensitive,
+        }
+    }
+
+    pub fn all(rows: PhysicalTableIterator<'table>) -> PatternScan<'table> {
+        PatternScan {
+            source: TableSource(rows),
+            field: 0,
+            pattern: None,
+            case_insensitive: false,
+        }
+    }
+
+    pub fn uses_index(&self) -> bool {
+        match self.source {
+            IndexSource(_) => true,
+            TableSource(_) => false,
+        }
+    }
+
+    fn matches(&self, value: &Field) -> bool {
+        match (&self.pattern, value) {
+            (&None, _) => true,
+            (&Some(ref pattern), &Text(ref s)) =>
+                like_match(pattern.as_slice(), s.as_slice(), self.case_insensitive),
+            _ => false,
+        }
+    }
+}
+
+impl<'table> Iterator<Vec<Field>> for PatternScan<'table> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            let values = match self.source {
+                IndexSource(ref mut rows) => rows.next(),
+                TableSource(ref mut rows) => rows.next(),
+            };
+            match values {
+                None => return None,
+                Some(values) => if self.matches(values.get(self.field)) { return Some(values) },
+            }
+        }
+    }
+}
+
+impl<'table> TableIterator for PatternScan<'table> {
+    fn blocks_accessed(&self) -> uint {
+        match self.source {
+            IndexSource(ref rows) => rows.blocks_accessed(),
+            TableSource(ref rows) => rows.blocks_accessed(),
+        }
+    }
+
+    fn records_accessed(&self) -> uint {
+        match self.source {
+            IndexSource(ref rows) => rows.records_accessed(),
+            TableSource(ref rows) => rows.records_accessed(),
+        }
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        match self.source {
+            IndexSource(ref rows) => rows.schema(),
+            TableSource(ref rows) => rows.schema(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Excluded, Text, Unbounded};
+    use super::{like_match, like_prefix, prefix_end};
+
+    #[test]
+    fn like_wildcards() {
+        assert!(like_match("", "", false));
+        assert!(!like_match("", "a", false));
+        assert!(like_match("%", "", false));
+        assert!(like_match("%", "anything", false));
+        assert!(like_match("_", "é", false));
+        assert!(!like_match("_", "", false));
+        assert!(!like_match("a_", "a", false));
+        assert!(like_match("%a%b", "xaxab", false));
+        assert!(!like_match("%a%b", "xbxa", false));
+    }
+
+    #[test]
+    fn like_escapes() {
+        assert!(like_match("100\\%", "100%", false));
+        assert!(!like_match("100\\%", "1000", false));
+        assert!(like_match("a\\_", "a_", false));
+        assert!(!like_match("a\\_", "ab", false));
+        assert!(like_match("a\\", "a\\", false));
+    }
+
+    #[test]
+    fn ilike() {
+        assert!(like_match("ÁB%", "ábc", true));
+        assert!(!like_match("ÁB%", "ábc", false));
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(like_prefix("%ab").as_slice(), "");
+        assert_eq!(like_prefix("ab_c").as_slice(), "ab");
+        assert_eq!(like_prefix("a\\%b%").as_slice(), "a%b");
+        assert_eq!(like_prefix("abc").as_slice(), "abc");
+    }
+
+    fn end(prefix: &str) -> Option<String> {
+        match prefix_end(prefix) {
+            Excluded(Text(s)) => Some(s),
+            Unbounded => None,
+            bound => fail!("unexpected bound {}", bound),
+        }
+    }
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(end(""), None);
+        assert_eq!(end("ab"), Some("ac".to_strbuf()));
+        assert_eq!(end("a\U0010ffff"), Some("b".to_strbuf()));
+        assert_eq!(end("\U0010ffff\U0010ffff"), None);
+        assert_eq!(end("a\ud7ff"), Some("a\ue000".to_strbuf()));
+    }
+}
@@ -1,9 +1,37 @@
+use collections::{HashMap, HashSet};
+use std::cmp::{Equal, Greater, Less, Ordering, max, min};
+use std::hash::Hash;
+use std::hash::sip;
+use std::io;
+use std::mem;
+use std::u32;
+
+use super::bitmap::Bitmap;
+use super::expr::{BoundExpr, Expr, bind, bind_condition, truncate_text};
+use super::spill::SpillFile;
 use super::{
+    AmbiguousFieldError,
+    BLOCK_SIZE,
     Field,
+    FieldCountError,
+    FieldIndexError,
     FieldSchema,
+    Integer,
+    IntegerType,
+    IoError,
+    KeyLookupIterator,
+    MemoryBudgetError,
+    NoPrimaryKeyError,
+    Null,
+    OverflowError,
+    ProbeIterator,
     RewindableIterator,
+    TableError,
     TableIterator,
     TableSchema,
+    Text,
+    TypeError,
+    split_field_name,
 };
 
 pub struct Select<'closure, Iter> {
@@ -24,6 +52,17 @@ impl<'closure, Iter: TableIterator> Iterator<Vec<Field>> for Select<'closure, It
     }
 }
 
+// Each pass reads the whole base again, so the base's statistics grow by one full scan per
+// pass even if no record passes the condition.
+impl<
+    'closure,
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for Select<'closure, Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+    }
+}
+
 impl<'closure, Iter: TableIterator> TableIterator for Select<'closure, Iter> {
     fn blocks_accessed(&self) -> uint {
         self.base.blocks_accessed()
@@ -36,6 +75,211 @@ impl<'closure, Iter: TableIterator> TableIterator for Select<'closure, Iter> {
     fn schema<'s>(&'s self) -> &'s TableSchema {
         self.base.schema()
     }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+// Like `Select`, but with the condition given as an expression, which stays available for
+// inspection after it was checked against the input's schema. Every input record is tested;
+// `Table::select_where` narrows a table down by its indexes first.
+pub struct SelectExpr<Iter> {
+    base: Iter,
+    condition: BoundExpr,
+}
+
+pub fn select_expr<Iter: TableIterator>(base: Iter, condition: &Expr)
+        -> Result<SelectExpr<Iter>, TableError> {
+    let condition = try!(bind_condition(condition, base.schema()));
+    Ok(SelectExpr { base: base, condition: condition })
+}
+
+impl<Iter: TableIterator> SelectExpr<Iter> {
+    pub fn condition<'s>(&'s self) -> &'s Expr {
+        self.condition.expr()
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for SelectExpr<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            match self.base.next() {
+                None => return None,
+                Some(values) => if self.condition.test(&values) { return Some(values) },
+            }
+        }
+    }
+}
+
+impl<
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for SelectExpr<Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for SelectExpr<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+// Keeps only some of the fields of each record, in the given order.
+pub struct Project<Iter> {
+    base: Iter,
+    fields: Vec<uint>,
+    schema: TableSchema,
+}
+
+pub fn project<Iter: TableIterator>(base: Iter, fields: Vec<uint>)
+        -> Result<Project<Iter>, TableError> {
+    let schema = {
+        let base_schema = base.schema();
+        let mut offset = 0;
+        let mut projected = Vec::with_capacity(fields.len());
+        for &i in fields.iter() {
+            if i >= base_schema.fields.len() {
+                return Err(FieldIndexError(i, base_schema.fields.len()));
+            }
+            let f = base_schema.fields.get(i);
+            projected.push(FieldSchema { name: f.name.clone(), offset: offset, ..*f });
+            offset += f.length;
+        }
+
+        TableSchema {
+            name: base_schema.name.clone(),
+            fields: projected,
+            entry_stride: offset,
+            primary_key: None,
+        }
+    };
+
+    Ok(Project {
+        base: base,
+        fields: fields,
+        schema: schema,
+    })
+}
+
+pub fn project_names<Iter: TableIterator>(base: Iter, names: &[&str])
+        -> Result<Project<Iter>, TableError> {
+    let mut fields = Vec::with_capacity(names.len());
+    for name in names.iter() {
+        fields.push(try!(base.schema().find_field(*name)));
+    }
+    project(base, fields)
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Project<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        self.base.next().map(|values| {
+            self.fields.iter().map(|&i| values.get(i).clone()).collect()
+        })
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for Project<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+// Projection onto computed fields, such as `upper(nome)` or `id * 10`, each given as a name and
+// an expression over the input's fields. Types and lengths of the output fields are inferred,
+// and type errors are reported before any record is read. Text values longer than their field
+// are truncated.
+pub struct ComputedProject<Iter> {
+    base: Iter,
+    exprs: Vec<BoundExpr>,
+    schema: TableSchema,
+}
+
+pub fn project_exprs<Iter: TableIterator>(base: Iter, exprs: &[(&str, Expr)])
+        -> Result<ComputedProject<Iter>, TableError> {
+    let mut bound = Vec::with_capacity(exprs.len());
+    let mut fields = Vec::with_capacity(exprs.len());
+    let mut offset = 0;
+    for &(name, ref expr) in exprs.iter() {
+        let expr = try!(bind(expr, base.schema()));
+        let field = try!(expr.field_schema(name, offset, base.schema()));
+        offset += field.length;
+        fields.push(field);
+        bound.push(expr);
+    }
+
+    let schema = TableSchema {
+        name: base.schema().name.clone(),
+        fields: fields,
+        entry_stride: offset,
+        primary_key: None,
+    };
+    Ok(ComputedProject {
+        base: base,
+        exprs: bound,
+        schema: schema,
+    })
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for ComputedProject<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        self.base.next().map(|values| {
+            self.exprs.iter().zip(self.schema.fields.iter())
+                .map(|(expr, field)| truncate_text(expr.eval(&values), field.length))
+                .collect()
+        })
+    }
+}
+
+impl<
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for ComputedProject<Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for ComputedProject<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
 }
 
 pub struct CrossJoin<IterA, IterB> {
@@ -46,20 +290,258 @@ pub struct CrossJoin<IterA, IterB> {
     schema: TableSchema,
 }
 
+// Gives its input another table name, and optionally new field names, like `Clientes AS c` in
+// SQL. Joins qualify the fields with the new name, which is how a table is joined with itself.
+//
+// Fields that were already qualified, such as those coming out of a join, lose their old
+// qualifier, so they must have distinct names without it or be renamed.
+pub struct Alias<Iter> {
+    base: Iter,
+    schema: TableSchema,
+}
+
+pub fn alias<Iter: TableIterator>(base: Iter, name: &str) -> Result<Alias<Iter>, TableError> {
+    let schema = try!(alias_schema(base.schema(), name, None));
+    Ok(Alias { base: base, schema: schema })
+}
+
+pub fn alias_fields<Iter: TableIterator>(base: Iter, name: &str, field_names: &[&str])
+        -> Result<Alias<Iter>, TableError> {
+    let schema = try!(alias_schema(base.schema(), name, Some(field_names)));
+    Ok(Alias { base: base, schema: schema })
+}
+
+fn alias_schema(base: &TableSchema, name: &str, field_names: Option<&[&str]>)
+        -> Result<TableSchema, TableError> {
+    match field_names {
+        Some(names) if names.len() != base.fields.len() =>
+            return Err(FieldCountError(names.len(), base.fields.len())),
+        _ => (),
+    }
+
+    let mut fields: Vec<FieldSchema> = Vec::with_capacity(base.fields.len());
+    for (i, f) in base.fields.iter().enumerate() {
+        let field_name = match field_names {
+            Some(names) => names[i].to_strbuf(),
+            None => {
+                let (_, unqualified) = split_field_name(f.name.as_slice());
+                unqualified.to_strbuf()
+            },
+        };
+        if fields.iter().any(|g| g.name == field_name) {
+            return Err(AmbiguousFieldError(field_name));
+        }
+        fields.push(FieldSchema { name: field_name, ..*f });
+    }
+
+    let primary_key = match base.primary_key {
+        Some(ref key) => Some(fields.get(try!(base.find_field(key.as_slice()))).name.clone()),
+        None => None,
+    };
+    Ok(TableSchema {
+        name: name.to_strbuf(),
+        fields: fields,
+        entry_stride: base.entry_stride,
+        primary_key: primary_key,
+    })
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Alias<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        self.base.next()
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for Alias<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+impl<
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for Alias<Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+    }
+}
+
+impl<
+    Iter: TableIterator + RandomAccessIterator<Vec<Field>>
+> RandomAccessIterator<Vec<Field>> for Alias<Iter> {
+    fn indexable(&self) -> uint {
+        self.base.indexable()
+    }
+
+    fn idx(&mut self, i: uint) -> Option<Vec<Field>> {
+        self.base.idx(i)
+    }
+}
+
+impl<Iter: KeyLookupIterator> KeyLookupIterator for Alias<Iter> {
+    fn lookup_key(&mut self, key: &Field) -> Result<Option<Vec<Field>>, TableError> {
+        self.base.lookup_key(key)
+    }
+}
+
+impl<Iter: ProbeIterator> ProbeIterator for Alias<Iter> {
+    fn probe(&mut self, key: &Field) {
+        self.base.probe(key)
+    }
+
+    fn index_blocks_accessed(&self) -> uint {
+        self.base.index_blocks_accessed()
+    }
+}
+
+// Name of field `f` of table `table` in a join: qualified by the table name unless it already
+// is. A name taken by an earlier field, as in self-joins, gets a numbered qualifier instead
+// (`Clientes_2.id`).
+fn qualified_name(fields: &[FieldSchema], table: &TableSchema, f: &FieldSchema) -> String {
+    let (qualifier, name) = match split_field_name(f.name.as_slice()) {
+        (Some(qualifier), name) => (qualifier, name),
+        (None, name) => (table.name.as_slice(), name),
+    };
+    let mut qualified = format!("{}.{}", qualifier, name);
+    let mut n = 2u;
+    while fields.iter().any(|g| g.name == qualified) {
+        qualified = format!("{}_{}.{}", qualifier, n, name);
+        n += 1;
+    }
+    qualified
+}
+
 fn concat_schemas(table_name: &str, sa: &TableSchema, sb: &TableSchema) -> TableSchema {
     let mut fields = Vec::with_capacity(sa.fields.len() + sb.fields.len());
-    fields.extend(sa.fields.iter().map(|f| FieldSchema {
-        name: format!("{}.{}", sa.name, f.name), ..*f
-    }));
-    fields.extend(sb.fields.iter().map(|f| FieldSchema {
-        name: format!("{}.{}", sb.name, f.name),
-        offset: f.offset + sa.entry_stride, ..*f
-    }));
+    for f in sa.fields.iter() {
+        let name = qualified_name(fields.as_slice(), sa, f);
+        fields.push(FieldSchema { name: name, ..*f });
+    }
+    for f in sb.fields.iter() {
+        let name = qualified_name(fields.as_slice(), sb, f);
+        fields.push(FieldSchema { name: name, offset: f.offset + sa.entry_stride, ..*f });
+    }
 
     TableSchema {
         name: table_name.to_owned(),
         fields: fields,
         entry_stride: sa.entry_stride + sb.entry_stride,
+        primary_key: None,
+    }
+}
+
+// Error of an operator with two inputs: the first input's, if both stopped early.
+fn take_either_error<IterA: TableIterator, IterB: TableIterator>(iter_a: &mut IterA,
+                                                                 iter_b: &mut IterB)
+        -> Option<TableError> {
+    match iter_a.take_error() {
+        None => iter_b.take_error(),
+        error => error,
+    }
+}
+
+// Which records a join returns. Outer joins also return records of one or both inputs that
+// matched nothing, padded with NULLs. Semi and anti joins only return records of the first
+// input: those with at least one match, or with none.
+#[deriving(Clone, Eq, Show)]
+pub enum JoinKind {
+    InnerJoin,
+    LeftOuterJoin,
+    RightOuterJoin,
+    FullOuterJoin,
+    SemiJoin,
+    AntiJoin,
+}
+
+fn keeps_unmatched_b(kind: JoinKind) -> bool {
+    kind == RightOuterJoin || kind == FullOuterJoin
+}
+
+fn join_schema(table_name: &str, kind: JoinKind, sa: &TableSchema, sb: &TableSchema)
+        -> TableSchema {
+    match kind {
+        SemiJoin | AntiJoin => TableSchema {
+            name: sa.name.clone(),
+            fields: sa.fields.iter().map(|f| FieldSchema { name: f.name.clone(), ..*f }).collect(),
+            entry_stride: sa.entry_stride,
+            primary_key: None,
+        },
+        _ => concat_schemas(table_name, sa, sb),
+    }
+}
+
+// Missing and NULL keys never match anything.
+fn join_key(key: Option<Field>) -> Option<Field> {
+    match key {
+        Some(Null) => None,
+        key => key,
+    }
+}
+
+// Output for a pair of matching records, given whether it's the first match of `a`.
+fn matched_row(kind: JoinKind, a: &Vec<Field>, b: &Vec<Field>, first_match_of_a: bool)
+        -> Option<Vec<Field>> {
+    match kind {
+        SemiJoin => if first_match_of_a { Some(a.clone()) } else { None },
+        AntiJoin => None,
+        _ => Some(*a + *b),
+    }
+}
+
+// Output for a record of the first or second input that matched nothing.
+fn unmatched_row(kind: JoinKind, from_a: bool, values: Vec<Field>, a_width: uint,
+                 b_width: uint) -> Option<Vec<Field>> {
+    match (from_a, kind) {
+        (true, LeftOuterJoin) | (true, FullOuterJoin) =>
+            Some(values + Vec::from_elem(b_width, Null)),
+        (true, AntiJoin) => Some(values),
+        (false, RightOuterJoin) | (false, FullOuterJoin) =>
+            Some(Vec::from_elem(a_width, Null) + values),
+        _ => None,
+    }
+}
+
+// How a join gets the key of a record: with a closure, or with an expression bound to the
+// schema of the record's input. Keys evaluating to NULL match nothing.
+pub enum JoinKey<'closure> {
+    KeyClosure(|&Vec<Field>|:'closure -> Option<Field>),
+    KeyExpr(BoundExpr),
+}
+
+impl<'closure> JoinKey<'closure> {
+    fn key(&mut self, values: &Vec<Field>) -> Option<Field> {
+        join_key(match *self {
+            KeyClosure(ref mut closure) => (*closure)(values),
+            KeyExpr(ref expr) => Some(expr.eval(values)),
+        })
+    }
+}
+
+// Condition on a pair of records of a join. Expressions are bound to the concatenation of the
+// inputs' schemas.
+pub enum JoinCondition<'closure> {
+    ConditionClosure(|&Vec<Field>, &Vec<Field>|:'closure -> bool),
+    ConditionExpr(BoundExpr),
+}
+
+impl<'closure> JoinCondition<'closure> {
+    fn test(&mut self, a: &Vec<Field>, b: &Vec<Field>) -> bool {
+        match *self {
+            ConditionClosure(ref mut closure) => (*closure)(a, b),
+            ConditionExpr(ref expr) => expr.test_pair(a, b),
+        }
     }
 }
 
@@ -95,6 +577,22 @@ impl<
     fn schema<'s>(&'s self) -> &'s TableSchema {
         &self.schema
     }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+// A pass reads the first input once and the second input once per record of the first.
+impl<
+    IterA: TableIterator + RewindableIterator<Vec<Field>>,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for CrossJoin<IterA, IterB> {
+    fn rewind(&mut self) {
+        self.iter_a.rewind();
+        self.iter_b.rewind();
+        self.current_a = self.iter_a.next();
+    }
 }
 
 impl<
@@ -118,28 +616,52 @@ impl<
     }
 }
 
+fn check_primary_key(schema: &TableSchema) -> Result<(), TableError> {
+    match schema.primary_key {
+        Some(_) => Ok(()),
+        None => Err(NoPrimaryKeyError(schema.name.clone())),
+    }
+}
+
 pub struct SelectPrimaryKey<Iter> {
-    pub base: Iter,
-    pub key: Option<u32>,
+    base: Iter,
+    key: Option<Field>,
+    done: bool,
 }
 
-impl<
-    Iter: TableIterator + RandomAccessIterator<Vec<Field>>
-> Iterator<Vec<Field>> for SelectPrimaryKey<Iter> {
+// Fails if the base's table has no primary key.
+pub fn select_primary_key<Iter: KeyLookupIterator>(base: Iter, key: Option<Field>)
+        -> Result<SelectPrimaryKey<Iter>, TableError> {
+    try!(check_primary_key(base.schema()));
+    Ok(SelectPrimaryKey {
+        base: base,
+        key: key,
+        done: false,
+    })
+}
+
+impl<Iter: KeyLookupIterator> Iterator<Vec<Field>> for SelectPrimaryKey<Iter> {
     fn next(&mut self) -> Option<Vec<Field>> {
+        if self.done {
+            return None;
+        }
+        self.done = true;
         match self.key {
-            Some(k) => {
-                self.key = None;
-                self.base.idx(k as uint)
-            },
+            Some(ref k) => self.base.lookup_key(k).unwrap(),
             None => None,
         }
     }
 }
 
-impl<
-    Iter: TableIterator + RandomAccessIterator<Vec<Field>>
-> TableIterator for SelectPrimaryKey<Iter> {
+// The base doesn't need to rewind, since every pass is a single lookup. Each pass repeats it,
+// index blocks included.
+impl<Iter: KeyLookupIterator> RewindableIterator<Vec<Field>> for SelectPrimaryKey<Iter> {
+    fn rewind(&mut self) {
+        self.done = false;
+    }
+}
+
+impl<Iter: KeyLookupIterator> TableIterator for SelectPrimaryKey<Iter> {
     fn blocks_accessed(&self) -> uint {
         self.base.blocks_accessed()
     }
@@ -151,45 +673,29 @@ impl<
     fn schema<'s>(&'s self) -> &'s TableSchema {
         self.base.schema()
     }
-}
 
-pub struct PrimaryKeyJoin<'closure, IterA, IterB> {
-    iter_a: IterA,
-    iter_b: IterB,
-    key_closure: |&Vec<Field>|:'closure -> Option<u32>,
-    schema: TableSchema,
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
 }
 
-pub fn pk_join<
-    'closure,
-    IterA: TableIterator,
-    IterB: TableIterator + RandomAccessIterator<Vec<Field>>
->(iter_a: IterA, iter_b: IterB, key_closure: |&Vec<Field>|:'closure -> Option<u32>)
-        -> PrimaryKeyJoin<'closure, IterA, IterB> {
-    let schema = concat_schemas("pk-join", iter_a.schema(), iter_b.schema());
-    PrimaryKeyJoin {
-        iter_a: iter_a,
-        iter_b: iter_b,
-        key_closure: key_closure,
-        schema: schema,
-    }
+// Fetches the records at the given positions, such as those produced by a bitmap query.
+pub struct SelectPositions<Iter, Positions> {
+    pub base: Iter,
+    pub positions: Positions,
 }
 
 impl<
-    'closure,
-    IterA: TableIterator,
-    IterB: TableIterator + RandomAccessIterator<Vec<Field>>
-> Iterator<Vec<Field>> for PrimaryKeyJoin<'closure, IterA, IterB> {
+    Iter: TableIterator + RandomAccessIterator<Vec<Field>>,
+    Positions: Iterator<uint>
+> Iterator<Vec<Field>> for SelectPositions<Iter, Positions> {
     fn next(&mut self) -> Option<Vec<Field>> {
         loop {
-            match self.iter_a.next() {
+            match self.positions.next() {
                 None => return None,
-                Some(val) => match (self.key_closure)(&val) {
+                Some(pos) => match self.base.idx(pos) {
                     None => continue,
-                    Some(k) => match self.iter_b.idx(k as uint) {
-                        None => continue,
-                        Some(b) => return Some(val + b),
-                    },
+                    Some(values) => return Some(values),
                 },
             }
         }
@@ -197,19 +703,3554 @@ impl<
 }
 
 impl<
-    'closure,
-    IterA: TableIterator,
-    IterB: TableIterator + RandomAccessIterator<Vec<Field>>
-> TableIterator for PrimaryKeyJoin<'closure, IterA, IterB> {
+    Iter: TableIterator + RandomAccessIterator<Vec<Field>>,
+    Positions: Iterator<uint>
+> TableIterator for SelectPositions<Iter, Positions> {
     fn blocks_accessed(&self) -> uint {
-        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+        self.base.blocks_accessed()
     }
 
     fn records_accessed(&self) -> uint {
-        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+        self.base.records_accessed()
     }
 
     fn schema<'s>(&'s self) -> &'s TableSchema {
-        &self.schema
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+pub struct PrimaryKeyJoin<'closure, IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    key: JoinKey<'closure>,
+    kind: JoinKind,
+    schema: TableSchema,
+    b_key_field: uint,
+
+    a_done: bool,
+    // Primary keys of the matched records of `iter_b`, kept for right and full outer joins.
+    matched_b: HashSet<Field>,
+}
+
+pub fn pk_join<
+    'closure,
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+>(iter_a: IterA, iter_b: IterB, key_closure: |&Vec<Field>|:'closure -> Option<Field>)
+        -> Result<PrimaryKeyJoin<'closure, IterA, IterB>, TableError> {
+    pk_join_kind(iter_a, iter_b, InnerJoin, key_closure)
+}
+
+pub fn pk_join_kind<
+    'closure,
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind,
+  key_closure: |&Vec<Field>|:'closure -> Option<Field>)
+        -> Result<PrimaryKeyJoin<'closure, IterA, IterB>, TableError> {
+    pk_join_key(iter_a, iter_b, kind, KeyClosure(key_closure))
+}
+
+// Primary key join with the key of the first input's records given by an expression.
+pub fn pk_join_expr<
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, key: &Expr)
+        -> Result<PrimaryKeyJoin<'static, IterA, IterB>, TableError> {
+    let key = try!(bind(key, iter_a.schema()));
+    pk_join_key(iter_a, iter_b, kind, KeyExpr(key))
+}
+
+// Fails if the second input's table has no primary key.
+fn pk_join_key<
+    'closure,
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, key: JoinKey<'closure>)
+        -> Result<PrimaryKeyJoin<'closure, IterA, IterB>, TableError> {
+    try!(check_primary_key(iter_b.schema()));
+    let b_key_field = {
+        let schema = iter_b.schema();
+        try!(schema.find_field(schema.primary_key.get_ref().as_slice()))
+    };
+
+    let schema = join_schema("pk-join", kind, iter_a.schema(), iter_b.schema());
+    Ok(PrimaryKeyJoin {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        key: key,
+        kind: kind,
+        schema: schema,
+        b_key_field: b_key_field,
+
+        a_done: false,
+        matched_b: HashSet::new(),
+    })
+}
+
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+> Iterator<Vec<Field>> for PrimaryKeyJoin<'closure, IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        let a_width = self.iter_a.schema().fields.len();
+        let b_width = self.iter_b.schema().fields.len();
+
+        while !self.a_done {
+            let a = match self.iter_a.next() {
+                Some(a) => a,
+                None => {
+                    self.a_done = true;
+                    break;
+                },
+            };
+
+            let b = match self.key.key(&a) {
+                None => None,
+                Some(k) => self.iter_b.lookup_key(&k).unwrap(),
+            };
+            let row = match b {
+                Some(b) => {
+                    if keeps_unmatched_b(self.kind) {
+                        self.matched_b.insert(b.get(self.b_key_field).clone());
+                    }
+                    matched_row(self.kind, &a, &b, true)
+                },
+                None => unmatched_row(self.kind, true, a, a_width, b_width),
+            };
+            match row {
+                Some(row) => return Some(row),
+                None => (),
+            }
+        }
+
+        // Once the first input is done, outer joins go through the second one for records
+        // that were never looked up.
+        if !keeps_unmatched_b(self.kind) {
+            return None;
+        }
+        loop {
+            match self.iter_b.next() {
+                None => return None,
+                Some(b) => {
+                    if !self.matched_b.contains(b.get(self.b_key_field)) {
+                        return unmatched_row(self.kind, false, b, a_width, b_width);
+                    }
+                },
+            }
+        }
+    }
+}
+
+// A pass reads the first input once and looks up each of its keys again. Right and full outer
+// joins also scan the second input once more at the end of every pass.
+impl<
+    'closure,
+    IterA: TableIterator + RewindableIterator<Vec<Field>>,
+    IterB: KeyLookupIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for PrimaryKeyJoin<'closure, IterA, IterB> {
+    fn rewind(&mut self) {
+        self.iter_a.rewind();
+        self.iter_b.rewind();
+        self.a_done = false;
+        self.matched_b.clear();
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: KeyLookupIterator
+> TableIterator for PrimaryKeyJoin<'closure, IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+pub struct IndexJoin<'closure, IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    key: JoinKey<'closure>,
+
+    current_a: Option<Vec<Field>>,
+    schema: TableSchema,
+}
+
+pub fn index_join<
+    'closure,
+    IterA: TableIterator,
+    IterB: ProbeIterator
+>(iter_a: IterA, iter_b: IterB, key_closure: |&Vec<Field>|:'closure -> Option<Field>)
+        -> IndexJoin<'closure, IterA, IterB> {
+    index_join_key(iter_a, iter_b, KeyClosure(key_closure))
+}
+
+pub fn index_join_expr<
+    IterA: TableIterator,
+    IterB: ProbeIterator
+>(iter_a: IterA, iter_b: IterB, key: &Expr)
+        -> Result<IndexJoin<'static, IterA, IterB>, TableError> {
+    let key = try!(bind(key, iter_a.schema()));
+    Ok(index_join_key(iter_a, iter_b, KeyExpr(key)))
+}
+
+fn index_join_key<
+    'closure,
+    IterA: TableIterator,
+    IterB: ProbeIterator
+>(iter_a: IterA, iter_b: IterB, key: JoinKey<'closure>) -> IndexJoin<'closure, IterA, IterB> {
+    let schema = concat_schemas("index-join", iter_a.schema(), iter_b.schema());
+    IndexJoin {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        key: key,
+
+        current_a: None,
+        schema: schema,
+    }
+}
+
+impl<'closure, IterA: TableIterator, IterB: ProbeIterator> IndexJoin<'closure, IterA, IterB> {
+    pub fn index_blocks_accessed(&self) -> uint {
+        self.iter_b.index_blocks_accessed()
+    }
+
+    pub fn data_blocks_accessed(&self) -> uint {
+        self.blocks_accessed() - self.index_blocks_accessed()
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: ProbeIterator
+> Iterator<Vec<Field>> for IndexJoin<'closure, IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            match self.current_a {
+                Some(ref a) => match self.iter_b.next() {
+                    Some(b) => return Some(a + b),
+                    None => (),
+                },
+                None => (),
+            }
+
+            match self.iter_a.next() {
+                None => {
+                    self.current_a = None;
+                    return None;
+                },
+                Some(a) => {
+                    let key = self.key.key(&a);
+                    match key {
+                        None => self.current_a = None,
+                        Some(k) => {
+                            self.iter_b.probe(&k);
+                            self.current_a = Some(a);
+                        },
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: ProbeIterator
+> TableIterator for IndexJoin<'closure, IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+#[deriving(Clone, Eq, Show)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
+fn compare_records(keys: &[(uint, SortOrder)], a: &Vec<Field>, b: &Vec<Field>) -> Ordering {
+    for &(field, order) in keys.iter() {
+        let ordering = match (a.get(field).cmp(b.get(field)), order) {
+            (Less, Descending) => Greater,
+            (Greater, Descending) => Less,
+            (ordering, _) => ordering,
+        };
+        if ordering != Equal {
+            return ordering;
+        }
+    }
+    Equal
+}
+
+// Index of the smallest record among the heads of some sorted runs. Ties go to the earliest run,
+// which keeps merges stable.
+fn min_head(keys: &[(uint, SortOrder)], heads: &Vec<Option<Vec<Field>>>) -> Option<uint> {
+    let mut smallest = None;
+    for (i, head) in heads.iter().enumerate() {
+        match *head {
+            None => (),
+            Some(ref values) => {
+                let smaller = match smallest {
+                    None => true,
+                    Some(j) => compare_records(keys, values, heads.get(j).get_ref()) == Less,
+                };
+                if smaller {
+                    smallest = Some(i);
+                }
+            },
+        }
+    }
+    smallest
+}
+
+// Sorts records by one or more fields, using an external merge sort when they don't fit in
+// `memory_blocks` blocks. Sorted runs are spilled to the database's temporary directory and
+// merged, in several passes if there are more runs than can be merged at once.
+pub struct Sort<Iter> {
+    base: Iter,
+    keys: Vec<(uint, SortOrder)>,
+    memory_blocks: uint,
+    db_path: Path,
+
+    started: bool,
+    // Used instead of runs when all records fit in memory. Reversed so it can be popped.
+    sorted: Vec<Vec<Field>>,
+    runs: Vec<SpillFile>,
+    heads: Vec<Option<Vec<Field>>>,
+    // Spill I/O of runs that were already merged into others.
+    merged_spill_blocks: uint,
+    error: Option<TableError>,
+
+    pub runs_created: uint,
+    pub merge_passes: uint,
+}
+
+// Fails if the memory budget is under 3 blocks. Spill file errors end iteration early and are
+// reported by `take_error`.
+pub fn sort<Iter: TableIterator>(base: Iter, keys: Vec<(uint, SortOrder)>, memory_blocks: uint,
+                                 db_path: &Path) -> Result<Sort<Iter>, TableError> {
+    if memory_blocks < 3 {
+        return Err(MemoryBudgetError("Sort".to_strbuf(), 3));
+    }
+
+    Ok(Sort {
+        base: base,
+        keys: keys,
+        memory_blocks: memory_blocks,
+        db_path: db_path.clone(),
+
+        started: false,
+        sorted: Vec::new(),
+        runs: Vec::new(),
+        heads: Vec::new(),
+        merged_spill_blocks: 0,
+        error: None,
+
+        runs_created: 0,
+        merge_passes: 0,
+    })
+}
+
+impl<Iter: TableIterator> Sort<Iter> {
+    // Blocks written to and read from spill files.
+    pub fn spill_blocks(&self) -> uint {
+        self.runs.iter().fold(self.merged_spill_blocks,
+                              |total, run| total + run.blocks_written + run.blocks_read)
+    }
+
+    fn write_run(&mut self, buffer: &mut Vec<Vec<Field>>) -> io::IoResult<()> {
+        let keys = self.keys.as_slice();
+        buffer.as_mut_slice().sort_by(|a, b| compare_records(keys, a, b));
+
+        let mut run = try!(SpillFile::create(&self.db_path));
+        for values in buffer.iter() {
+            try!(run.write(values.as_slice()));
+        }
+        buffer.clear();
+
+        self.runs.push(run);
+        self.runs_created += 1;
+        Ok(())
+    }
+
+    fn merge(&mut self, mut runs: Vec<SpillFile>) -> io::IoResult<SpillFile> {
+        let mut output = try!(SpillFile::create(&self.db_path));
+        let mut heads = Vec::with_capacity(runs.len());
+        for run in runs.mut_iter() {
+            heads.push(try!(run.read()));
+        }
+
+        loop {
+            let i = match min_head(self.keys.as_slice(), &heads) {
+                Some(i) => i, None => break };
+            let next = try!(runs.get_mut(i).read());
+            let values = mem::replace(heads.get_mut(i), next);
+            try!(output.write(values.unwrap().as_slice()));
+        }
+
+        for run in runs.iter() {
+            self.merged_spill_blocks += run.blocks_written + run.blocks_read;
+        }
+        self.runs_created += 1;
+        Ok(output)
+    }
+
+    fn start(&mut self) -> io::IoResult<()> {
+        let memory_records = self.memory_blocks * BLOCK_SIZE;
+        let mut buffer = Vec::new();
+        loop {
+            match self.base.next() {
+                None => break,
+                Some(values) => {
+                    buffer.push(values);
+                    if buffer.len() == memory_records {
+                        try!(self.write_run(&mut buffer));
+                    }
+                },
+            }
+        }
+
+        if self.runs.is_empty() {
+            let keys = self.keys.as_slice();
+            buffer.as_mut_slice().sort_by(|a, b| compare_records(keys, a, b));
+            buffer.reverse();
+            self.sorted = buffer;
+            return Ok(());
+        }
+        if !buffer.is_empty() {
+            try!(self.write_run(&mut buffer));
+        }
+
+        // Each run being merged needs a block of memory, plus one for the output.
+        let fan_in = max(self.memory_blocks - 1, 2);
+        while self.runs.len() > fan_in {
+            // Consecutive runs are merged together so that equal records keep their order.
+            let mut remaining = mem::replace(&mut self.runs, Vec::new());
+            let mut merged = Vec::new();
+            while !remaining.is_empty() {
+                let count = min(fan_in, remaining.len());
+                let group: Vec<SpillFile> =
+                    range(0, count).map(|_| remaining.remove(0).unwrap()).collect();
+                merged.push(try!(self.merge(group)));
+            }
+            self.runs = merged;
+            self.merge_passes += 1;
+        }
+
+        // The last pass is done on the fly by `next`.
+        self.merge_passes += 1;
+        for run in self.runs.mut_iter() {
+            self.heads.push(try!(run.read()));
+        }
+        Ok(())
+    }
+
+    // Ends iteration early, keeping the error for `take_error`.
+    fn stop(&mut self, e: io::IoError) {
+        self.merged_spill_blocks = self.spill_blocks();
+        self.runs.clear();
+        self.heads.clear();
+        self.sorted.clear();
+        self.error = Some(IoError(e));
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Sort<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            match self.start() {
+                Ok(()) => {},
+                Err(e) => self.stop(e),
+            }
+        }
+
+        if self.runs.is_empty() {
+            return self.sorted.pop();
+        }
+
+        let i = match min_head(self.keys.as_slice(), &self.heads) {
+            Some(i) => i, None => return None };
+        match self.runs.get_mut(i).read() {
+            Ok(next) => mem::replace(self.heads.get_mut(i), next),
+            Err(e) => {
+                self.stop(e);
+                None
+            },
+        }
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for Sort<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed() + self.spill_blocks()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        match self.error.take() {
+            None => self.base.take_error(),
+            error => error,
+        }
+    }
+}
+
+// Partitions whose build side still doesn't fit in memory are split again, up to this many
+// times. Beyond that they are probably one huge key, which more splitting won't help.
+static MAX_PARTITION_DEPTH : uint = 3;
+
+fn partition_of<K: Hash>(key: &K, level: uint, num_partitions: uint) -> uint {
+    (sip::hash_with_keys(level as u64, 0, key) % num_partitions as u64) as uint
+}
+
+// Equi-join that builds a hash table on the smaller input and probes it with the larger one.
+//
+// Both inputs are read alternately until one of them ends; if that happens within the memory
+// budget, the finished input is the build side. Otherwise both are partitioned to spill files
+// by key, and each pair of partitions is joined on its own (Grace hash join).
+//
+// For outer joins, unmatched build records are emitted once their batch has been probed, and
+// records with a NULL or missing key are emitted as unmatched as soon as they are read.
+pub struct HashJoin<'closure, IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    key_a: JoinKey<'closure>,
+    key_b: JoinKey<'closure>,
+    kind: JoinKind,
+    memory_blocks: uint,
+    db_path: Path,
+    schema: TableSchema,
+    a_width: uint,
+    b_width: uint,
+
+    started: bool,
+    // Whether the probe side is no longer read from the inputs.
+    partitioned: bool,
+    build_a: bool,
+    // Build records by key, with whether each has matched a probe record yet.
+    table: HashMap<Field, Vec<(Vec<Field>, bool)>>,
+    // Probe records read while looking for the smaller input. Reversed so it can be popped.
+    probe_buffer: Vec<Vec<Field>>,
+    probe_spill: Option<SpillFile>,
+    // Pairs of partitions still to be joined, with the number of times they were split.
+    partitions: Vec<(SpillFile, SpillFile, uint)>,
+    // Rows for unmatched records, waiting to be returned.
+    output: Vec<Vec<Field>>,
+
+    current_probe: Option<Vec<Field>>,
+    current_key: Option<Field>,
+    probe_matched: bool,
+    match_index: uint,
+
+    // Spill I/O of partitions that were already joined or split.
+    retired_spill_blocks: uint,
+    error: Option<TableError>,
+    pub partitions_created: uint,
+}
+
+// These fail if the memory budget is under 3 blocks. Spill file errors end iteration early and
+// are reported by `take_error`.
+
+pub fn hash_join<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB,
+  key_a: |&Vec<Field>|:'closure -> Option<Field>,
+  key_b: |&Vec<Field>|:'closure -> Option<Field>,
+  memory_blocks: uint, db_path: &Path) -> Result<HashJoin<'closure, IterA, IterB>, TableError> {
+    hash_join_kind(iter_a, iter_b, InnerJoin, key_a, key_b, memory_blocks, db_path)
+}
+
+pub fn hash_join_kind<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind,
+  key_a: |&Vec<Field>|:'closure -> Option<Field>,
+  key_b: |&Vec<Field>|:'closure -> Option<Field>,
+  memory_blocks: uint, db_path: &Path) -> Result<HashJoin<'closure, IterA, IterB>, TableError> {
+    hash_join_key(iter_a, iter_b, kind, KeyClosure(key_a), KeyClosure(key_b), memory_blocks,
+                  db_path)
+}
+
+pub fn hash_join_expr<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, key_a: &Expr, key_b: &Expr,
+  memory_blocks: uint, db_path: &Path) -> Result<HashJoin<'static, IterA, IterB>, TableError> {
+    let key_a = try!(bind(key_a, iter_a.schema()));
+    let key_b = try!(bind(key_b, iter_b.schema()));
+    hash_join_key(iter_a, iter_b, kind, KeyExpr(key_a), KeyExpr(key_b), memory_blocks, db_path)
+}
+
+fn hash_join_key<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, key_a: JoinKey<'closure>,
+  key_b: JoinKey<'closure>, memory_blocks: uint, db_path: &Path)
+        -> Result<HashJoin<'closure, IterA, IterB>, TableError> {
+    if memory_blocks < 3 {
+        return Err(MemoryBudgetError("Hash join".to_strbuf(), 3));
+    }
+
+    let schema = join_schema("hash-join", kind, iter_a.schema(), iter_b.schema());
+    let a_width = iter_a.schema().fields.len();
+    let b_width = iter_b.schema().fields.len();
+    Ok(HashJoin {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        key_a: key_a,
+        key_b: key_b,
+        kind: kind,
+        memory_blocks: memory_blocks,
+        db_path: db_path.clone(),
+        schema: schema,
+        a_width: a_width,
+        b_width: b_width,
+
+        started: false,
+        partitioned: false,
+        build_a: false,
+        table: HashMap::new(),
+        probe_buffer: Vec::new(),
+        probe_spill: None,
+        partitions: Vec::new(),
+        output: Vec::new(),
+
+        current_probe: None,
+        current_key: None,
+        probe_matched: false,
+        match_index: 0,
+
+        retired_spill_blocks: 0,
+        error: None,
+        partitions_created: 0,
+    })
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+> HashJoin<'closure, IterA, IterB> {
+    // Blocks written to and read from partition files.
+    pub fn spill_blocks(&self) -> uint {
+        let mut total = self.retired_spill_blocks;
+        for spill in self.probe_spill.iter() {
+            total += spill.blocks_written + spill.blocks_read;
+        }
+        for &(ref a, ref b, _) in self.partitions.iter() {
+            total += a.blocks_written + a.blocks_read + b.blocks_written + b.blocks_read;
+        }
+        total
+    }
+
+    fn retire(&mut self, spill: &SpillFile) {
+        self.retired_spill_blocks += spill.blocks_written + spill.blocks_read;
+    }
+
+    // Ends iteration early, keeping the error for `take_error`.
+    fn stop(&mut self, e: io::IoError) {
+        self.retired_spill_blocks = self.spill_blocks();
+        self.probe_spill = None;
+        self.partitions.clear();
+        self.probe_buffer.clear();
+        self.table.clear();
+        self.output.clear();
+        self.current_probe = None;
+        self.partitioned = true;
+        self.error = Some(IoError(e));
+    }
+
+    fn key_of(&mut self, from_a: bool, values: &Vec<Field>) -> Option<Field> {
+        if from_a { self.key_a.key(values) } else { self.key_b.key(values) }
+    }
+
+    // Queues the row, if any, for a record that can't match anything.
+    fn add_unmatched(&mut self, from_a: bool, values: Vec<Field>) {
+        match unmatched_row(self.kind, from_a, values, self.a_width, self.b_width) {
+            Some(row) => self.output.push(row),
+            None => (),
+        }
+    }
+
+    fn drain_unmatched(&mut self, spill: &mut SpillFile, from_a: bool) -> io::IoResult<()> {
+        loop {
+            match try!(spill.read()) {
+                None => return Ok(()),
+                Some(values) => self.add_unmatched(from_a, values),
+            }
+        }
+    }
+
+    fn create_partitions(&mut self) -> io::IoResult<Vec<SpillFile>> {
+        // Each partition being written needs a block of memory for its buffer.
+        let mut partitions = Vec::new();
+        for _ in range(0, self.memory_blocks - 1) {
+            partitions.push(try!(SpillFile::create(&self.db_path)));
+        }
+        self.partitions_created += partitions.len();
+        Ok(partitions)
+    }
+
+    fn write_partition(&mut self, from_a: bool, values: Vec<Field>, level: uint,
+                       partitions: &mut Vec<SpillFile>) -> io::IoResult<()> {
+        match self.key_of(from_a, &values) {
+            None => {
+                self.add_unmatched(from_a, values);
+                Ok(())
+            },
+            Some(key) => {
+                let p = partition_of(&key, level, partitions.len());
+                partitions.get_mut(p).write(values.as_slice())
+            },
+        }
+    }
+
+    fn insert_build(&mut self, values: Vec<Field>) {
+        let build_a = self.build_a;
+        match self.key_of(build_a, &values) {
+            None => self.add_unmatched(build_a, values),
+            Some(key) =>
+                self.table.find_or_insert_with(key, |_| Vec::new()).push((values, false)),
+        }
+    }
+
+    // Queues the rows for build records that no probe record matched.
+    fn finish_build(&mut self) {
+        let mut unmatched = Vec::new();
+        for (_, matches) in self.table.iter() {
+            for &(ref values, matched) in matches.iter() {
+                if !matched {
+                    unmatched.push(values.clone());
+                }
+            }
+        }
+        let build_a = self.build_a;
+        for values in unmatched.move_iter() {
+            self.add_unmatched(build_a, values);
+        }
+        self.table.clear();
+    }
+
+    fn start(&mut self) -> io::IoResult<()> {
+        let memory_records = self.memory_blocks * BLOCK_SIZE;
+        let mut buffer_a = Vec::new();
+        let mut buffer_b = Vec::new();
+        let mut finished = None;
+        while buffer_a.len() + buffer_b.len() < memory_records {
+            match self.iter_a.next() {
+                None => { finished = Some(true); break; },
+                Some(values) => buffer_a.push(values),
+            }
+            match self.iter_b.next() {
+                None => { finished = Some(false); break; },
+                Some(values) => buffer_b.push(values),
+            }
+        }
+
+        match finished {
+            Some(build_a) => {
+                self.build_a = build_a;
+                let (build, mut probe) =
+                    if build_a { (buffer_a, buffer_b) } else { (buffer_b, buffer_a) };
+                for values in build.move_iter() {
+                    self.insert_build(values);
+                }
+                probe.reverse();
+                self.probe_buffer = probe;
+                return Ok(());
+            },
+            None => (),
+        }
+
+        let mut partitions_a = try!(self.create_partitions());
+        let mut partitions_b = try!(self.create_partitions());
+        for values in buffer_a.move_iter() {
+            try!(self.write_partition(true, values, 0, &mut partitions_a));
+        }
+        loop {
+            match self.iter_a.next() {
+                None => break,
+                Some(values) => try!(self.write_partition(true, values, 0, &mut partitions_a)),
+            }
+        }
+        for values in buffer_b.move_iter() {
+            try!(self.write_partition(false, values, 0, &mut partitions_b));
+        }
+        loop {
+            match self.iter_b.next() {
+                None => break,
+                Some(values) => try!(self.write_partition(false, values, 0, &mut partitions_b)),
+            }
+        }
+
+        for (a, b) in partitions_a.move_iter().zip(partitions_b.move_iter()) {
+            self.partitions.push((a, b, 0));
+        }
+        self.partitioned = true;
+        try!(self.next_partition());
+        Ok(())
+    }
+
+    // Loads the build side of the next pair of partitions. Returns false if none are left.
+    fn next_partition(&mut self) -> io::IoResult<bool> {
+        match self.probe_spill.take() {
+            Some(spill) => self.retire(&spill),
+            None => (),
+        }
+        self.table.clear();
+
+        let memory_records = self.memory_blocks * BLOCK_SIZE;
+        loop {
+            let (mut a, mut b, level) = match self.partitions.pop() {
+                Some(p) => p, None => return Ok(false) };
+
+            // A partition without a counterpart can't match, but may still belong in an
+            // outer join.
+            if min(a.records, b.records) == 0 {
+                try!(self.drain_unmatched(&mut a, true));
+                try!(self.drain_unmatched(&mut b, false));
+                self.retire(&a);
+                self.retire(&b);
+                if self.output.is_empty() {
+                    continue;
+                }
+                return Ok(true);
+            }
+
+            if min(a.records, b.records) > memory_records && level < MAX_PARTITION_DEPTH {
+                let mut partitions_a = try!(self.create_partitions());
+                let mut partitions_b = try!(self.create_partitions());
+                loop {
+                    match try!(a.read()) {
+                        None => break,
+                        Some(values) =>
+                            try!(self.write_partition(true, values, level + 1, &mut partitions_a)),
+                    }
+                }
+                loop {
+                    match try!(b.read()) {
+                        None => break,
+                        Some(values) =>
+                            try!(self.write_partition(false, values, level + 1, &mut partitions_b)),
+                    }
+                }
+                self.retire(&a);
+                self.retire(&b);
+                for (a, b) in partitions_a.move_iter().zip(partitions_b.move_iter()) {
+                    self.partitions.push((a, b, level + 1));
+                }
+                continue;
+            }
+
+            self.build_a = a.records <= b.records;
+            let (mut build, probe) = if self.build_a { (a, b) } else { (b, a) };
+            loop {
+                match try!(build.read()) {
+                    None => break,
+                    Some(values) => self.insert_build(values),
+                }
+            }
+            self.retire(&build);
+            self.probe_spill = Some(probe);
+            return Ok(true);
+        }
+    }
+
+    fn next_probe(&mut self) -> Option<Vec<Field>> {
+        match self.probe_buffer.pop() {
+            Some(values) => return Some(values),
+            None => (),
+        }
+        let read = match self.probe_spill {
+            Some(ref mut spill) => spill.read(),
+            None if self.partitioned => return None,
+            None => return if self.build_a { self.iter_b.next() } else { self.iter_a.next() },
+        };
+        match read {
+            Ok(values) => values,
+            Err(e) => {
+                self.stop(e);
+                None
+            },
+        }
+    }
+
+    // Next row for the current probe record, if it has any left.
+    fn next_match(&mut self) -> Option<Option<Vec<Field>>> {
+        let i = self.match_index;
+        let found = match self.table.find_mut(self.current_key.get_ref()) {
+            Some(matches) => {
+                if i < matches.len() {
+                    let (ref build, ref mut matched) = *matches.get_mut(i);
+                    let first_build_match = !*matched;
+                    *matched = true;
+                    Some((build.clone(), first_build_match))
+                } else {
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let (build, first_build_match) = match found {
+            Some(f) => f, None => return None };
+        self.match_index += 1;
+        let first_probe_match = !self.probe_matched;
+        self.probe_matched = true;
+
+        let probe = self.current_probe.get_ref();
+        Some(if self.build_a {
+            matched_row(self.kind, &build, probe, first_build_match)
+        } else {
+            matched_row(self.kind, probe, &build, first_probe_match)
+        })
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+> Iterator<Vec<Field>> for HashJoin<'closure, IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            match self.start() {
+                Ok(()) => {},
+                Err(e) => self.stop(e),
+            }
+        }
+
+        loop {
+            match self.output.pop() {
+                Some(row) => return Some(row),
+                None => (),
+            }
+
+            if self.current_probe.is_some() {
+                match self.next_match() {
+                    Some(Some(row)) => return Some(row),
+                    Some(None) => continue,
+                    None => {
+                        let probe = self.current_probe.take().unwrap();
+                        if !self.probe_matched {
+                            let probe_a = !self.build_a;
+                            self.add_unmatched(probe_a, probe);
+                        }
+                        continue;
+                    },
+                }
+            }
+
+            let probe = match self.next_probe() {
+                Some(values) => values,
+                None => {
+                    self.finish_build();
+                    self.partitioned = true;
+                    let more = match self.next_partition() {
+                        Ok(more) => more,
+                        Err(e) => {
+                            self.stop(e);
+                            false
+                        },
+                    };
+                    if more || !self.output.is_empty() {
+                        continue;
+                    }
+                    return None;
+                },
+            };
+            let probe_a = !self.build_a;
+            match self.key_of(probe_a, &probe) {
+                None => self.add_unmatched(probe_a, probe),
+                Some(key) => {
+                    self.current_key = Some(key);
+                    self.current_probe = Some(probe);
+                    self.probe_matched = false;
+                    self.match_index = 0;
+                },
+            }
+        }
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator
+> TableIterator for HashJoin<'closure, IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed() + self.spill_blocks()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        match self.error.take() {
+            None => take_either_error(&mut self.iter_a, &mut self.iter_b),
+            error => error,
+        }
+    }
+}
+
+// Equi-join of two inputs sorted in ascending order on their join fields. Records of the second
+// input sharing a key are kept in memory while records of the first input with that key go by.
+pub struct MergeJoin<IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    field_a: uint,
+    field_b: uint,
+    schema: TableSchema,
+
+    started: bool,
+    current_a: Option<Vec<Field>>,
+    next_b: Option<Vec<Field>>,
+    group_key: Option<Field>,
+    group: Vec<Vec<Field>>,
+    group_index: uint,
+}
+
+pub fn merge_join<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, field_a: uint, iter_b: IterB, field_b: uint) -> MergeJoin<IterA, IterB> {
+    let schema = concat_schemas("merge-join", iter_a.schema(), iter_b.schema());
+    MergeJoin {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        field_a: field_a,
+        field_b: field_b,
+        schema: schema,
+
+        started: false,
+        current_a: None,
+        next_b: None,
+        group_key: None,
+        group: Vec::new(),
+        group_index: 0,
+    }
+}
+
+// Merge join of inputs that aren't already sorted on their join fields.
+pub fn sort_merge_join<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, field_a: uint, iter_b: IterB, field_b: uint, memory_blocks: uint,
+  db_path: &Path) -> Result<MergeJoin<Sort<IterA>, Sort<IterB>>, TableError> {
+    let sorted_a = try!(sort(iter_a, vec![(field_a, Ascending)], memory_blocks, db_path));
+    let sorted_b = try!(sort(iter_b, vec![(field_b, Ascending)], memory_blocks, db_path));
+    Ok(merge_join(sorted_a, field_a, sorted_b, field_b))
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> Iterator<Vec<Field>> for MergeJoin<IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            self.next_b = self.iter_b.next();
+        }
+
+        loop {
+            match self.current_a {
+                Some(ref a) if self.group_index < self.group.len() => {
+                    let b = self.group.get(self.group_index);
+                    self.group_index += 1;
+                    return Some(*a + *b);
+                },
+                _ => (),
+            }
+
+            let a = match self.iter_a.next() {
+                Some(a) => a, None => return None };
+            let key = a.get(self.field_a).clone();
+            // NULL keys match nothing, not even other NULLs.
+            if key == Null {
+                continue;
+            }
+            self.current_a = Some(a);
+            self.group_index = 0;
+            if self.group_key.as_ref() == Some(&key) {
+                continue;
+            }
+
+            // Skip records of the second input with smaller keys, then gather those matching.
+            loop {
+                let smaller = match self.next_b {
+                    Some(ref b) => *b.get(self.field_b) < key,
+                    None => false,
+                };
+                if !smaller {
+                    break;
+                }
+                self.next_b = self.iter_b.next();
+            }
+
+            self.group.clear();
+            loop {
+                let matches = match self.next_b {
+                    Some(ref b) => *b.get(self.field_b) == key,
+                    None => false,
+                };
+                if !matches {
+                    break;
+                }
+                let b = mem::replace(&mut self.next_b, self.iter_b.next());
+                self.group.push(b.unwrap());
+            }
+            self.group_key = Some(key);
+        }
+    }
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> TableIterator for MergeJoin<IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+// Nested-loop join that reads the first input `buffer_blocks` blocks at a time and scans the
+// second input once per buffer, instead of once per record like `CrossJoin`.
+//
+// Right and full outer joins scan the second input once more at the end, to emit the records
+// that matched no buffer.
+pub struct BlockNestedLoopJoin<'closure, IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    condition: JoinCondition<'closure>,
+    kind: JoinKind,
+    buffer_records: uint,
+    schema: TableSchema,
+    a_width: uint,
+    b_width: uint,
+
+    phase: NestedLoopPhase,
+    buffer: Vec<Vec<Field>>,
+    matched_a: Vec<bool>,
+    buffer_index: uint,
+    current_b: Option<Vec<Field>>,
+    // Ordinals of the records of the second input that matched something.
+    matched_b: Bitmap,
+    b_ordinal: uint,
+}
+
+enum NestedLoopPhase {
+    ScanInnerPhase,
+    // Emitting the unmatched records of the buffer, from this index on.
+    UnmatchedAPhase(uint),
+    UnmatchedBPhase,
+    FinishedPhase,
+}
+
+// These fail if the buffer is empty.
+pub fn block_nested_loop_join<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+>(iter_a: IterA, iter_b: IterB, buffer_blocks: uint,
+  condition: |&Vec<Field>, &Vec<Field>|:'closure -> bool)
+        -> Result<BlockNestedLoopJoin<'closure, IterA, IterB>, TableError> {
+    block_nested_loop_join_kind(iter_a, iter_b, InnerJoin, buffer_blocks, condition)
+}
+
+pub fn block_nested_loop_join_kind<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, buffer_blocks: uint,
+  condition: |&Vec<Field>, &Vec<Field>|:'closure -> bool)
+        -> Result<BlockNestedLoopJoin<'closure, IterA, IterB>, TableError> {
+    block_nested_loop_join_condition(iter_a, iter_b, kind, buffer_blocks,
+                                     ConditionClosure(condition))
+}
+
+// Nested-loop join on a condition over the fields of both inputs, named as in an inner join.
+pub fn block_nested_loop_join_expr<
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, buffer_blocks: uint, condition: &Expr)
+        -> Result<BlockNestedLoopJoin<'static, IterA, IterB>, TableError> {
+    let condition = {
+        let schema = concat_schemas("block-nested-loop-join", iter_a.schema(), iter_b.schema());
+        try!(bind_condition(condition, &schema))
+    };
+    block_nested_loop_join_condition(iter_a, iter_b, kind, buffer_blocks,
+                                     ConditionExpr(condition))
+}
+
+fn block_nested_loop_join_condition<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+>(iter_a: IterA, iter_b: IterB, kind: JoinKind, buffer_blocks: uint,
+  condition: JoinCondition<'closure>)
+        -> Result<BlockNestedLoopJoin<'closure, IterA, IterB>, TableError> {
+    if buffer_blocks == 0 {
+        return Err(MemoryBudgetError("Block nested-loop join".to_strbuf(), 1));
+    }
+
+    let schema = join_schema("block-nested-loop-join", kind, iter_a.schema(), iter_b.schema());
+    let a_width = iter_a.schema().fields.len();
+    let b_width = iter_b.schema().fields.len();
+    Ok(BlockNestedLoopJoin {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        condition: condition,
+        kind: kind,
+        buffer_records: buffer_blocks * BLOCK_SIZE,
+        schema: schema,
+        a_width: a_width,
+        b_width: b_width,
+
+        // Starts by going through an empty buffer, which reads the first one.
+        phase: UnmatchedAPhase(0),
+        buffer: Vec::new(),
+        matched_a: Vec::new(),
+        buffer_index: 0,
+        current_b: None,
+        matched_b: Bitmap::new(),
+        b_ordinal: 0,
+    })
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+> BlockNestedLoopJoin<'closure, IterA, IterB> {
+    // Reads the next buffer and rewinds the second input. Returns false if the first input
+    // is exhausted.
+    fn refill(&mut self) -> bool {
+        self.buffer.clear();
+        for _ in range(0, self.buffer_records) {
+            match self.iter_a.next() {
+                None => break,
+                Some(a) => self.buffer.push(a),
+            }
+        }
+        self.matched_a = Vec::from_elem(self.buffer.len(), false);
+        self.current_b = None;
+        self.buffer_index = 0;
+        self.b_ordinal = 0;
+        self.iter_b.rewind();
+        !self.buffer.is_empty()
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+> Iterator<Vec<Field>> for BlockNestedLoopJoin<'closure, IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            let phase = self.phase;
+            match phase {
+                ScanInnerPhase => {
+                    match self.current_b {
+                        Some(ref b) => {
+                            // Semi and anti joins only care whether a record matched at all.
+                            let first_only = self.kind == SemiJoin || self.kind == AntiJoin;
+                            while self.buffer_index < self.buffer.len() {
+                                let i = self.buffer_index;
+                                self.buffer_index += 1;
+                                let first_match = !*self.matched_a.get(i);
+                                if first_only && !first_match {
+                                    continue;
+                                }
+
+                                let a = self.buffer.get(i);
+                                if self.condition.test(a, b) {
+                                    *self.matched_a.get_mut(i) = true;
+                                    if keeps_unmatched_b(self.kind) {
+                                        self.matched_b.insert(self.b_ordinal - 1);
+                                    }
+                                    match matched_row(self.kind, a, b, first_match) {
+                                        Some(row) => return Some(row),
+                                        None => (),
+                                    }
+                                }
+                            }
+                        },
+                        None => (),
+                    }
+
+                    self.current_b = self.iter_b.next();
+                    self.buffer_index = 0;
+                    if self.current_b.is_some() {
+                        self.b_ordinal += 1;
+                    } else {
+                        self.phase = UnmatchedAPhase(0);
+                    }
+                },
+                UnmatchedAPhase(i) => {
+                    if i < self.buffer.len() {
+                        self.phase = UnmatchedAPhase(i + 1);
+                        if !*self.matched_a.get(i) {
+                            let a = self.buffer.get(i).clone();
+                            match unmatched_row(self.kind, true, a, self.a_width, self.b_width) {
+                                Some(row) => return Some(row),
+                                None => (),
+                            }
+                        }
+                        continue;
+                    }
+
+                    self.phase = if self.refill() {
+                        ScanInnerPhase
+                    } else if keeps_unmatched_b(self.kind) {
+                        UnmatchedBPhase
+                    } else {
+                        FinishedPhase
+                    };
+                },
+                UnmatchedBPhase => {
+                    let b = match self.iter_b.next() {
+                        Some(b) => b,
+                        None => {
+                            self.phase = FinishedPhase;
+                            continue;
+                        },
+                    };
+                    let ordinal = self.b_ordinal;
+                    self.b_ordinal += 1;
+                    if !self.matched_b.contains(ordinal) {
+                        match unmatched_row(self.kind, false, b, self.a_width, self.b_width) {
+                            Some(row) => return Some(row),
+                            None => (),
+                        }
+                    }
+                },
+                FinishedPhase => return None,
+            }
+        }
+    }
+}
+
+impl<
+    'closure,
+    IterA: TableIterator,
+    IterB: TableIterator + RewindableIterator<Vec<Field>>
+> TableIterator for BlockNestedLoopJoin<'closure, IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+// Aggregate functions, each over a field of the input except for `Count`.
+#[deriving(Clone, Eq, Show)]
+pub enum Aggregate {
+    Count,
+    Sum(uint),
+    Min(uint),
+    Max(uint),
+    // Truncated to an integer, since that's the only numeric field type.
+    Avg(uint),
+}
+
+struct Accumulator {
+    count: u32,
+    sum: u64,
+    value: Option<Field>,
+}
+
+impl Accumulator {
+    fn new() -> Accumulator {
+        Accumulator { count: 0, sum: 0, value: None }
+    }
+
+    fn update(&mut self, aggregate: &Aggregate, values: &Vec<Field>) {
+        match *aggregate {
+            Count => self.count += 1,
+            // NULLs, such as the padding of outer joins, are left out of all but COUNT.
+            Sum(field) | Avg(field) => {
+                match *values.get(field) {
+                    Integer(x) => self.sum += x as u64,
+                    // Text fields are rejected by `aggregate_schema`.
+                    _ => return,
+                }
+                self.count += 1;
+            },
+            Min(field) => {
+                let value = values.get(field);
+                if *value != Null && self.value.as_ref().map_or(true, |min| value < min) {
+                    self.value = Some(value.clone());
+                }
+            },
+            Max(field) => {
+                let value = values.get(field);
+                if *value != Null && self.value.as_ref().map_or(true, |max| value > max) {
+                    self.value = Some(value.clone());
+                }
+            },
+        }
+    }
+
+    fn result(&self, aggregate: &Aggregate, schema: &FieldSchema) -> Result<Field, TableError> {
+        Ok(match *aggregate {
+            Count => Integer(self.count),
+            Sum(_) if self.count == 0 => Null,
+            Sum(_) => {
+                if self.sum > u32::MAX as u64 {
+                    return Err(OverflowError(schema.name.clone()));
+                }
+                Integer(self.sum as u32)
+            },
+            Avg(_) if self.count == 0 => Null,
+            Avg(_) => Integer((self.sum / self.count as u64) as u32),
+            Min(_) | Max(_) => self.value.clone().unwrap_or(Null),
+        })
+    }
+}
+
+// Output schema of an aggregation: the grouping fields followed by one field per aggregate.
+fn aggregate_schema(base: &TableSchema, group_fields: &[uint], aggregates: &[Aggregate])
+        -> Result<TableSchema, TableError> {
+    let num_fields = base.fields.len();
+    let mut fields = Vec::with_capacity(group_fields.len() + aggregates.len());
+    let mut offset = 0;
+    for &i in group_fields.iter() {
+        if i >= num_fields {
+            return Err(FieldIndexError(i, num_fields));
+        }
+        let f = base.fields.get(i);
+        fields.push(FieldSchema { name: f.name.clone(), offset: offset, ..*f });
+        offset += f.length;
+    }
+
+    for aggregate in aggregates.iter() {
+        let (name, source) = match *aggregate {
+            Count => ("COUNT", None),
+            Sum(i) => ("SUM", Some(i)),
+            Min(i) => ("MIN", Some(i)),
+            Max(i) => ("MAX", Some(i)),
+            Avg(i) => ("AVG", Some(i)),
+        };
+        let field = match source {
+            None => FieldSchema {
+                name: "COUNT(*)".to_strbuf(),
+                offset: offset,
+                data_type: IntegerType,
+                length: 4,
+                auto_increment: None,
+            },
+            Some(i) => {
+                if i >= num_fields {
+                    return Err(FieldIndexError(i, num_fields));
+                }
+                let f = base.fields.get(i);
+                match *aggregate {
+                    Sum(_) | Avg(_) if f.data_type != IntegerType =>
+                        return Err(TypeError(i, f.data_type, IntegerType)),
+                    Min(_) | Max(_) => FieldSchema {
+                        name: format!("{}({})", name, f.name),
+                        offset: offset,
+                        auto_increment: None,
+                        ..*f
+                    },
+                    _ => FieldSchema {
+                        name: format!("{}({})", name, f.name),
+                        offset: offset,
+                        data_type: IntegerType,
+                        length: 4,
+                        auto_increment: None,
+                    },
+                }
+            },
+        };
+        offset += field.length;
+        fields.push(field);
+    }
+
+    Ok(TableSchema {
+        name: base.name.clone(),
+        fields: fields,
+        entry_stride: offset,
+        primary_key: None,
+    })
+}
+
+fn group_key(group_fields: &[uint], values: &Vec<Field>) -> Vec<Field> {
+    group_fields.iter().map(|&i| values.get(i).clone()).collect()
+}
+
+// Fails if a sum doesn't fit in an Integer field.
+fn aggregate_row(key: Vec<Field>, aggregates: &[Aggregate], accumulators: &[Accumulator],
+                 schema: &TableSchema) -> Result<Vec<Field>, TableError> {
+    let mut row = key;
+    let aggregate_fields = schema.fields.slice_from(row.len());
+    for ((aggregate, accumulator), field) in
+            aggregates.iter().zip(accumulators.iter()).zip(aggregate_fields.iter()) {
+        row.push(try!(accumulator.result(aggregate, field)));
+    }
+    Ok(row)
+}
+
+// Groups records by some fields in a hash table, computing aggregates for each group.
+//
+// Once the table holds as many groups as fit in `memory_blocks` blocks, records of new groups
+// are partitioned to spill files by group and aggregated after the groups in memory are done.
+// Empty inputs produce no rows, even without grouping fields.
+//
+// A sum too large for an Integer field, or a failure of a spill file, ends the output early,
+// with the error reported by `take_error`.
+pub struct HashAggregate<Iter> {
+    base: Iter,
+    group_fields: Vec<uint>,
+    aggregates: Vec<Aggregate>,
+    memory_blocks: uint,
+    db_path: Path,
+    schema: TableSchema,
+
+    started: bool,
+    output: Vec<Vec<Field>>,
+    // Spilled records still to be aggregated, with the number of times they were partitioned.
+    partitions: Vec<(SpillFile, uint)>,
+
+    retired_spill_blocks: uint,
+    pub partitions_created: uint,
+    error: Option<TableError>,
+}
+
+// Fails if a SUM or AVG is over a Text field, or if the memory budget is under 2 blocks.
+pub fn hash_aggregate<Iter: TableIterator>(base: Iter, group_fields: Vec<uint>,
+                                           aggregates: Vec<Aggregate>, memory_blocks: uint,
+                                           db_path: &Path)
+        -> Result<HashAggregate<Iter>, TableError> {
+    if memory_blocks < 2 {
+        return Err(MemoryBudgetError("Hash aggregation".to_strbuf(), 2));
+    }
+
+    let schema = try!(aggregate_schema(base.schema(), group_fields.as_slice(),
+                                       aggregates.as_slice()));
+    Ok(HashAggregate {
+        base: base,
+        group_fields: group_fields,
+        aggregates: aggregates,
+        memory_blocks: memory_blocks,
+        db_path: db_path.clone(),
+        schema: schema,
+
+        started: false,
+        output: Vec::new(),
+        partitions: Vec::new(),
+
+        retired_spill_blocks: 0,
+        partitions_created: 0,
+        error: None,
+    })
+}
+
+impl<Iter: TableIterator> HashAggregate<Iter> {
+    // Blocks written to and read from partition files.
+    pub fn spill_blocks(&self) -> uint {
+        self.partitions.iter().fold(self.retired_spill_blocks,
+            |total, &(ref spill, _)| total + spill.blocks_written + spill.blocks_read)
+    }
+
+    // Aggregates the records of the base iterator, or of a partition if one is given.
+    fn aggregate_batch(&mut self, mut source: Option<SpillFile>, level: uint)
+            -> Result<(), TableError> {
+        let max_groups = self.memory_blocks * BLOCK_SIZE;
+        let mut groups: HashMap<Vec<Field>, Vec<Accumulator>> = HashMap::new();
+        let mut partitions = Vec::new();
+
+        loop {
+            let values = match source {
+                Some(ref mut spill) => try!(spill.read().map_err(IoError)),
+                None => self.base.next(),
+            };
+            let values = match values {
+                Some(v) => v, None => break };
+            let key = group_key(self.group_fields.as_slice(), &values);
+
+            if !groups.contains_key(&key) && groups.len() >= max_groups
+                    && level < MAX_PARTITION_DEPTH {
+                if partitions.is_empty() {
+                    for _ in range(0, self.memory_blocks - 1) {
+                        partitions.push(try!(SpillFile::create(&self.db_path).map_err(IoError)));
+                    }
+                    self.partitions_created += partitions.len();
+                }
+                let p = partition_of(&key, level, partitions.len());
+                try!(partitions.get_mut(p).write(values.as_slice()).map_err(IoError));
+                continue;
+            }
+
+            let aggregates = self.aggregates.as_slice();
+            let accumulators = groups.find_or_insert_with(key,
+                |_| aggregates.iter().map(|_| Accumulator::new()).collect());
+            for (aggregate, accumulator) in aggregates.iter().zip(accumulators.mut_iter()) {
+                accumulator.update(aggregate, &values);
+            }
+        }
+
+        for (key, accumulators) in groups.move_iter() {
+            self.output.push(try!(aggregate_row(key, self.aggregates.as_slice(),
+                                                accumulators.as_slice(), &self.schema)));
+        }
+        for spill in source.iter() {
+            self.retired_spill_blocks += spill.blocks_written + spill.blocks_read;
+        }
+        for spill in partitions.move_iter() {
+            self.partitions.push((spill, level + 1));
+        }
+        Ok(())
+    }
+
+    // Aggregates a batch, or ends the output if that fails, keeping the error for
+    // `take_error`.
+    fn finish_batch(&mut self, source: Option<SpillFile>, level: uint) {
+        match self.aggregate_batch(source, level) {
+            Ok(()) => (),
+            Err(e) => {
+                self.retired_spill_blocks = self.spill_blocks();
+                self.error = Some(e);
+                self.output.clear();
+                self.partitions.clear();
+            },
+        }
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for HashAggregate<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            self.finish_batch(None, 0);
+        }
+
+        loop {
+            match self.output.pop() {
+                Some(row) => return Some(row),
+                None => (),
+            }
+            match self.partitions.pop() {
+                None => return None,
+                Some((spill, level)) => self.finish_batch(Some(spill), level),
+            }
+        }
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for HashAggregate<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed() + self.spill_blocks()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        match self.error.take() {
+            None => self.base.take_error(),
+            error => error,
+        }
+    }
+}
+
+// Computes aggregates over groups of records by sorting on the grouping fields, then reading
+// each group in one go. Groups come out in ascending order, up to the first one whose sum
+// overflows, if any, whose error is then reported by `take_error`.
+pub struct SortAggregate<Iter> {
+    sorted: Sort<Iter>,
+    group_fields: Vec<uint>,
+    aggregates: Vec<Aggregate>,
+    schema: TableSchema,
+
+    started: bool,
+    next_record: Option<Vec<Field>>,
+    error: Option<TableError>,
+}
+
+// Fails if a SUM or AVG is over a Text field.
+pub fn sort_aggregate<Iter: TableIterator>(base: Iter, group_fields: Vec<uint>,
+                                           aggregates: Vec<Aggregate>, memory_blocks: uint,
+                                           db_path: &Path)
+        -> Result<SortAggregate<Iter>, TableError> {
+    let schema = try!(aggregate_schema(base.schema(), group_fields.as_slice(),
+                                       aggregates.as_slice()));
+    let keys = group_fields.iter().map(|&i| (i, Ascending)).collect();
+    Ok(SortAggregate {
+        sorted: try!(sort(base, keys, memory_blocks, db_path)),
+        group_fields: group_fields,
+        aggregates: aggregates,
+        schema: schema,
+
+        started: false,
+        next_record: None,
+        error: None,
+    })
+}
+
+impl<Iter: TableIterator> SortAggregate<Iter> {
+    pub fn runs_created(&self) -> uint {
+        self.sorted.runs_created
+    }
+
+    pub fn spill_blocks(&self) -> uint {
+        self.sorted.spill_blocks()
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for SortAggregate<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            self.next_record = self.sorted.next();
+        }
+
+        let first = match self.next_record.take() {
+            Some(values) => values, None => return None };
+        let key = group_key(self.group_fields.as_slice(), &first);
+        let mut accumulators: Vec<Accumulator> =
+            self.aggregates.iter().map(|_| Accumulator::new()).collect();
+
+        let mut values = first;
+        loop {
+            for (aggregate, accumulator) in self.aggregates.iter().zip(accumulators.mut_iter()) {
+                accumulator.update(aggregate, &values);
+            }
+            match self.sorted.next() {
+                None => break,
+                Some(next) => {
+                    if group_key(self.group_fields.as_slice(), &next) != key {
+                        self.next_record = Some(next);
+                        break;
+                    }
+                    values = next;
+                },
+            }
+        }
+
+        match aggregate_row(key, self.aggregates.as_slice(), accumulators.as_slice(),
+                            &self.schema) {
+            Ok(row) => Some(row),
+            Err(e) => {
+                // Nothing is returned after the failed group.
+                self.next_record = None;
+                self.error = Some(e);
+                None
+            },
+        }
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for SortAggregate<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.sorted.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.sorted.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        match self.error.take() {
+            None => self.sorted.take_error(),
+            error => error,
+        }
+    }
+}
+
+fn distinct_key(fields: &Option<Vec<uint>>, values: &Vec<Field>) -> Vec<Field> {
+    match *fields {
+        None => values.clone(),
+        Some(ref fields) => group_key(fields.as_slice(), values),
+    }
+}
+
+// Removes records that repeat the fields of an earlier one, or all fields if none are given.
+// The first record of each distinct key is kept whole.
+//
+// Records are returned as soon as their key is first seen. Once the keys seen fill
+// `memory_blocks` blocks, records with new keys are partitioned to spill files by key and
+// deduplicated after the input is done. A failure of a spill file ends the output early, with
+// the error reported by `take_error`.
+pub struct HashDistinct<Iter> {
+    base: Iter,
+    fields: Option<Vec<uint>>,
+    memory_blocks: uint,
+    db_path: Path,
+
+    seen: HashSet<Vec<Field>>,
+    source: Option<SpillFile>,
+    level: uint,
+    // Partitions being written from the current source.
+    new_partitions: Vec<SpillFile>,
+    partitions: Vec<(SpillFile, uint)>,
+
+    retired_spill_blocks: uint,
+    pub partitions_created: uint,
+    stopped: bool,
+    error: Option<TableError>,
+}
+
+// Fails if the memory budget is under 2 blocks.
+pub fn hash_distinct<Iter: TableIterator>(base: Iter, fields: Option<Vec<uint>>,
+                                          memory_blocks: uint, db_path: &Path)
+        -> Result<HashDistinct<Iter>, TableError> {
+    if memory_blocks < 2 {
+        return Err(MemoryBudgetError("Hash distinct".to_strbuf(), 2));
+    }
+
+    Ok(HashDistinct {
+        base: base,
+        fields: fields,
+        memory_blocks: memory_blocks,
+        db_path: db_path.clone(),
+
+        seen: HashSet::new(),
+        source: None,
+        level: 0,
+        new_partitions: Vec::new(),
+        partitions: Vec::new(),
+
+        retired_spill_blocks: 0,
+        partitions_created: 0,
+        stopped: false,
+        error: None,
+    })
+}
+
+impl<Iter: TableIterator> HashDistinct<Iter> {
+    // Blocks written to and read from partition files.
+    pub fn spill_blocks(&self) -> uint {
+        let mut total = self.retired_spill_blocks;
+        for spill in self.source.iter().chain(self.new_partitions.iter()) {
+            total += spill.blocks_written + spill.blocks_read;
+        }
+        for &(ref spill, _) in self.partitions.iter() {
+            total += spill.blocks_written + spill.blocks_read;
+        }
+        total
+    }
+
+    fn spill(&mut self, key: &Vec<Field>, values: &Vec<Field>) -> io::IoResult<()> {
+        if self.new_partitions.is_empty() {
+            for _ in range(0, self.memory_blocks - 1) {
+                self.new_partitions.push(try!(SpillFile::create(&self.db_path)));
+            }
+            self.partitions_created += self.new_partitions.len();
+        }
+        let p = partition_of(key, self.level, self.new_partitions.len());
+        self.new_partitions.get_mut(p).write(values.as_slice())
+    }
+
+    // Ends iteration early, keeping the error for `take_error`.
+    fn stop(&mut self, e: io::IoError) {
+        self.retired_spill_blocks = self.spill_blocks();
+        self.source = None;
+        self.new_partitions.clear();
+        self.partitions.clear();
+        self.seen.clear();
+        self.stopped = true;
+        self.error = Some(IoError(e));
+    }
+
+    // Moves on to the next partition. Returns false if none are left.
+    fn next_source(&mut self) -> bool {
+        for spill in self.source.take().iter() {
+            self.retired_spill_blocks += spill.blocks_written + spill.blocks_read;
+        }
+        let level = self.level;
+        for spill in mem::replace(&mut self.new_partitions, Vec::new()).move_iter() {
+            self.partitions.push((spill, level + 1));
+        }
+        self.seen.clear();
+
+        match self.partitions.pop() {
+            None => false,
+            Some((spill, level)) => {
+                self.source = Some(spill);
+                self.level = level;
+                true
+            },
+        }
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for HashDistinct<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if self.stopped {
+            return None;
+        }
+
+        let max_keys = self.memory_blocks * BLOCK_SIZE;
+        loop {
+            let read = match self.source {
+                Some(ref mut spill) => spill.read(),
+                None => Ok(self.base.next()),
+            };
+            let values = match read {
+                Ok(Some(v)) => v,
+                Ok(None) => {
+                    if self.next_source() {
+                        continue;
+                    }
+                    return None;
+                },
+                Err(e) => {
+                    self.stop(e);
+                    return None;
+                },
+            };
+
+            let key = distinct_key(&self.fields, &values);
+            if self.seen.contains(&key) {
+                continue;
+            }
+            if self.seen.len() < max_keys || self.level >= MAX_PARTITION_DEPTH {
+                self.seen.insert(key);
+                return Some(values);
+            }
+            match self.spill(&key, &values) {
+                Ok(()) => (),
+                Err(e) => {
+                    self.stop(e);
+                    return None;
+                },
+            }
+        }
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for HashDistinct<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed() + self.spill_blocks()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        match self.error.take() {
+            None => self.base.take_error(),
+            error => error,
+        }
+    }
+}
+
+// Removes duplicates by sorting on the distinct fields, keeping the first record of each run
+// of equal keys. Records come out in ascending order of those fields.
+pub struct SortDistinct<Iter> {
+    sorted: Sort<Iter>,
+    fields: Option<Vec<uint>>,
+    last_key: Option<Vec<Field>>,
+}
+
+pub fn sort_distinct<Iter: TableIterator>(base: Iter, fields: Option<Vec<uint>>,
+                                          memory_blocks: uint, db_path: &Path)
+        -> Result<SortDistinct<Iter>, TableError> {
+    let keys = match fields {
+        Some(ref fields) => fields.iter().map(|&i| (i, Ascending)).collect(),
+        None => range(0, base.schema().fields.len()).map(|i| (i, Ascending)).collect(),
+    };
+    Ok(SortDistinct {
+        sorted: try!(sort(base, keys, memory_blocks, db_path)),
+        fields: fields,
+        last_key: None,
+    })
+}
+
+impl<Iter: TableIterator> SortDistinct<Iter> {
+    pub fn runs_created(&self) -> uint {
+        self.sorted.runs_created
+    }
+
+    pub fn spill_blocks(&self) -> uint {
+        self.sorted.spill_blocks()
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for SortDistinct<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            let values = match self.sorted.next() {
+                Some(v) => v, None => return None };
+            let key = distinct_key(&self.fields, &values);
+            if self.last_key.as_ref() != Some(&key) {
+                self.last_key = Some(key);
+                return Some(values);
+            }
+        }
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for SortDistinct<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.sorted.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.sorted.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.sorted.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.sorted.take_error()
+    }
+}
+
+// Output schema of a set operation, if the inputs have the same number of fields with the same
+// types. Fields take the names of the first input and the longer of the two lengths.
+fn set_schema(table_name: &str, sa: &TableSchema, sb: &TableSchema)
+        -> Result<TableSchema, TableError> {
+    if sa.fields.len() != sb.fields.len() {
+        return Err(FieldCountError(sb.fields.len(), sa.fields.len()));
+    }
+
+    let mut fields = Vec::with_capacity(sa.fields.len());
+    let mut offset = 0;
+    for (i, (fa, fb)) in sa.fields.iter().zip(sb.fields.iter()).enumerate() {
+        if fa.data_type != fb.data_type {
+            return Err(TypeError(i, fb.data_type, fa.data_type));
+        }
+        let length = max(fa.length, fb.length);
+        fields.push(FieldSchema {
+            name: fa.name.clone(),
+            offset: offset,
+            length: length,
+            ..*fa
+        });
+        offset += length;
+    }
+
+    Ok(TableSchema {
+        name: table_name.to_owned(),
+        fields: fields,
+        entry_stride: offset,
+        primary_key: None,
+    })
+}
+
+// All records of the first input followed by all records of the second (UNION ALL).
+pub struct UnionAll<IterA, IterB> {
+    iter_a: IterA,
+    iter_b: IterB,
+    schema: TableSchema,
+
+    a_done: bool,
+}
+
+pub fn union_all<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB) -> Result<UnionAll<IterA, IterB>, TableError> {
+    let schema = try!(set_schema("union-all", iter_a.schema(), iter_b.schema()));
+    Ok(UnionAll {
+        iter_a: iter_a,
+        iter_b: iter_b,
+        schema: schema,
+
+        a_done: false,
+    })
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> Iterator<Vec<Field>> for UnionAll<IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.a_done {
+            match self.iter_a.next() {
+                Some(values) => return Some(values),
+                None => self.a_done = true,
+            }
+        }
+        self.iter_b.next()
+    }
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> TableIterator for UnionAll<IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+enum SetOperationKind {
+    UnionOperation,
+    IntersectOperation,
+    ExceptOperation,
+}
+
+// UNION, INTERSECT or EXCEPT without duplicates. Both inputs are sorted on all fields and
+// deduplicated, then merged. Records come out in ascending order.
+//
+// Intersections stop reading once either input ends, differences once the first one does.
+pub struct SetOperation<IterA, IterB> {
+    iter_a: SortDistinct<IterA>,
+    iter_b: SortDistinct<IterB>,
+    kind: SetOperationKind,
+    schema: TableSchema,
+
+    started: bool,
+    head_a: Option<Vec<Field>>,
+    head_b: Option<Vec<Field>>,
+}
+
+fn set_operation<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(table_name: &str, kind: SetOperationKind, iter_a: IterA, iter_b: IterB, memory_blocks: uint,
+  db_path: &Path) -> Result<SetOperation<IterA, IterB>, TableError> {
+    let schema = try!(set_schema(table_name, iter_a.schema(), iter_b.schema()));
+    Ok(SetOperation {
+        iter_a: try!(sort_distinct(iter_a, None, memory_blocks, db_path)),
+        iter_b: try!(sort_distinct(iter_b, None, memory_blocks, db_path)),
+        kind: kind,
+        schema: schema,
+
+        started: false,
+        head_a: None,
+        head_b: None,
+    })
+}
+
+pub fn union<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, memory_blocks: uint, db_path: &Path)
+        -> Result<SetOperation<IterA, IterB>, TableError> {
+    set_operation("union", UnionOperation, iter_a, iter_b, memory_blocks, db_path)
+}
+
+pub fn intersect<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, memory_blocks: uint, db_path: &Path)
+        -> Result<SetOperation<IterA, IterB>, TableError> {
+    set_operation("intersect", IntersectOperation, iter_a, iter_b, memory_blocks, db_path)
+}
+
+pub fn except<
+    IterA: TableIterator,
+    IterB: TableIterator
+>(iter_a: IterA, iter_b: IterB, memory_blocks: uint, db_path: &Path)
+        -> Result<SetOperation<IterA, IterB>, TableError> {
+    set_operation("except", ExceptOperation, iter_a, iter_b, memory_blocks, db_path)
+}
+
+impl<IterA: TableIterator, IterB: TableIterator> SetOperation<IterA, IterB> {
+    pub fn spill_blocks(&self) -> uint {
+        self.iter_a.spill_blocks() + self.iter_b.spill_blocks()
+    }
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> Iterator<Vec<Field>> for SetOperation<IterA, IterB> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            self.head_a = self.iter_a.next();
+            let need_b = match self.kind {
+                UnionOperation => true,
+                IntersectOperation | ExceptOperation => self.head_a.is_some(),
+            };
+            if need_b {
+                self.head_b = self.iter_b.next();
+            }
+        }
+
+        loop {
+            match self.kind {
+                IntersectOperation if self.head_a.is_none() || self.head_b.is_none() =>
+                    return None,
+                ExceptOperation if self.head_a.is_none() => return None,
+                _ => (),
+            }
+
+            let ordering = match (&self.head_a, &self.head_b) {
+                (&None, &None) => return None,
+                (&Some(_), &None) => Less,
+                (&None, &Some(_)) => Greater,
+                (&Some(ref a), &Some(ref b)) => a.cmp(b),
+            };
+
+            match ordering {
+                Less => {
+                    let a = mem::replace(&mut self.head_a, self.iter_a.next());
+                    match self.kind {
+                        UnionOperation | ExceptOperation => return a,
+                        IntersectOperation => (),
+                    }
+                },
+                Greater => {
+                    let b = mem::replace(&mut self.head_b, self.iter_b.next());
+                    match self.kind {
+                        UnionOperation => return b,
+                        IntersectOperation | ExceptOperation => (),
+                    }
+                },
+                Equal => {
+                    let a = mem::replace(&mut self.head_a, self.iter_a.next());
+                    self.head_b = self.iter_b.next();
+                    match self.kind {
+                        UnionOperation | IntersectOperation => return a,
+                        ExceptOperation => (),
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<
+    IterA: TableIterator,
+    IterB: TableIterator
+> TableIterator for SetOperation<IterA, IterB> {
+    fn blocks_accessed(&self) -> uint {
+        self.iter_a.blocks_accessed() + self.iter_b.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.iter_a.records_accessed() + self.iter_b.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        &self.schema
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        take_either_error(&mut self.iter_a, &mut self.iter_b)
+    }
+}
+
+// Reads its whole input up front and keeps it for any number of passes, so that inputs which
+// can't rewind themselves can be used on the inner side of `cross` and nested-loop joins.
+//
+// Records are kept in memory while they fit in `memory_blocks` blocks. Otherwise they are all
+// written to a spill file, which is then read back from disk on every pass. A failed read ends
+// the pass early, with the error reported by `take_error`.
+pub struct Materialize<Iter> {
+    base: Iter,
+    records: Vec<Vec<Field>>,
+    spill: Option<SpillFile>,
+    len: uint,
+    pos: uint,
+    error: Option<TableError>,
+}
+
+// Fails if the memory budget is empty, or if the input can't be read or spilled in full.
+pub fn materialize<Iter: TableIterator>(mut base: Iter, memory_blocks: uint, db_path: &Path)
+        -> Result<Materialize<Iter>, TableError> {
+    if memory_blocks == 0 {
+        return Err(MemoryBudgetError("Materialize".to_strbuf(), 1));
+    }
+
+    let memory_records = memory_blocks * BLOCK_SIZE;
+    let mut records = Vec::new();
+    let mut spill = None;
+    let mut len = 0;
+    loop {
+        let values = match base.next() {
+            Some(v) => v, None => break };
+        len += 1;
+
+        if spill.is_none() && records.len() < memory_records {
+            records.push(values);
+            continue;
+        }
+        if spill.is_none() {
+            let mut file = try!(SpillFile::create(db_path).map_err(IoError));
+            for buffered in records.iter() {
+                try!(file.write(buffered.as_slice()).map_err(IoError));
+            }
+            records.clear();
+            spill = Some(file);
+        }
+        try!(spill.get_mut_ref().write(values.as_slice()).map_err(IoError));
+    }
+    match base.take_error() {
+        Some(e) => return Err(e),
+        None => (),
+    }
+
+    Ok(Materialize {
+        base: base,
+        records: records,
+        spill: spill,
+        len: len,
+        pos: 0,
+        error: None,
+    })
+}
+
+impl<Iter: TableIterator> Materialize<Iter> {
+    pub fn is_spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    pub fn spill_blocks_written(&self) -> uint {
+        self.spill.as_ref().map_or(0, |spill| spill.blocks_written)
+    }
+
+    // Blocks read back from the spill file over all passes and random accesses.
+    pub fn spill_blocks_read(&self) -> uint {
+        self.spill.as_ref().map_or(0, |spill| spill.blocks_read)
+    }
+
+    pub fn spill_blocks(&self) -> uint {
+        self.spill_blocks_written() + self.spill_blocks_read()
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Materialize<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        let pos = self.pos;
+        let values = self.idx(pos);
+        if values.is_some() {
+            self.pos += 1;
+        }
+        values
+    }
+}
+
+impl<Iter: TableIterator> RewindableIterator<Vec<Field>> for Materialize<Iter> {
+    fn rewind(&mut self) {
+        self.pos = 0;
+    }
+}
+
+impl<Iter: TableIterator> RandomAccessIterator<Vec<Field>> for Materialize<Iter> {
+    fn indexable(&self) -> uint {
+        self.len
+    }
+
+    fn idx(&mut self, i: uint) -> Option<Vec<Field>> {
+        if i >= self.len {
+            return None;
+        }
+        let read = match self.spill {
+            Some(ref mut spill) => spill.read_at(i),
+            None => return Some(self.records.get(i).clone()),
+        };
+        match read {
+            Ok(values) => values,
+            Err(e) => {
+                self.error = Some(IoError(e));
+                None
+            },
+        }
+    }
+}
+
+// The input is read once, so its statistics don't grow with the number of passes. Passes over
+// spilled records add to `blocks_accessed` through the spill file.
+impl<Iter: TableIterator> TableIterator for Materialize<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed() + self.spill_blocks()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.error.take()
+    }
+}
+
+// Returns at most `limit` records of its input. Once they are out the input isn't read any
+// further, so a limited table scan only loads the blocks holding them.
+pub struct Limit<Iter> {
+    base: Iter,
+    limit: uint,
+    returned: uint,
+}
+
+pub fn limit<Iter: TableIterator>(base: Iter, limit: uint) -> Limit<Iter> {
+    Limit {
+        base: base,
+        limit: limit,
+        returned: 0,
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Limit<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if self.returned >= self.limit {
+            return None;
+        }
+        let values = self.base.next();
+        if values.is_some() {
+            self.returned += 1;
+        }
+        values
+    }
+}
+
+impl<
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for Limit<Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+        self.returned = 0;
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for Limit<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+// Skips the first `offset` records of its input. They still have to be read, so put filters
+// below it rather than above.
+pub struct Offset<Iter> {
+    base: Iter,
+    offset: uint,
+    skipped: bool,
+}
+
+pub fn offset<Iter: TableIterator>(base: Iter, offset: uint) -> Offset<Iter> {
+    Offset {
+        base: base,
+        offset: offset,
+        skipped: false,
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for Offset<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.skipped {
+            self.skipped = true;
+            for _ in range(0, self.offset) {
+                if self.base.next().is_none() {
+                    return None;
+                }
+            }
+        }
+        self.base.next()
+    }
+}
+
+impl<
+    Iter: TableIterator + RewindableIterator<Vec<Field>>
+> RewindableIterator<Vec<Field>> for Offset<Iter> {
+    fn rewind(&mut self) {
+        self.base.rewind();
+        self.skipped = false;
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for Offset<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+// Records in the heap used by `TopK` are numbered in input order, and ties on `keys` are
+// broken by that number, the way the stable `Sort` would order them.
+fn compare_numbered(keys: &[(uint, SortOrder)], a: &(uint, Vec<Field>), b: &(uint, Vec<Field>))
+        -> Ordering {
+    let (&(a_number, ref a_values), &(b_number, ref b_values)) = (a, b);
+    match compare_records(keys, a_values, b_values) {
+        Equal => a_number.cmp(&b_number),
+        order => order,
+    }
+}
+
+// The heap is a max-heap in that order: its top is the record that would be dropped first.
+fn sift_up(keys: &[(uint, SortOrder)], heap: &mut Vec<(uint, Vec<Field>)>, mut i: uint) {
+    while i > 0 {
+        let parent = (i - 1) / 2;
+        if compare_numbered(keys, heap.get(i), heap.get(parent)) != Greater {
+            return;
+        }
+        heap.as_mut_slice().swap(i, parent);
+        i = parent;
+    }
+}
+
+fn sift_down(keys: &[(uint, SortOrder)], heap: &mut Vec<(uint, Vec<Field>)>, mut i: uint) {
+    loop {
+        let mut largest = i;
+        for child in range(2 * i + 1, min(2 * i + 3, heap.len())) {
+            if compare_numbered(keys, heap.get(child), heap.get(largest)) == Greater {
+                largest = child;
+            }
+        }
+        if largest == i {
+            return;
+        }
+        heap.as_mut_slice().swap(i, largest);
+        i = largest;
+    }
+}
+
+// The first `k` records in the order of `keys`, as `limit(sort(...), k)` would return them, but
+// without sorting the whole input: only the best `k` records so far are kept, in a heap. Ties
+// come out in input order, as they would from `Sort`, so a later record never displaces an
+// earlier one with the same key. The input is still read in full, so `blocks_accessed` is
+// that of one scan with no spill I/O.
+pub struct TopK<Iter> {
+    base: Iter,
+    keys: Vec<(uint, SortOrder)>,
+    k: uint,
+
+    started: bool,
+    // Sorted in reverse, so records can be popped in order.
+    records: Vec<Vec<Field>>,
+}
+
+pub fn top_k<Iter: TableIterator>(base: Iter, keys: Vec<(uint, SortOrder)>, k: uint)
+        -> TopK<Iter> {
+    TopK {
+        base: base,
+        keys: keys,
+        k: k,
+
+        started: false,
+        records: Vec::new(),
+    }
+}
+
+impl<Iter: TableIterator> TopK<Iter> {
+    fn start(&mut self) {
+        let keys = self.keys.as_slice();
+        let mut heap = Vec::with_capacity(self.k);
+        if self.k > 0 {
+            for (number, values) in self.base.by_ref().enumerate() {
+                let record = (number, values);
+                if heap.len() < self.k {
+                    heap.push(record);
+                    let last = heap.len() - 1;
+                    sift_up(keys, &mut heap, last);
+                } else if compare_numbered(keys, &record, heap.get(0)) == Less {
+                    *heap.get_mut(0) = record;
+                    sift_down(keys, &mut heap, 0);
+                }
+            }
+        }
+
+        heap.sort_by(|a, b| compare_numbered(keys, b, a));
+        self.records = heap.move_iter().map(|(_, values)| values).collect();
+    }
+}
+
+impl<Iter: TableIterator> Iterator<Vec<Field>> for TopK<Iter> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        if !self.started {
+            self.started = true;
+            self.start();
+        }
+        self.records.pop()
+    }
+}
+
+impl<Iter: TableIterator> TableIterator for TopK<Iter> {
+    fn blocks_accessed(&self) -> uint {
+        self.base.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.base.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.base.schema()
+    }
+
+    fn take_error(&mut self) -> Option<TableError> {
+        self.base.take_error()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing;
+    use super::super::{
+        AmbiguousFieldError,
+        BTreeIndexType,
+        ExprTypeError,
+        Field,
+        FieldCountError,
+        FieldIndexError,
+        FieldNameError,
+        RewindableIterator,
+        Integer,
+        IntegerType,
+        MemoryBudgetError,
+        NoPrimaryKeyError,
+        Null,
+        OverflowError,
+        Table,
+        TableIterator,
+        Text,
+        TextType,
+        TypeError,
+        Unbounded,
+    };
+    use super::super::expr::{
+        AndExpr,
+        ArithmeticExpr,
+        CallExpr,
+        CompareExpr,
+        ConcatFn,
+        EqualTo,
+        FieldExpr,
+        LessThan,
+        LiteralExpr,
+        MultiplyOp,
+        OrExpr,
+        SubtractOp,
+        ValueType,
+    };
+    use super::{
+        Ascending,
+        Avg,
+        Count,
+        Descending,
+        FullOuterJoin,
+        InnerJoin,
+        JoinKind,
+        LeftOuterJoin,
+        Max,
+        Min,
+        RightOuterJoin,
+        Select,
+        Sum,
+        alias,
+        alias_fields,
+        block_nested_loop_join,
+        block_nested_loop_join_expr,
+        cross,
+        except,
+        hash_aggregate,
+        hash_distinct,
+        hash_join,
+        hash_join_expr,
+        hash_join_kind,
+        index_join,
+        intersect,
+        limit,
+        materialize,
+        merge_join,
+        offset,
+        pk_join,
+        pk_join_expr,
+        pk_join_kind,
+        project,
+        project_exprs,
+        select_expr,
+        select_primary_key,
+        sort,
+        sort_aggregate,
+        sort_distinct,
+        top_k,
+        union,
+        union_all,
+    };
+
+    #[test]
+    fn project_out_of_range() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 5, 2);
+        match project(table.iter(), vec![1, 2]) {
+            Err(FieldIndexError(2, 2)) => (),
+            _ => fail!("expected FieldIndexError"),
+        }
+
+        let records: Vec<Vec<_>> = project(table.iter(), vec![1, 0]).unwrap().take(2).collect();
+        assert_eq!(records, vec![vec![Integer(0), Integer(0)], vec![Integer(1), Integer(1)]]);
+    }
+
+    #[test]
+    fn computed_fields_fit_their_schema() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T", &[("id", IntegerType), ("name", TextType)],
+                                        None, &[vec![Integer(500000000), Text("ab".to_strbuf())]]);
+        let accents: String = range(0, 200).map(|_| 'é').collect();
+        let mut computed = project_exprs(table.iter(), &[
+            ("label", CallExpr(ConcatFn, vec![FieldExpr("name".to_strbuf()),
+                                              LiteralExpr(Text(accents))])),
+            ("scaled", ArithmeticExpr(MultiplyOp, box FieldExpr("id".to_strbuf()),
+                                      box LiteralExpr(Integer(10)))),
+        ]).unwrap();
+        assert_eq!(computed.schema().fields.get(0).length, 256);
+
+        // The label is cut at the last whole character within 255 bytes, and the product
+        // overflows to NULL.
+        let label: String = "ab".chars().chain(range(0, 126).map(|_| 'é')).collect();
+        let records: Vec<Vec<Field>> = computed.by_ref().collect();
+        assert_eq!(records, vec![vec![Text(label), Null]]);
+    }
+
+    #[test]
+    fn select_expr_on_table() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 20, 5);
+        {
+            let condition = OrExpr(
+                box CompareExpr(EqualTo, box FieldExpr("value".to_strbuf()),
+                                box LiteralExpr(Integer(0))),
+                box CompareExpr(LessThan, box FieldExpr("id".to_strbuf()),
+                                box LiteralExpr(Integer(2))));
+            let mut select = select_expr(table.iter(), &condition).unwrap();
+            let ids: Vec<Field> = select.collect_records().unwrap().move_iter()
+                .map(|r| r.get(0).clone())
+                .collect();
+            assert_eq!(ids, [0u32, 1, 5, 10, 15].iter().map(|&id| Integer(id)).collect());
+            assert_eq!(select.records_accessed(), 20);
+        }
+
+        // Names are checked against the table's schema, and values aren't conditions.
+        match select_expr(table.iter(), &FieldExpr("name".to_strbuf())) {
+            Err(FieldNameError(ref name)) => assert_eq!(name.as_slice(), "name"),
+            _ => fail!("expected FieldNameError"),
+        }
+        match select_expr(table.iter(), &FieldExpr("id".to_strbuf())) {
+            Err(ExprTypeError(_, ValueType(IntegerType))) => (),
+            _ => fail!("expected ExprTypeError"),
+        }
+    }
+
+    #[test]
+    fn expression_join_keys() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 10, 5);
+        let mut b = testing::numbers(db.path(), "B", 20, 5);
+
+        // A's key is id - 5, which is NULL below 5 and then matches nothing, and B's is id * 2.
+        let key_a = ArithmeticExpr(SubtractOp, box FieldExpr("id".to_strbuf()),
+                                   box LiteralExpr(Integer(5)));
+        let key_b = ArithmeticExpr(MultiplyOp, box FieldExpr("id".to_strbuf()),
+                                   box LiteralExpr(Integer(2)));
+        let mut rows = hash_join_expr(a.iter(), b.iter(), LeftOuterJoin, &key_a, &key_b, 3,
+                                      db.path()).unwrap().collect_records().unwrap();
+        rows.as_mut_slice().sort();
+        let mut expected: Vec<Vec<Field>> = range(0u32, 10).map(|id| {
+            if id >= 5 && (id - 5) % 2 == 0 {
+                let b_id = (id - 5) / 2;
+                int_row(&[id, id % 5, b_id, b_id % 5])
+            } else {
+                vec![Integer(id), Integer(id % 5), Null, Null]
+            }
+        }).collect();
+        expected.as_mut_slice().sort();
+        assert_eq!(rows, expected);
+
+        // Primary key joins look up the value of the expression.
+        let rows = pk_join_expr(a.iter(), b.iter(), InnerJoin, &key_b).unwrap()
+            .collect_records().unwrap();
+        assert_eq!(rows, range(0u32, 10).map(|id| int_row(&[id, id % 5, id * 2, id * 2 % 5]))
+                                         .collect());
+
+        // Keys are bound to the schema of their own input.
+        match hash_join_expr(a.iter(), b.iter(), InnerJoin, &FieldExpr("B.id".to_strbuf()),
+                             &key_b, 3, db.path()) {
+            Err(FieldNameError(ref name)) => assert_eq!(name.as_slice(), "B.id"),
+            _ => fail!("expected FieldNameError"),
+        }
+    }
+
+    #[test]
+    fn alias_renames_fields_and_primary_key() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 6, 3);
+        let mut b = testing::numbers(db.path(), "B", 6, 3);
+        {
+            let renamed = alias_fields(b.iter(), "c", &["key", "v"]).unwrap();
+            let names: Vec<&str> =
+                renamed.schema().fields.iter().map(|f| f.name.as_slice()).collect();
+            assert_eq!(names, vec!["key", "v"]);
+            assert_eq!(renamed.schema().primary_key, Some("key".to_strbuf()));
+        }
+        match alias_fields(b.iter(), "c", &["key"]) {
+            Err(FieldCountError(1, 2)) => (),
+            _ => fail!("expected FieldCountError"),
+        }
+
+        // A primary key join finds the key of the aliased input under its new name.
+        let mut join = pk_join(a.iter(), alias(b.iter(), "c").unwrap(),
+                               |r| Some(r.get(1).clone())).unwrap();
+        let names: Vec<String> = join.schema().fields.iter().map(|f| f.name.clone()).collect();
+        assert_eq!(names, vec!["A.id".to_strbuf(), "A.value".to_strbuf(),
+                               "c.id".to_strbuf(), "c.value".to_strbuf()]);
+        let rows = join.collect_records().unwrap();
+        assert_eq!(rows, range(0u32, 6).map(|id| int_row(&[id, id % 3, id % 3, id % 3]))
+                                         .collect());
+    }
+
+    #[test]
+    fn self_join_needs_aliases() {
+        let db = testing::scratch_db();
+        let mut t1 = testing::numbers(db.path(), "T", 6, 3);
+        let mut t2 = Table::open(db.path(), "T").unwrap();
+
+        // Records of T with the same value as a later record.
+        let condition = AndExpr(
+            box CompareExpr(EqualTo, box FieldExpr("a.value".to_strbuf()),
+                            box FieldExpr("b.value".to_strbuf())),
+            box CompareExpr(LessThan, box FieldExpr("a.id".to_strbuf()),
+                            box FieldExpr("b.id".to_strbuf())));
+
+        // Without aliases, both sides' fields go by the same unqualified names.
+        let unaliased = CompareExpr(EqualTo, box FieldExpr("value".to_strbuf()),
+                                    box FieldExpr("T.id".to_strbuf()));
+        match block_nested_loop_join_expr(t1.iter(), t2.iter(), InnerJoin, 1, &unaliased) {
+            Err(AmbiguousFieldError(ref name)) => assert_eq!(name.as_slice(), "value"),
+            _ => fail!("expected AmbiguousFieldError"),
+        }
+
+        let mut join = block_nested_loop_join_expr(alias(t1.iter(), "a").unwrap(),
+                                                   alias(t2.iter(), "b").unwrap(),
+                                                   InnerJoin, 1, &condition).unwrap();
+        let mut rows = join.collect_records().unwrap();
+        rows.as_mut_slice().sort();
+        assert_eq!(rows, vec![int_row(&[0, 0, 3, 0]), int_row(&[1, 1, 4, 1]),
+                              int_row(&[2, 2, 5, 2])]);
+    }
+
+    #[test]
+    fn index_join_on_non_unique_keys() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 3, 3);
+        let mut b = testing::numbers(db.path(), "B", 30, 3);
+        b.create_index("value_btree", "value", BTreeIndexType).unwrap();
+        let mut join = index_join(a.iter(),
+                                  b.index_scan("value_btree", Unbounded, Unbounded).unwrap(),
+                                  |r| Some(r.get(0).clone()));
+        let rows: Vec<Vec<Field>> = join.by_ref().collect();
+
+        // Every record of A matches the ten records of B with its id as value.
+        assert_eq!(rows.len(), 30);
+        assert!(rows.iter().all(|r| r.get(0) == r.get(3)));
+        for id in range(0, 3u32) {
+            let ids: Vec<Field> = rows.iter()
+                .filter(|r| *r.get(0) == Integer(id))
+                .map(|r| r.get(2).clone())
+                .collect();
+            assert_eq!(ids, range(0, 10u32).map(|j| Integer(j * 3 + id)).collect());
+        }
+
+        // One leaf per probe, and A's block plus the three blocks of B on each probe.
+        assert_eq!(join.index_blocks_accessed(), 3);
+        assert_eq!(join.data_blocks_accessed(), 1 + 3 * 3);
+        assert_eq!(join.records_accessed(), 3 + 30);
+    }
+
+    #[test]
+    fn external_sort_cost() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 7);
+        let mut sorted = sort(table.iter(), vec![(1, Descending)], 3, db.path()).unwrap();
+        let records = sorted.collect_records().unwrap();
+        assert_eq!(records.len(), 100);
+        for pair in records.as_slice().windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert!(a.get(1) > b.get(1) || (a.get(1) == b.get(1) && a.get(0) < b.get(0)));
+        }
+
+        // 3 blocks of memory give runs of 30 records, merged 2 at a time. The 4 runs are
+        // written (10 blocks), merged into 2 (10 read, 10 written), and those are merged on
+        // the fly (10 read), on top of the 10 blocks of the input.
+        assert_eq!(sorted.runs_created, 6);
+        assert_eq!(sorted.merge_passes, 2);
+        assert_eq!(sorted.spill_blocks(), 40);
+        assert_eq!(sorted.blocks_accessed(), 50);
+        assert_eq!(sorted.records_accessed(), 100);
+    }
+
+    #[test]
+    fn in_memory_sort_cost() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 7);
+        let mut sorted = sort(table.iter(), vec![(1, Descending)], 11, db.path()).unwrap();
+        assert_eq!(sorted.by_ref().count(), 100);
+        assert!(sorted.take_error().is_none());
+        assert_eq!(sorted.runs_created, 0);
+        assert_eq!(sorted.blocks_accessed(), 10);
+    }
+
+    #[test]
+    fn sort_memory_budget() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 10, 7);
+        match sort(table.iter(), vec![(1, Descending)], 2, db.path()) {
+            Err(MemoryBudgetError(_, 3)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn in_memory_hash_join_cost() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 40, 7);
+        let mut b = testing::numbers(db.path(), "B", 20, 7);
+        let mut join = hash_join(a.iter(), b.iter(),
+                                 |r| Some(r.get(0).clone()), |r| Some(r.get(0).clone()),
+                                 5, db.path()).unwrap();
+        assert_eq!(join.by_ref().count(), 20);
+
+        // B runs out within the 50 records of memory, so it's the build side and both inputs
+        // are read once.
+        assert_eq!(join.partitions_created, 0);
+        assert_eq!(join.blocks_accessed(), 4 + 2);
+        assert_eq!(join.records_accessed(), 40 + 20);
+    }
+
+    #[test]
+    fn grace_hash_join_cost() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 100, 7);
+        let mut b = testing::numbers(db.path(), "B", 100, 7);
+        let mut join = hash_join(a.iter(), b.iter(),
+                                 |r| Some(r.get(0).clone()), |r| Some(r.get(0).clone()),
+                                 6, db.path()).unwrap();
+        assert_eq!(join.collect_records().unwrap().len(), 100);
+
+        // Neither input fits, so both go to 5 partitions each, small enough to be joined
+        // without splitting them again. Every partition is written once and read once: at
+        // least the 20 blocks of the inputs each way, plus a partial block per partition.
+        assert_eq!(join.partitions_created, 10);
+        let spill_blocks = join.spill_blocks();
+        assert!(spill_blocks >= 2 * 20 && spill_blocks <= 2 * (20 + 10));
+        assert_eq!(join.blocks_accessed(), 10 + 10 + spill_blocks);
+        assert_eq!(join.records_accessed(), 100 + 100);
+    }
+
+    #[test]
+    fn hash_join_memory_budget() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 10, 7);
+        let mut b = testing::numbers(db.path(), "B", 10, 7);
+        match hash_join(a.iter(), b.iter(),
+                        |r| Some(r.get(0).clone()), |r| Some(r.get(0).clone()),
+                        2, db.path()) {
+            Err(MemoryBudgetError(_, 3)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn merge_join_duplicate_keys() {
+        let db = testing::scratch_db();
+        let fields = &[("id", IntegerType), ("key", IntegerType)];
+        let a_records: Vec<Vec<Field>> =
+            range(0u32, 25).map(|id| int_row(&[id, id / 5])).collect();
+        let b_records: Vec<Vec<Field>> =
+            range(0u32, 30).map(|id| int_row(&[id, id / 3])).collect();
+        let mut a = testing::create(db.path(), "A", fields, Some("id"), a_records.as_slice());
+        let mut b = testing::create(db.path(), "B", fields, Some("id"), b_records.as_slice());
+        let mut join = merge_join(a.iter(), 1, b.iter(), 1);
+        let rows = join.collect_records().unwrap();
+
+        // Keys 0 to 4 have five records in A and three in B, and each record of A is paired
+        // with the whole group of B in order.
+        let mut expected = Vec::new();
+        for id_a in range(0u32, 25) {
+            let key = id_a / 5;
+            for id_b in range(key * 3, key * 3 + 3) {
+                expected.push(int_row(&[id_a, key, id_b, key]));
+            }
+        }
+        assert_eq!(rows, expected);
+
+        // A is read whole, but B only up to the first record past key 4, in its second block.
+        assert_eq!(join.blocks_accessed(), 3 + 2);
+        assert_eq!(join.records_accessed(), 25 + 16);
+    }
+
+    #[test]
+    fn block_nested_loop_join_cost() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 40, 7);
+        let mut b = testing::numbers(db.path(), "B", 30, 7);
+        let mut join = block_nested_loop_join(a.iter(), b.iter(), 2,
+                                              |x, y| x.get(0) == y.get(0)).unwrap();
+        assert_eq!(join.by_ref().count(), 30);
+
+        // 2 buffers of 2 blocks each, with a scan of B for each: B_a + 2 B_b blocks, and no
+        // scan once A has run out.
+        assert_eq!(join.blocks_accessed(), 4 + 2 * 3);
+        assert_eq!(join.records_accessed(), 40 + 2 * 30);
+    }
+
+    #[test]
+    fn block_nested_loop_join_memory_budget() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 10, 7);
+        let mut b = testing::numbers(db.path(), "B", 10, 7);
+        match block_nested_loop_join(a.iter(), b.iter(), 0, |x, y| x.get(0) == y.get(0)) {
+            Err(MemoryBudgetError(_, 1)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn aggregates_skip_nulls() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 10, 5);
+        let mut b = testing::numbers(db.path(), "B", 4, 3);
+        let aggregates = vec![Count, Sum(3), Avg(3), Min(3), Max(3)];
+        let rows: Vec<Vec<Field>> = {
+            let join = hash_join_kind(a.iter(), b.iter(), LeftOuterJoin,
+                                      |r| Some(r.get(0).clone()), |r| Some(r.get(0).clone()),
+                                      3, db.path()).unwrap();
+            sort_aggregate(join, vec![1], aggregates.clone(), 3, db.path()).unwrap().collect()
+        };
+
+        // Each group has two records of A, of which those with an id below 4 found a match.
+        // COUNT(*) counts the others too, but they are left out of everything else, and a
+        // group with nothing but NULLs gets NULL.
+        fn row(key: u32, value: Field) -> Vec<Field> {
+            vec![Integer(key), Integer(2), value.clone(), value.clone(), value.clone(), value]
+        }
+        assert_eq!(rows, vec![row(0, Integer(0)), row(1, Integer(1)), row(2, Integer(2)),
+                              row(3, Integer(0)), row(4, Null)]);
+
+        let join = hash_join_kind(a.iter(), b.iter(), LeftOuterJoin,
+                                  |r| Some(r.get(0).clone()), |r| Some(r.get(0).clone()),
+                                  3, db.path()).unwrap();
+        let mut hashed: Vec<Vec<Field>> =
+            hash_aggregate(join, vec![1], aggregates, 2, db.path()).unwrap().collect();
+        hashed.as_mut_slice().sort();
+        assert_eq!(hashed, rows);
+    }
+
+    #[test]
+    fn in_memory_hash_distinct() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 7);
+        let mut distinct = hash_distinct(table.iter(), Some(vec![1]), 11, db.path()).unwrap();
+
+        // The first record of each value is kept whole, in input order.
+        let rows = distinct.collect_records().unwrap();
+        assert_eq!(rows, range(0u32, 7).map(|id| int_row(&[id, id])).collect());
+        assert_eq!(distinct.partitions_created, 0);
+        assert_eq!(distinct.blocks_accessed(), 10);
+    }
+
+    #[test]
+    fn spilled_hash_distinct() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 30);
+        {
+            let mut distinct =
+                hash_distinct(table.iter(), Some(vec![1]), 2, db.path()).unwrap();
+
+            // The first 20 values fill memory. The 30 records with the other 10 values go to a
+            // single partition (3 blocks written and read back), whose keys come out after.
+            let rows = distinct.collect_records().unwrap();
+            assert_eq!(rows, range(0u32, 30).map(|id| int_row(&[id, id])).collect());
+            assert_eq!(distinct.partitions_created, 1);
+            assert_eq!(distinct.spill_blocks(), 6);
+            assert_eq!(distinct.blocks_accessed(), 10 + 6);
+        }
+
+        match hash_distinct(table.iter(), None, 1, db.path()) {
+            Err(MemoryBudgetError(_, 2)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn sort_distinct_on_some_fields() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 7);
+        let expected: Vec<Vec<Field>> = range(0u32, 7).map(|id| int_row(&[id, id])).collect();
+
+        // Equal values keep their input order, so the first record of each is kept, whether
+        // the sort spills or not.
+        {
+            let mut spilled = sort_distinct(table.iter(), Some(vec![1]), 3, db.path()).unwrap();
+            assert_eq!(spilled.collect_records().unwrap(), expected);
+            assert!(spilled.runs_created() > 0);
+        }
+        let mut in_memory = sort_distinct(table.iter(), Some(vec![1]), 11, db.path()).unwrap();
+        assert_eq!(in_memory.collect_records().unwrap(), expected);
+        assert_eq!(in_memory.runs_created(), 0);
+        assert_eq!(in_memory.blocks_accessed(), 10);
+    }
+
+    #[test]
+    fn sum_overflow() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T", &[("value", IntegerType)], None,
+                                        &[vec![Integer(4000000000)], vec![Integer(1000000000)]]);
+        {
+            let mut sum =
+                hash_aggregate(table.iter(), vec![], vec![Sum(0)], 2, db.path()).unwrap();
+            match sum.collect_records() {
+                Err(OverflowError(ref name)) => assert_eq!(name.as_slice(), "SUM(value)"),
+                _ => fail!("expected OverflowError"),
+            }
+        }
+
+        let avg: Vec<Vec<Field>> =
+            sort_aggregate(table.iter(), vec![], vec![Avg(0)], 3, db.path()).unwrap().collect();
+        assert_eq!(avg, vec![vec![Integer(2500000000)]]);
+    }
+
+    #[test]
+    fn sum_overflow_ends_sorted_groups() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T",
+                                        &[("key", IntegerType), ("value", IntegerType)], None,
+                                        &[int_row(&[2, 1]), int_row(&[1, 4000000000]),
+                                          int_row(&[0, 1]), int_row(&[1, 1000000000])]);
+        let mut sum =
+            sort_aggregate(table.iter(), vec![0], vec![Sum(1)], 3, db.path()).unwrap();
+
+        // Groups before the overflowing one come out, and none after it.
+        let rows: Vec<Vec<Field>> = sum.by_ref().collect();
+        assert_eq!(rows, vec![int_row(&[0, 1])]);
+        match sum.take_error() {
+            Some(OverflowError(ref name)) => assert_eq!(name.as_slice(), "SUM(value)"),
+            _ => fail!("expected OverflowError"),
+        }
+        assert!(sum.next().is_none());
+        assert!(sum.take_error().is_none());
+    }
+
+    #[test]
+    fn hash_aggregate_memory_budget() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 10, 7);
+        match hash_aggregate(table.iter(), vec![1], vec![Count], 1, db.path()) {
+            Err(MemoryBudgetError(_, 2)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn sum_over_text() {
+        let db = testing::scratch_db();
+        let mut table = testing::create(db.path(), "T", &[("name", TextType)], None, &[]);
+        match sort_aggregate(table.iter(), vec![], vec![Avg(0)], 3, db.path()) {
+            Err(TypeError(0, TextType, IntegerType)) => (),
+            _ => fail!("expected TypeError"),
+        }
+    }
+
+    // The id of a record, or NULL for id 0.
+    fn id_or_null(r: &Vec<Field>) -> Option<Field> {
+        if *r.get(0) == Integer(0) { Some(Null) } else { Some(r.get(0).clone()) }
+    }
+
+    // Joins A (ids 0 to 4) with B (ids 3 to 7) on their ids, except that record 0 of A has a
+    // NULL key.
+    fn pk_join_rows(kind: JoinKind) -> Vec<Vec<Field>> {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 5, 5);
+        let b_records: Vec<Vec<Field>> =
+            range(3u32, 8).map(|id| vec![Integer(id), Integer(id * 10)]).collect();
+        let mut b = testing::create(db.path(), "B", &[("id", IntegerType), ("value", IntegerType)],
+                                    Some("id"), b_records.as_slice());
+        pk_join_kind(a.iter(), b.iter(), kind, |r| id_or_null(r)).unwrap().collect()
+    }
+
+    fn int_row(values: &[u32]) -> Vec<Field> {
+        values.iter().map(|&x| Integer(x)).collect()
+    }
+
+    #[test]
+    fn pk_outer_joins() {
+        let a_unmatched: Vec<Vec<Field>> = range(0u32, 3)
+            .map(|id| vec![Integer(id), Integer(id), Null, Null]).collect();
+        let matched = vec![int_row(&[3, 3, 3, 30]), int_row(&[4, 4, 4, 40])];
+        let b_unmatched: Vec<Vec<Field>> = range(5u32, 8)
+            .map(|id| vec![Null, Null, Integer(id), Integer(id * 10)]).collect();
+
+        assert_eq!(pk_join_rows(LeftOuterJoin), a_unmatched + matched);
+        assert_eq!(pk_join_rows(RightOuterJoin), matched + b_unmatched);
+        assert_eq!(pk_join_rows(FullOuterJoin), a_unmatched + matched + b_unmatched);
+    }
+
+    #[test]
+    fn pk_outer_join_without_primary_key() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 5, 5);
+        let mut b = testing::create(db.path(), "B", &[("id", IntegerType)], None, &[]);
+        match pk_join_kind(a.iter(), b.iter(), RightOuterJoin, |r| Some(r.get(0).clone())) {
+            Err(NoPrimaryKeyError(ref name)) => assert_eq!(name.as_slice(), "B"),
+            _ => fail!("expected NoPrimaryKeyError"),
+        }
+    }
+
+    #[test]
+    fn null_keys_never_match() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 3, 3);
+        let mut b = testing::numbers(db.path(), "B", 3, 3);
+        let mut rows: Vec<Vec<Field>> = hash_join_kind(a.iter(), b.iter(), FullOuterJoin,
+                                                       |r| id_or_null(r), |r| id_or_null(r),
+                                                       3, db.path()).unwrap().collect();
+        rows.as_mut_slice().sort();
+
+        let mut expected = vec![vec![Integer(0), Integer(0), Null, Null],
+                                vec![Null, Null, Integer(0), Integer(0)],
+                                int_row(&[1, 1, 1, 1]),
+                                int_row(&[2, 2, 2, 2])];
+        expected.as_mut_slice().sort();
+        assert_eq!(rows, expected);
+    }
+
+    // Rows returned by a full pass over the iterator, and the blocks and records it accessed.
+    fn pass<Iter: TableIterator>(iter: &mut Iter) -> (uint, uint, uint) {
+        let (blocks, records) = (iter.blocks_accessed(), iter.records_accessed());
+        let rows = iter.by_ref().count();
+        (rows, iter.blocks_accessed() - blocks, iter.records_accessed() - records)
+    }
+
+    #[test]
+    fn rewind_select() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 30, 3);
+        let mut select = Select { base: table.iter(), condition: |r| *r.get(1) == Integer(0) };
+        assert_eq!(pass(&mut select), (10, 3, 30));
+        select.rewind();
+        assert_eq!(pass(&mut select), (10, 3, 30));
+    }
+
+    #[test]
+    fn rewind_cross_join() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 20, 3);
+        let mut b = testing::numbers(db.path(), "B", 30, 3);
+        let mut join = cross(a.iter(), b.iter());
+        // A once, and B once per record of A.
+        assert_eq!(pass(&mut join), (600, 2 + 20 * 3, 20 + 20 * 30));
+        join.rewind();
+        assert_eq!(pass(&mut join), (600, 2 + 20 * 3, 20 + 20 * 30));
+    }
+
+    #[test]
+    fn rewind_select_primary_key() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 20, 3);
+        let mut select = select_primary_key(table.iter(), Some(Integer(15))).unwrap();
+        // A leaf of the primary key index and the record's block.
+        assert_eq!(pass(&mut select), (1, 2, 1));
+        // The lookup is repeated, but the record's block is still loaded.
+        select.rewind();
+        assert_eq!(pass(&mut select), (1, 1, 1));
+        select.rewind();
+        assert_eq!(pass(&mut select), (1, 1, 1));
+    }
+
+    #[test]
+    fn rewind_pk_join() {
+        let db = testing::scratch_db();
+        let mut a = testing::numbers(db.path(), "A", 20, 3);
+        let mut b = testing::numbers(db.path(), "B", 30, 3);
+        let mut join = pk_join_kind(a.iter(), b.iter(), FullOuterJoin,
+                                    |r| Some(r.get(0).clone())).unwrap();
+        // A once, then for each of its records an index leaf of B and, the first time, the
+        // block holding the match. The final scan of B reads it all again.
+        let expected = (30, 2 + (20 + 2) + 3, 20 + 20 + 30);
+        assert_eq!(pass(&mut join), expected);
+        join.rewind();
+        assert_eq!(pass(&mut join), expected);
+    }
+
+    #[test]
+    fn limit_stops_early() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 100, 10);
+        {
+            let mut first = limit(table.iter(), 15);
+            assert_eq!(pass(&mut first), (15, 2, 15));
+        }
+
+        // Below a filter, the input is read up to the last record returned, id 20.
+        let select = Select { base: table.iter(), condition: |r| *r.get(1) == Integer(0) };
+        let mut first = limit(select, 3);
+        assert_eq!(pass(&mut first), (3, 3, 21));
+    }
+
+    #[test]
+    fn offset_skips_records() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 30, 3);
+        {
+            // The skipped records are still read.
+            let mut rest = offset(table.iter(), 25);
+            assert_eq!(pass(&mut rest), (5, 3, 30));
+        }
+        {
+            let rest = offset(table.iter(), 25).collect_records().unwrap();
+            assert_eq!(rest, range(25u32, 30).map(|id| int_row(&[id, id % 3])).collect());
+        }
+        {
+            let mut paged = limit(offset(table.iter(), 10), 10);
+            assert_eq!(paged.collect_records().unwrap(),
+                       range(10u32, 20).map(|id| int_row(&[id, id % 3])).collect());
+        }
+        let mut past_end = offset(table.iter(), 40);
+        assert_eq!(pass(&mut past_end), (0, 3, 30));
+    }
+
+    #[test]
+    fn top_k_matches_limited_sort() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 30, 4);
+        {
+            // Only one scan, and ties on value come out in input order.
+            let mut top = top_k(table.iter(), vec![(1, Descending)], 5);
+            assert_eq!(pass(&mut top), (5, 3, 30));
+        }
+        for &k in [0u, 1, 5, 8, 30, 40].iter() {
+            for keys in [vec![(1, Descending)], vec![(1, Ascending), (0, Descending)]].iter() {
+                let top = top_k(table.iter(), keys.clone(), k).collect_records().unwrap();
+                let sorted = sort(table.iter(), keys.clone(), 3, db.path()).unwrap();
+                assert_eq!(top, limit(sorted, k).collect_records().unwrap());
+            }
+        }
+        let top = top_k(table.iter(), vec![(1, Descending)], 5).collect_records().unwrap();
+        assert_eq!(top, [3u32, 7, 11, 15, 19].iter().map(|&id| int_row(&[id, 3])).collect());
+    }
+
+    #[test]
+    fn rewind_materialize_in_memory() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 30, 3);
+        let mut stored = materialize(table.iter(), 3, db.path()).unwrap();
+        assert!(!stored.is_spilled());
+        // The input was read whole up front, and passes don't read anything more.
+        assert_eq!(stored.blocks_accessed(), 3);
+        let first = stored.collect_records().unwrap();
+        assert_eq!(first, range(0u32, 30).map(|id| int_row(&[id, id % 3])).collect());
+        stored.rewind();
+        assert_eq!(pass(&mut stored), (30, 0, 0));
+        stored.rewind();
+        assert_eq!(stored.collect_records().unwrap(), first);
+    }
+
+    #[test]
+    fn rewind_materialize_spilled() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 30, 3);
+        {
+            let mut stored = materialize(table.iter(), 2, db.path()).unwrap();
+            assert!(stored.is_spilled());
+            // The input, then the 3 blocks of the spill file written up front.
+            assert_eq!(stored.blocks_accessed(), 3 + 3);
+            let first = stored.collect_records().unwrap();
+            assert_eq!(first, range(0u32, 30).map(|id| int_row(&[id, id % 3])).collect());
+            // Every pass reads the spill file back, but not the input.
+            stored.rewind();
+            assert_eq!(pass(&mut stored), (30, 3, 0));
+            stored.rewind();
+            assert_eq!(stored.collect_records().unwrap(), first);
+            assert_eq!(stored.spill_blocks_read(), 3 * 3);
+        }
+
+        match materialize(table.iter(), 0, db.path()) {
+            Err(MemoryBudgetError(_, 1)) => (),
+            _ => fail!("expected MemoryBudgetError"),
+        }
+    }
+
+    #[test]
+    fn set_operations_with_duplicates() {
+        let db = testing::scratch_db();
+        let a_records: Vec<Vec<Field>> =
+            [1u32, 1, 2, 3, 3, 3, 5].iter().map(|&x| int_row(&[x])).collect();
+        let b_records: Vec<Vec<Field>> =
+            [3u32, 3, 1, 4, 4].iter().map(|&x| int_row(&[x])).collect();
+        let mut a = testing::create(db.path(), "A", &[("value", IntegerType)], None,
+                                    a_records.as_slice());
+        let mut b = testing::create(db.path(), "B", &[("value", IntegerType)], None,
+                                    b_records.as_slice());
+
+        // Each value comes out once, however often it's repeated on either side.
+        let common = intersect(a.iter(), b.iter(), 3, db.path()).unwrap()
+            .collect_records().unwrap();
+        assert_eq!(common, vec![int_row(&[1]), int_row(&[3])]);
+        let only_a = except(a.iter(), b.iter(), 3, db.path()).unwrap()
+            .collect_records().unwrap();
+        assert_eq!(only_a, vec![int_row(&[2]), int_row(&[5])]);
+        let either = union(a.iter(), b.iter(), 3, db.path()).unwrap()
+            .collect_records().unwrap();
+        assert_eq!(either, range(1u32, 6).map(|x| int_row(&[x])).collect());
+
+        // UNION ALL keeps every record, in input order.
+        let all = union_all(a.iter(), b.iter()).unwrap().collect_records().unwrap();
+        assert_eq!(all, a_records + b_records);
+    }
+
+    #[test]
+    fn set_operations_with_null_rows() {
+        let db = testing::scratch_db();
+        let b_records: Vec<Vec<Field>> =
+            range(3u32, 8).map(|id| vec![Integer(id), Integer(id * 10)]).collect();
+        let fields = &[("id", IntegerType), ("value", IntegerType)];
+        let mut a = testing::numbers(db.path(), "A", 5, 5);
+        let mut b1 = testing::create(db.path(), "B1", fields, Some("id"), b_records.as_slice());
+        let mut c = testing::numbers(db.path(), "C", 2, 2);
+        let mut b2 = testing::create(db.path(), "B2", fields, Some("id"), b_records.as_slice());
+
+        // X holds the ids of B matched by A in a left outer join: NULL three times, 3 and 4.
+        // Y holds those matched by C, which are both NULL. Unlike join keys, NULLs count as
+        // equal here, and sort after every value.
+        let common = {
+            let x = project(pk_join_kind(a.iter(), b1.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            let y = project(pk_join_kind(c.iter(), b2.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            intersect(x, y, 3, db.path()).unwrap().collect_records().unwrap()
+        };
+        assert_eq!(common, vec![vec![Null]]);
+
+        let only_x = {
+            let x = project(pk_join_kind(a.iter(), b1.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            let y = project(pk_join_kind(c.iter(), b2.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            except(x, y, 3, db.path()).unwrap().collect_records().unwrap()
+        };
+        assert_eq!(only_x, vec![int_row(&[3]), int_row(&[4])]);
+
+        let only_y = {
+            let x = project(pk_join_kind(a.iter(), b1.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            let y = project(pk_join_kind(c.iter(), b2.iter(), LeftOuterJoin,
+                                         |r| Some(r.get(0).clone())).unwrap(), vec![2]).unwrap();
+            except(y, x, 3, db.path()).unwrap().collect_records().unwrap()
+        };
+        assert!(only_y.is_empty());
     }
 }
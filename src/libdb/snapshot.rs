@@ -0,0 +1,82 @@
+use std::io::fs;
+use std::io;
+
+static CLEAN : u8 = 0;
+static DIRTY : u8 = 1;
+
+// A file holding a snapshot of a structure that lives in memory and is written out as a whole,
+// such as a bitmap index.
+//
+// Snapshots replace the file atomically. Its first byte marks whether changes were made in
+// memory since, and is set on disk before the first of them, so a snapshot that missed changes
+// because of a crash or a failed write is recognized when it is opened again.
+pub struct SnapshotFile {
+    path: Path,
+    dirty: bool,
+    // Whether the file was already dirty when opened.
+    pub stale: bool,
+}
+
+impl SnapshotFile {
+    // For a structure that hasn't been written yet.
+    pub fn new(path: &Path) -> SnapshotFile {
+        SnapshotFile {
+            path: path.clone(),
+            dirty: true,
+            stale: false,
+        }
+    }
+
+    // Returns a reader positioned at the start of the snapshot.
+    pub fn open(path: &Path) -> io::IoResult<(SnapshotFile, io::BufferedReader<fs::File>)> {
+        let mut reader = io::BufferedReader::new(try!(fs::File::open(path)));
+        let stale = match try!(reader.read_u8()) {
+            CLEAN => false,
+            DIRTY => true,
+            _ => return Err(io::standard_error(io::InvalidInput)),
+        };
+
+        let file = SnapshotFile {
+            path: path.clone(),
+            dirty: stale,
+            stale: stale,
+        };
+        Ok((file, reader))
+    }
+
+    // Must be called before changing the structure in memory.
+    pub fn mark_dirty(&mut self) -> io::IoResult<()> {
+        if self.dirty {
+            return Ok(());
+        }
+
+        let mut file = try!(fs::File::open_mode(&self.path, io::Open, io::Write));
+        try!(file.write_u8(DIRTY));
+        try!(file.fsync());
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Replaces the file with a snapshot written by `contents`, unless nothing changed since the
+    // last one.
+    pub fn write(&mut self, contents: |&mut io::BufferedWriter<fs::File>| -> io::IoResult<()>)
+            -> io::IoResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let tmp_path = Path::new(format!("{}.tmp", self.path.display()));
+        {
+            let mut writer = io::BufferedWriter::new(try!(fs::File::create(&tmp_path)));
+            try!(writer.write_u8(CLEAN));
+            try!(contents(&mut writer));
+            try!(writer.flush());
+            try!(writer.get_mut_ref().fsync());
+        }
+        try!(fs::rename(&tmp_path, &self.path));
+
+        self.dirty = false;
+        self.stale = false;
+        Ok(())
+    }
+}
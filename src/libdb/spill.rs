@@ -0,0 +1,184 @@
+use std::io::fs;
+use std::io;
+use std::os;
+use std::str;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+
+use super::{
+    BLOCK_SIZE,
+    Field,
+    Integer,
+    Null,
+    Text,
+};
+
+static mut NEXT_SPILL_ID : AtomicUint = INIT_ATOMIC_UINT;
+
+fn corrupt_spill() -> io::IoError {
+    io::IoError {
+        kind: io::InvalidInput,
+        desc: "spill file contains invalid data",
+        detail: None,
+    }
+}
+
+// A temporary file holding records that don't fit in an operator's memory budget. Records are
+// written in one go, then read back any number of times, sequentially or by position. The file
+// lives in the `tmp` directory of the database and is removed when dropped.
+//
+// Blocks are counted as BLOCK_SIZE records, like in the table files, so spill I/O can be added
+// to an operator's `blocks_accessed`.
+pub struct SpillFile {
+    path: Path,
+    writer: Option<io::BufferedWriter<fs::File>>,
+    reader: Option<io::BufferedReader<fs::File>>,
+
+    pub records: uint,
+    records_read: uint,
+    // Byte offset of each record, for reading by position.
+    offsets: Vec<u64>,
+    bytes_written: u64,
+    current_block: Option<uint>,
+    pub blocks_written: uint,
+    pub blocks_read: uint,
+}
+
+impl SpillFile {
+    pub fn create(db_path: &Path) -> io::IoResult<SpillFile> {
+        let tmp_path = db_path.join("tmp");
+        if !tmp_path.exists() {
+            try!(fs::mkdir_recursive(&tmp_path, io::UserDir));
+        }
+
+        let id = unsafe { NEXT_SPILL_ID.fetch_add(1, SeqCst) };
+        let path = tmp_path.join(format!("spill-{}-{}.bin", os::getpid(), id).as_slice());
+        let file = try!(fs::File::create(&path));
+
+        Ok(SpillFile {
+            path: path,
+            writer: Some(io::BufferedWriter::new(file)),
+            reader: None,
+
+            records: 0,
+            records_read: 0,
+            offsets: Vec::new(),
+            bytes_written: 0,
+            current_block: None,
+            blocks_written: 0,
+            blocks_read: 0,
+        })
+    }
+
+    pub fn write(&mut self, values: &[Field]) -> io::IoResult<()> {
+        let writer = match self.writer {
+            Some(ref mut w) => w,
+            None => fail!("Spill file was already read from."),
+        };
+
+        let mut size = 4;
+        try!(writer.write_be_u32(values.len() as u32));
+        for value in values.iter() {
+            match *value {
+                Integer(x) => {
+                    try!(writer.write_u8(0));
+                    try!(writer.write_be_u32(x));
+                    size += 5;
+                },
+                Text(ref s) => {
+                    try!(writer.write_u8(1));
+                    try!(writer.write_be_u32(s.len() as u32));
+                    try!(writer.write_str(s.as_slice()));
+                    size += 5 + s.len() as u64;
+                },
+                Null => {
+                    try!(writer.write_u8(2));
+                    size += 1;
+                },
+            }
+        }
+        self.offsets.push(self.bytes_written);
+        self.bytes_written += size;
+
+        if self.records % BLOCK_SIZE == 0 {
+            self.blocks_written += 1;
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    // Starts reading from the first record. Ends the writing phase if still in it.
+    pub fn rewind(&mut self) -> io::IoResult<()> {
+        match self.writer.take() {
+            Some(mut w) => try!(w.flush()),
+            None => (),
+        }
+        self.reader = Some(io::BufferedReader::new(try!(fs::File::open(&self.path))));
+        self.records_read = 0;
+        self.current_block = None;
+        Ok(())
+    }
+
+    // Reads the record at position `i`, after which reading continues from `i + 1`. Only seeks
+    // if `i` isn't the next record anyway.
+    pub fn read_at(&mut self, i: uint) -> io::IoResult<Option<Vec<Field>>> {
+        if self.reader.is_none() {
+            try!(self.rewind());
+        }
+        if i >= self.records {
+            return Ok(None);
+        }
+
+        if i != self.records_read {
+            let mut file = self.reader.take().unwrap().unwrap();
+            try!(file.seek(*self.offsets.get(i) as i64, io::SeekSet));
+            self.reader = Some(io::BufferedReader::new(file));
+            self.records_read = i;
+        }
+        self.read()
+    }
+
+    pub fn read(&mut self) -> io::IoResult<Option<Vec<Field>>> {
+        if self.reader.is_none() {
+            try!(self.rewind());
+        }
+        if self.records_read == self.records {
+            return Ok(None);
+        }
+
+        let reader = self.reader.get_mut_ref();
+        let len = try!(reader.read_be_u32()) as uint;
+        let mut values = Vec::with_capacity(len);
+        for _ in range(0, len) {
+            values.push(match try!(reader.read_u8()) {
+                0 => Integer(try!(reader.read_be_u32())),
+                1 => {
+                    let text_len = try!(reader.read_be_u32()) as uint;
+                    let buf = try!(reader.read_exact(text_len));
+                    match str::from_utf8(buf.as_slice()) {
+                        Some(s) => Text(s.to_strbuf()),
+                        None => return Err(corrupt_spill()),
+                    }
+                },
+                2 => Null,
+                _ => return Err(corrupt_spill()),
+            });
+        }
+
+        // Rereading the block of the previous record is free, like in the table files.
+        let block = self.records_read / BLOCK_SIZE;
+        if self.current_block != Some(block) {
+            self.current_block = Some(block);
+            self.blocks_read += 1;
+        }
+        self.records_read += 1;
+        Ok(Some(values))
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        self.writer = None;
+        self.reader = None;
+        let _ = fs::unlink(&self.path);
+    }
+}
@@ -0,0 +1,59 @@
+// Scratch databases for the tests.
+use std::io::TempDir;
+
+use super::{
+    Field,
+    FieldSchema,
+    FieldType,
+    Integer,
+    IntegerType,
+    Table,
+    TableSchema,
+    TextType,
+    create_table,
+};
+
+pub fn scratch_db() -> TempDir {
+    TempDir::new("libdb-test").unwrap()
+}
+
+// Creates a table with the given fields and records. Text fields are 20 bytes long.
+pub fn create(db_path: &Path, name: &str, fields: &[(&str, FieldType)],
+              primary_key: Option<&str>, records: &[Vec<Field>]) -> Table {
+    let mut offset = 0;
+    let fields = fields.iter().map(|&(field_name, data_type)| {
+        let length = match data_type { IntegerType => 4, TextType => 20 };
+        let field = FieldSchema {
+            name: field_name.to_strbuf(),
+            offset: offset,
+            data_type: data_type,
+            length: length,
+            auto_increment: None,
+        };
+        offset += length;
+        field
+    }).collect();
+
+    let schema = TableSchema {
+        name: name.to_strbuf(),
+        fields: fields,
+        entry_stride: offset,
+        primary_key: primary_key.map(|key| key.to_strbuf()),
+    };
+    create_table(db_path, &schema).unwrap();
+
+    let mut table = Table::open(db_path, name).unwrap();
+    for values in records.iter() {
+        table.append_entry(values.as_slice()).unwrap();
+    }
+    table
+}
+
+// Table with an `id` primary key running from 0 to `len - 1`, and `value = id % modulo`.
+pub fn numbers(db_path: &Path, name: &str, len: uint, modulo: u32) -> Table {
+    let records: Vec<Vec<Field>> = range(0, len as u32)
+        .map(|id| vec![Integer(id), Integer(id % modulo)])
+        .collect();
+    create(db_path, name, &[("id", IntegerType), ("value", IntegerType)], Some("id"),
+           records.as_slice())
+}
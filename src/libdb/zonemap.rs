@@ -0,0 +1,301 @@
+use std::io::fs;
+use std::io;
+
+use super::{
+    BLOCK_SIZE,
+    Bound,
+    Field,
+    FieldSchema,
+    FieldType,
+    PhysicalTableIterator,
+    TableIterator,
+    TableSchema,
+    read_value,
+    write_value,
+};
+
+fn corrupt_zone_map() -> io::IoError {
+    io::IoError {
+        kind: io::InvalidInput,
+        desc: "zone map contains invalid data",
+        detail: None,
+    }
+}
+
+// Minimum and maximum value of each field, for every block of a table's data file.
+//
+// Blocks are aligned to multiples of BLOCK_SIZE records. A block without any live records has
+// no zone. Otherwise zones only ever grow when records are updated or deleted; they are made
+// tight again when the table is compacted.
+pub struct ZoneMap {
+    file: fs::File,
+    fields: Vec<(FieldType, uint)>,
+    zones: Vec<Option<Vec<(Field, Field)>>>,
+}
+
+impl ZoneMap {
+    pub fn open(path: &Path, fields: &[FieldSchema]) -> io::IoResult<ZoneMap> {
+        let mut zone_map = ZoneMap {
+            file: try!(fs::File::open_mode(path, io::Open, io::ReadWrite)),
+            fields: fields.iter().map(|f| (f.data_type, f.length)).collect(),
+            zones: Vec::new(),
+        };
+
+        let buf = try!(zone_map.file.read_to_end());
+        for entry in buf.as_slice().chunks(zone_map.entry_size()) {
+            let zone = try!(zone_map.decode_zone(entry));
+            zone_map.zones.push(zone);
+        }
+        Ok(zone_map)
+    }
+
+    fn entry_size(&self) -> uint {
+        1 + self.fields.iter().fold(0, |size, &(_, length)| size + 2 * length)
+    }
+
+    fn decode_zone(&self, buf: &[u8]) -> io::IoResult<Option<Vec<(Field, Field)>>> {
+        if buf.len() != self.entry_size() {
+            return Err(corrupt_zone_map());
+        }
+        if buf[0] == 0 {
+            return Ok(None);
+        }
+
+        let mut zone = Vec::with_capacity(self.fields.len());
+        let mut offset = 1;
+        for &(field_type, length) in self.fields.iter() {
+            let min = read_value(0, field_type, buf.slice(offset, offset + length));
+            let max = read_value(0, field_type, buf.slice(offset + length, offset + 2 * length));
+            match (min, max) {
+                (Ok(min), Ok(max)) => zone.push((min, max)),
+                _ => return Err(corrupt_zone_map()),
+            }
+            offset += 2 * length;
+        }
+        Ok(Some(zone))
+    }
+
+    fn write_zone(&mut self, block: uint) -> io::IoResult<()> {
+        let entry_size = self.entry_size();
+        let mut buf = Vec::from_elem(entry_size, 0u8);
+
+        match *self.zones.get(block) {
+            None => (),
+            Some(ref zone) => {
+                *buf.get_mut(0) = 1;
+                let mut offset = 1;
+                for (&(ref min, ref max), &(_, length)) in zone.iter().zip(self.fields.iter()) {
+                    let min_ok = write_value(0, min, buf.mut_slice(offset, offset + length));
+                    let max_ok = write_value(0, max,
+                            buf.mut_slice(offset + length, offset + 2 * length));
+                    if min_ok.is_err() || max_ok.is_err() {
+                        return Err(corrupt_zone_map());
+                    }
+                    offset += 2 * length;
+                }
+            },
+        }
+
+        try!(self.file.seek((block * entry_size) as i64, io::SeekSet));
+        self.file.write(buf.as_slice())
+    }
+
+    pub fn num_blocks(&self) -> uint {
+        self.zones.len()
+    }
+
+    pub fn clear(&mut self) -> io::IoResult<()> {
+        self.zones.clear();
+        self.file.truncate(0)
+    }
+
+    // Widens the zone of the block holding record `pos` to include `values`.
+    pub fn add(&mut self, pos: uint, values: &[Field]) -> io::IoResult<()> {
+        let block = pos / BLOCK_SIZE;
+        while self.zones.len() <= block {
+            self.zones.push(None);
+        }
+
+        if self.zones.get(block).is_none() {
+            *self.zones.get_mut(block) =
+                Some(values.iter().map(|v| (v.clone(), v.clone())).collect());
+        } else {
+            let zone = self.zones.get_mut(block).get_mut_ref();
+            for (bounds, value) in zone.mut_iter().zip(values.iter()) {
+                let (ref mut min, ref mut max) = *bounds;
+                if *value < *min { *min = value.clone(); }
+                if *value > *max { *max = value.clone(); }
+            }
+        }
+
+        self.write_zone(block)
+    }
+
+    // Called when the last live record of a block is deleted.
+    pub fn clear_block(&mut self, block: uint) -> io::IoResult<()> {
+        if block >= self.zones.len() {
+            return Ok(());
+        }
+        *self.zones.get_mut(block) = None;
+        self.write_zone(block)
+    }
+
+    // Whether the block may contain records whose field lies between the bounds.
+    pub fn admits(&self, block: uint, field: uint, low: &Bound, high: &Bound) -> bool {
+        if block >= self.zones.len() {
+            return true;
+        }
+        match *self.zones.get(block) {
+            None => false,
+            Some(ref zone) => {
+                let &(ref min, ref max) = zone.get(field);
+                low.admits_above(max) && high.admits_below(min)
+            },
+        }
+    }
+}
+
+// Scans the records whose field lies between two bounds, skipping blocks that the zone map
+// shows can't contain any of them.
+pub struct ZoneScan<'table> {
+    rows: PhysicalTableIterator<'table>,
+    field: uint,
+    low: Bound,
+    high: Bound,
+
+    // Blocks that were not loaded thanks to the zone map. Not included in `blocks_accessed`.
+    pub blocks_skipped: uint,
+}
+
+impl<'table> ZoneScan<'table> {
+    pub fn new(rows: PhysicalTableIterator<'table>, field: uint, low: Bound, high: Bound)
+            -> ZoneScan<'table> {
+        ZoneScan {
+            rows: rows,
+            field: field,
+            low: low,
+            high: high,
+
+            blocks_skipped: 0,
+        }
+    }
+}
+
+impl<'table> Iterator<Vec<Field>> for ZoneScan<'table> {
+    fn next(&mut self) -> Option<Vec<Field>> {
+        loop {
+            let i = self.rows.i;
+            if i >= self.rows.len {
+                return None;
+            }
+
+            let block = i / BLOCK_SIZE;
+            if !self.rows.table.zone_map.admits(block, self.field, &self.low, &self.high) {
+                self.rows.i = (block + 1) * BLOCK_SIZE;
+                self.blocks_skipped += 1;
+                continue;
+            }
+
+            self.rows.i += 1;
+            match self.rows.idx(i) {
+                None => continue,
+                Some(values) => {
+                    let matches = {
+                        let value = values.get(self.field);
+                        self.low.admits_above(value) && self.high.admits_below(value)
+                    };
+                    if matches {
+                        return Some(values);
+                    }
+                },
+            }
+        }
+    }
+}
+
+impl<'table> TableIterator for ZoneScan<'table> {
+    fn blocks_accessed(&self) -> uint {
+        self.rows.blocks_accessed()
+    }
+
+    fn records_accessed(&self) -> uint {
+        self.rows.records_accessed()
+    }
+
+    fn schema<'s>(&'s self) -> &'s TableSchema {
+        self.rows.schema()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::testing;
+    use super::super::{
+        BLOCK_SIZE,
+        BTreeIndexType,
+        Bound,
+        Excluded,
+        Included,
+        Integer,
+        KeyLookupIterator,
+        Table,
+        TableIterator,
+        Unbounded,
+    };
+
+    // Ids of the records a zone scan on `value` returns, with the blocks it read and skipped.
+    fn scan(table: &mut Table, low: Bound, high: Bound) -> (Vec<u32>, uint, uint) {
+        let mut rows = table.zone_scan("value", low, high).unwrap();
+        let ids = rows.by_ref().map(|values| match *values.get(0) {
+            Integer(id) => id,
+            _ => fail!("expected an Integer id"),
+        }).collect();
+        (ids, rows.blocks_accessed(), rows.blocks_skipped)
+    }
+
+    #[test]
+    fn zone_scan_skips_blocks() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 10 * BLOCK_SIZE, 1000);
+        let (ids, read, skipped) = scan(&mut table, Included(Integer(35)), Excluded(Integer(52)));
+        assert_eq!(ids, range(35, 52).collect());
+        assert_eq!((read, skipped), (3, 7));
+
+        // A block whose records were all deleted has no zone left.
+        for id in range(30, 40u) {
+            table.delete_entry(id).unwrap();
+        }
+        let (ids, read, skipped) = scan(&mut table, Included(Integer(35)), Excluded(Integer(52)));
+        assert_eq!(ids, range(40, 52).collect());
+        assert_eq!((read, skipped), (2, 8));
+    }
+
+    #[test]
+    fn compact_rebuilds_zones_and_indexes() {
+        let db = testing::scratch_db();
+        let mut table = testing::numbers(db.path(), "T", 3 * BLOCK_SIZE, 1000);
+        table.create_index("value_btree", "value", BTreeIndexType).unwrap();
+        for id in range(0, 15u) {
+            table.delete_entry(id).unwrap();
+        }
+        // Updates widen zones, which stay wide after the value is put back.
+        table.update_entry(25, &[Integer(25), Integer(500)]).unwrap();
+        table.update_entry(25, &[Integer(25), Integer(25)]).unwrap();
+        let (ids, read, skipped) = scan(&mut table, Included(Integer(400)), Unbounded);
+        assert_eq!((ids, read, skipped), (vec![], 1, 2));
+
+        table.compact().unwrap();
+        assert_eq!(table.num_entries(), 15);
+        let (ids, read, skipped) = scan(&mut table, Included(Integer(400)), Unbounded);
+        assert_eq!((ids, read, skipped), (vec![], 0, 2));
+        let (ids, _, _) = scan(&mut table, Included(Integer(18)), Excluded(Integer(21)));
+        assert_eq!(ids, vec![18, 19, 20]);
+
+        // Indexes point at the records' new positions.
+        let rows: Vec<_> = table.index_scan("value_btree", Included(Integer(25)),
+                                            Included(Integer(25))).unwrap().collect();
+        assert_eq!(rows, vec![vec![Integer(25), Integer(25)]]);
+        assert_eq!(table.iter().lookup_key(&Integer(20)).unwrap(),
+                   Some(vec![Integer(20), Integer(20)]));
+    }
+}
@@ -16,14 +16,17 @@ fn create_tables(db_path: &Path) {
                 offset: 0,
                 data_type: db::IntegerType,
                 length: 4,
+                auto_increment: Some(true),
             },
             db::FieldSchema {
                 name: "nome".into_strbuf(),
                 offset: 4,
                 data_type: db::TextType,
                 length: 20,
+                auto_increment: None,
             }],
         entry_stride: 24,
+        primary_key: Some("id".into_strbuf()),
     };
 
     let clients_schema = db::TableSchema {
@@ -34,20 +37,24 @@ fn create_tables(db_path: &Path) {
                 offset: 0,
                 data_type: db::IntegerType,
                 length: 4,
+                auto_increment: Some(true),
             },
             db::FieldSchema {
                 name: "nome".into_strbuf(),
                 offset: 8,
                 data_type: db::TextType,
                 length: 20,
+                auto_increment: None,
             },
             db::FieldSchema {
                 name: "departamento".into_strbuf(),
                 offset: 4,
                 data_type: db::IntegerType,
                 length: 4,
+                auto_increment: None,
             }],
         entry_stride: 28,
+        primary_key: Some("id".into_strbuf()),
     };
 
     db::validate_schema(&clients_schema).unwrap();
@@ -106,10 +113,10 @@ fn main() {
         let mut depts = db::Table::open(&db_path, "Departamentos").unwrap();
 
         for (i, dept) in dept_names.iter().enumerate() {
-            let entry = [db::Integer(i as u32),
+            let entry = [db::Null,
                          db::Text(dept.into_owned())];
-            let foo = depts.append_entry(entry.as_slice());
-            foo.unwrap();
+            let id = depts.append_entry(entry.as_slice()).unwrap();
+            assert_eq!(id, Some(i as u32));
         }
     }
 
@@ -119,18 +126,30 @@ fn main() {
         let mut rng = rand::task_rng();
         let dept_sampler = Range::new(0, dept_names.len());
 
-        let mut next_id = 0;
-
         for first in names.iter() {
             for last in names.iter() {
                 let full_name = format!("{} {}", first, last);
                 let dept_id = dept_sampler.ind_sample(&mut rng);
-                let entry = [db::Integer(next_id),
+                // The id is filled in by the table's sequence.
+                let entry = [db::Null,
                              db::Text(full_name),
                              db::Integer(dept_id as u32)];
                 clients.append_entry(entry.as_slice()).unwrap();
-                next_id += 1;
             }
         }
+
+        clients.create_index("clientes_departamento", "departamento", db::BTreeIndexType).unwrap();
+        clients.create_index("clientes_nome", "nome", db::BTreeIndexType).unwrap();
+        clients.create_index("clientes_departamento_hash", "departamento", db::HashIndexType)
+            .unwrap();
+        clients.create_index("clientes_departamento_bitmap", "departamento", db::BitmapIndexType)
+            .unwrap();
+        let tokenizer = db::fulltext::TokenizerConfig {
+            case_fold: true,
+            strip_accents: true,
+            split: Some(db::fulltext::SplitOnPunctuation),
+        };
+        clients.create_text_index("clientes_nome_text", "nome", tokenizer).unwrap();
+        clients.create_bloom_filter("departamento", 0.01).unwrap();
     }
 }